@@ -1,6 +1,5 @@
-use crate::{db, commands::webtoon::check_webtoon};
+use crate::commands::{pings, webtoon::check_webtoon};
 use lazy_static::lazy_static;
-use mongodb::{bson::doc, options::UpdateOptions};
 use rand::Rng;
 use regex::{Regex, RegexBuilder};
 use serenity::{
@@ -58,7 +57,7 @@ pub async fn after(
             })
             .await;
     }
-    if let Err(why) = check_mentions(ctx, msg).await {
+    if let Err(why) = pings::check_mentions(ctx, msg).await {
         println!("Checking mentions had an error: {:?}", why);
     }
 }
@@ -85,107 +84,11 @@ pub async fn normal_message(ctx: &Context, msg: &Message) {
             .say(&ctx.http, "<:ping:719277539113041930>")
             .await;
     }
-    if let Err(why) = check_mentions(ctx, msg).await {
+    if let Err(why) = pings::check_mentions(ctx, msg).await {
         println!("Checking mentions had an error: {:?}", why);
     }
 }
 
-async fn check_mentions(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild_id = match msg.guild_id {
-        Some(id) => id.as_u64().to_owned(),
-        None => return Ok(()),
-    };
-
-    let mentioned_everyone = msg.mention_everyone;
-    let mentioned_roles = &msg.mention_roles;
-    let mentioned_users = &msg.mentions;
-
-    if !mentioned_everyone && mentioned_roles.len() == 0 && mentioned_users.len() == 0 {
-        return Ok(());
-    }
-
-    let data = ctx.data.read().await;
-    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
-    let past_pings = db.collection("past-pings");
-
-    if mentioned_everyone {
-        past_pings
-            .update_one(
-                doc! {
-                    "guild": guild_id,
-                    "everyone": true,
-                },
-                doc! {
-                    "$set": {
-                        "content": &msg.content,
-                        "author": msg.author.id.as_u64(),
-                        "channel_id": msg.channel_id.as_u64(),
-                        "message_id": msg.id.as_u64(),
-                    },
-                    "$setOnInsert": {
-                        "guild": &guild_id,
-                        "everyone": true,
-                    },
-                },
-                UpdateOptions::builder().upsert(true).build(),
-            )
-            .await?;
-    }
-    if mentioned_roles.len() > 0 {
-        for role_id in mentioned_roles {
-            past_pings
-                .update_one(
-                    doc! {
-                        "guild": &guild_id,
-                        "role": role_id.as_u64(),
-                    },
-                    doc! {
-                        "$set": {
-                            "content": &msg.content,
-                            "author": msg.author.id.as_u64(),
-                            "channel_id": msg.channel_id.as_u64(),
-                            "message_id": msg.id.as_u64(),
-                        },
-                        "$setOnInsert": {
-                            "guild": &guild_id,
-                            "role": role_id.as_u64(),
-                        },
-                    },
-                    UpdateOptions::builder().upsert(true).build(),
-                )
-                .await?;
-        }
-    }
-    if mentioned_users.len() > 0 {
-        for user in mentioned_users {
-            let user_id = user.id.as_u64();
-            past_pings
-                .update_one(
-                    doc! {
-                        "guild": &guild_id,
-                        "user": &user_id,
-                    },
-                    doc! {
-                        "$set": {
-                            "content": &msg.content,
-                            "author": msg.author.id.as_u64(),
-                            "channel_id": msg.channel_id.as_u64(),
-                            "message_id": msg.id.as_u64(),
-                        },
-                        "$setOnInsert": {
-                            "guild": &guild_id,
-                            "user": &user_id,
-                        },
-                    },
-                    UpdateOptions::builder().upsert(true).build(),
-                )
-                .await?;
-        }
-    }
-
-    Ok(())
-}
-
 #[hook]
 pub async fn dispatch_error(ctx: &Context, msg: &Message, error: DispatchError) {
     if let DispatchError::Ratelimited(duration) = error {
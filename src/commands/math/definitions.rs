@@ -0,0 +1,257 @@
+//! Remembers the variables and functions that members define, in `math-definitions`. They work
+//! everywhere, except ones defined with `:math local`, which only work in that server and come
+//! first there.
+use super::{
+    expression::{format_number, Environment, Expression, Function},
+    units::Quantity,
+};
+use crate::{db, pagination::truncate};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::UpdateOptions,
+    Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+    utils::Colour,
+};
+use tokio::stream::StreamExt;
+
+/// The most variables and functions each member can define.
+const MAX_DEFINITIONS: i64 = 50;
+
+/// Finds the member's definitions that apply in the guild, everywhere ones first.
+async fn find_definitions(
+    db: &Database,
+    user_id: u64,
+    guild_id: Option<u64>,
+) -> CommandResult<Vec<Document>> {
+    let guilds = match guild_id {
+        Some(guild_id) => vec![Bson::Null, Bson::from(guild_id)],
+        None => vec![Bson::Null],
+    };
+    let mut cursor = db
+        .collection("math-definitions")
+        .find(doc! { "user": user_id, "guild": { "$in": guilds } }, None)
+        .await?;
+    let mut definitions = Vec::new();
+    while let Some(doc_result) = cursor.next().await {
+        definitions.push(doc_result?);
+    }
+    definitions.sort_by_key(|definition| definition.get_i64("guild").is_ok());
+    Ok(definitions)
+}
+
+/// Loads the member's variables and functions that apply in the guild.
+pub async fn load_environment(
+    db: &Database,
+    user_id: u64,
+    guild_id: Option<u64>,
+) -> CommandResult<Environment> {
+    let mut environment = Environment::default();
+    for definition in find_definitions(db, user_id, guild_id).await? {
+        let name = String::from(definition.get_str("name").unwrap_or_default());
+        if let Ok(value) = definition.get_f64("value") {
+            // Units are stored as text, like `km/h`
+            let units = match definition.get_str("unit") {
+                Ok(unit) if !unit.is_empty() => match Expression::parse(unit)
+                    .and_then(|unit| unit.evaluate(&Environment::default()))
+                {
+                    Ok(units) => units,
+                    Err(_) => continue,
+                },
+                _ => Quantity::number(1.0),
+            };
+            let value = match Quantity::number(value).multiply(units) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            environment.functions.remove(&name);
+            environment.variables.insert(name, value);
+        } else if let (Ok(parameters), Ok(body)) = (
+            definition.get_array("parameters"),
+            definition.get_str("body"),
+        ) {
+            // The body was checked when it was defined
+            if let Ok(body) = Expression::parse(body) {
+                environment.variables.remove(&name);
+                environment.functions.insert(
+                    name,
+                    Function {
+                        parameters: parameters
+                            .iter()
+                            .filter_map(|parameter| parameter.as_str())
+                            .map(String::from)
+                            .collect(),
+                        body,
+                    },
+                );
+            }
+        }
+    }
+    Ok(environment)
+}
+
+/// Stores a variable or function, replacing any with the same name in the same place. Returns
+/// false if the member already has too many.
+pub async fn save(
+    db: &Database,
+    user_id: u64,
+    guild_id: Option<u64>,
+    name: &str,
+    definition: Document,
+) -> CommandResult<bool> {
+    let definitions = db.collection("math-definitions");
+    let filter = doc! {
+        "user": user_id,
+        "guild": guild_id.map_or(Bson::Null, Bson::from),
+        "name": name,
+    };
+    if definitions.find_one(filter.clone(), None).await?.is_none()
+        && definitions
+            .count_documents(doc! { "user": user_id }, None)
+            .await?
+            >= MAX_DEFINITIONS
+    {
+        return Ok(false);
+    }
+    // Unset what the other kind of definition uses
+    let unset = if definition.contains_key("value") {
+        doc! { "parameters": "", "body": "" }
+    } else {
+        doc! { "value": "", "unit": "" }
+    };
+    definitions
+        .update_one(
+            filter.clone(),
+            doc! { "$set": definition, "$unset": unset, "$setOnInsert": filter },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    Ok(true)
+}
+
+#[command]
+#[usage = ""]
+#[example = ""]
+/// Lists the variables and functions you've defined that work here.
+async fn vars(ctx: &Context, msg: &Message) -> CommandResult {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let guild_id = msg.guild_id.map(|id| id.as_u64().to_owned());
+    let mut definitions = find_definitions(db, *msg.author.id.as_u64(), guild_id).await?;
+    if definitions.is_empty() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "You haven't defined anything. Try `:math x = 3` or `:math f(t) = t^2 + 1`.",
+            )
+            .await?;
+        return Ok(());
+    }
+    definitions.sort_by(|a, b| {
+        a.get_str("name")
+            .unwrap_or_default()
+            .cmp(b.get_str("name").unwrap_or_default())
+    });
+    let list = definitions
+        .iter()
+        .map(|definition| {
+            let name = definition.get_str("name").unwrap_or_default();
+            let line = match definition.get_f64("value") {
+                Ok(value) => match definition.get_str("unit") {
+                    Ok(unit) if !unit.is_empty() => {
+                        format!("`{} = {} {}`", name, format_number(value), unit)
+                    }
+                    _ => format!("`{} = {}`", name, format_number(value)),
+                },
+                Err(_) => format!(
+                    "`{}({}) = {}`",
+                    name,
+                    definition.get_array("parameters").map_or_else(
+                        |_| String::new(),
+                        |parameters| parameters
+                            .iter()
+                            .filter_map(|parameter| parameter.as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    ),
+                    definition.get_str("body").unwrap_or_default()
+                ),
+            };
+            if definition.get_i64("guild").is_ok() {
+                format!("{} (only here)", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.title("Your variables and functions");
+                embed.colour(Colour::MAGENTA);
+                embed.description(truncate(list, 2000));
+                embed.footer(|footer| {
+                    footer.text(format!(
+                        "{} of {} definitions",
+                        definitions.len(),
+                        MAX_DEFINITIONS
+                    ))
+                });
+                embed
+            });
+            message
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[usage = "<name>"]
+#[example = "x"]
+/// Forgets a variable or function you've defined. If you defined it with `:math local` here, that
+/// one is forgotten, and otherwise the one that works everywhere is.
+async fn forget(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let name = args.rest().trim();
+    let user_id = *msg.author.id.as_u64();
+    let definitions = db.collection("math-definitions");
+    let mut deleted = 0;
+    if let Some(guild_id) = msg.guild_id {
+        deleted = definitions
+            .delete_one(
+                doc! { "user": user_id, "guild": guild_id.as_u64(), "name": name },
+                None,
+            )
+            .await?
+            .deleted_count;
+    }
+    if deleted == 0 {
+        deleted = definitions
+            .delete_one(
+                doc! { "user": user_id, "guild": Bson::Null, "name": name },
+                None,
+            )
+            .await?
+            .deleted_count;
+    }
+
+    if deleted == 0 {
+        msg.channel_id
+            .say(&ctx.http, format!("You haven't defined `{}`.", name))
+            .await?;
+    } else {
+        msg.react(&ctx.http, '👌').await?;
+    }
+
+    Ok(())
+}
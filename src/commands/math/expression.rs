@@ -0,0 +1,962 @@
+//! The arithmetic expressions that `:math` evaluates, like `2(3 + 4)^2 - sqrt(16)`.
+//!
+//! - `+`, `-`, `*`, `/`, `%` (remainder), and `^` (or `**`), with the usual precedence. `^` groups
+//!   from the right, so `2^3^2` is `2^9`, and binds tighter than a leading minus, so `-2^2` is -4.
+//! - Things written next to each other are multiplied, like `2pi` or `3(4 + 5)`. This binds
+//!   tighter than `*` and `/`, so `1/2x` is `1/(2x)` and `5 km / 200 m` has no units.
+//! - Functions, like `sqrt(2)`. See [`FUNCTIONS`].
+//! - Constants, like `pi`. See [`CONSTANTS`].
+//! - Units, like `3 km/h * 2 h`. See [`super::units`].
+//! - Variables and functions that members define, like `x = 3` or `f(t) = t^2 + 1`. See
+//!   [`Statement`].
+use super::units::{describe_dimension, is_unit, Quantity};
+use std::{
+    collections::HashMap,
+    f64::consts::{E, PI, TAU},
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+/// A problem with an expression. `position` and `length` are byte offsets into the expression of
+/// the offending token. If the problem is inside a function that the member defined, `definition`
+/// is its name, and the position is where it was called.
+#[derive(Debug)]
+pub struct MathError {
+    pub position: usize,
+    pub length: usize,
+    pub reason: String,
+    pub definition: Option<String>,
+}
+
+impl MathError {
+    pub fn new(position: usize, length: usize, reason: String) -> Self {
+        MathError {
+            position,
+            length: length.max(1),
+            reason,
+            definition: None,
+        }
+    }
+
+    /// Formats the error for Discord, underlining the offending token in the expression.
+    pub fn describe(&self, expression: &str) -> String {
+        let column = expression[..self.position].chars().count();
+        let token_end = (self.position + self.length).min(expression.len());
+        let width = expression[self.position..token_end].chars().count().max(1);
+        let reason = match &self.definition {
+            Some(name) => format!("In your definition of `{}`: {}", name, self.reason),
+            None => self.reason.clone(),
+        };
+        format!(
+            "{} (at character {}):\n```\n{}\n{}{}\n```",
+            reason,
+            column + 1,
+            expression,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl Display for MathError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{} (at byte {})", self.reason, self.position)
+    }
+}
+
+/// The named constants.
+pub const CONSTANTS: [(&str, f64); 5] = [("pi", PI), ("π", PI), ("tau", TAU), ("τ", TAU), ("e", E)];
+
+/// The functions and how they're used. Trigonometry is in radians.
+pub const FUNCTIONS: [(&str, &str); 15] = [
+    ("sin", "`sin(x)`"),
+    ("cos", "`cos(x)`"),
+    ("tan", "`tan(x)`"),
+    ("asin", "`asin(x)`"),
+    ("acos", "`acos(x)`"),
+    ("atan", "`atan(x)`"),
+    ("sqrt", "`sqrt(x)`"),
+    ("cbrt", "`cbrt(x)`"),
+    ("abs", "`abs(x)`"),
+    ("floor", "`floor(x)`"),
+    ("ceil", "`ceil(x)`"),
+    ("round", "`round(x)`"),
+    ("exp", "`exp(x)`"),
+    ("ln", "`ln(x)`"),
+    ("log", "`log(x)`, which is base 10, or `log(x, base)`"),
+];
+
+/// The most times functions that members defined can be called in one evaluation, so that ones
+/// that call themselves forever don't.
+const MAX_CALLS: usize = 1000;
+
+/// How deeply parentheses, signs, and powers can be nested, so that parsing doesn't overflow the
+/// stack.
+const MAX_DEPTH: usize = 200;
+
+/// Applies a function to arguments without units, or `None` if it doesn't take that many. The
+/// functions that work on units too are handled when evaluating.
+fn apply_function(name: &str, arguments: &[f64]) -> Option<f64> {
+    Some(match (name, arguments) {
+        ("sin", [x]) => x.sin(),
+        ("cos", [x]) => x.cos(),
+        ("tan", [x]) => x.tan(),
+        ("asin", [x]) => x.asin(),
+        ("acos", [x]) => x.acos(),
+        ("atan", [x]) => x.atan(),
+        ("exp", [x]) => x.exp(),
+        ("ln", [x]) => x.ln(),
+        ("log", [x]) => x.log10(),
+        ("log", [x, base]) => x.log(*base),
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Number(f64),
+    Name(String),
+    Operator(char),
+    Open,
+    Close,
+    Comma,
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+    length: usize,
+}
+
+/// How long the exponent at the start of `text` is, like `e-3`, or 0 if there isn't one. A lone
+/// `e` is Euler's number instead.
+fn exponent_length(text: &str) -> usize {
+    if !text.starts_with('e') && !text.starts_with('E') {
+        return 0;
+    }
+    let sign_length = if text[1..].starts_with('+') || text[1..].starts_with('-') {
+        1
+    } else {
+        0
+    };
+    let digits = &text[1 + sign_length..];
+    let digits_length = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    if digits_length == 0 {
+        0
+    } else {
+        1 + sign_length + digits_length
+    }
+}
+
+/// Splits an expression into tokens. `offset` is added to the positions in case the expression is
+/// part of something longer.
+fn tokenize(expression: &str, offset: usize) -> Result<Vec<Token>, MathError> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while let Some(c) = expression[position..].chars().next() {
+        let rest = &expression[position..];
+        if c.is_whitespace() {
+            position += c.len_utf8();
+            continue;
+        }
+        let (kind, length) = if c.is_ascii_digit() || c == '.' {
+            let mut length = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            length += exponent_length(&rest[length..]);
+            match rest[..length].parse::<f64>() {
+                Ok(number) => (TokenKind::Number(number), length),
+                Err(_) => {
+                    return Err(MathError::new(
+                        offset + position,
+                        length,
+                        format!("`{}` isn't a number", &rest[..length]),
+                    ))
+                }
+            }
+        } else if c.is_alphabetic() || c == '_' || c == '°' {
+            let length = c.len_utf8()
+                + rest[c.len_utf8()..]
+                    .find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len() - c.len_utf8());
+            (TokenKind::Name(String::from(&rest[..length])), length)
+        } else if rest.starts_with("**") {
+            (TokenKind::Operator('^'), 2)
+        } else {
+            let kind = match c {
+                '+' | '-' | '*' | '/' | '%' | '^' => TokenKind::Operator(c),
+                '−' => TokenKind::Operator('-'),
+                '×' | '·' => TokenKind::Operator('*'),
+                '÷' => TokenKind::Operator('/'),
+                '(' => TokenKind::Open,
+                ')' => TokenKind::Close,
+                ',' => TokenKind::Comma,
+                _ => {
+                    return Err(MathError::new(
+                        offset + position,
+                        c.len_utf8(),
+                        format!("I don't know what `{}` means", c),
+                    ))
+                }
+            };
+            (kind, c.len_utf8())
+        };
+        tokens.push(Token {
+            kind,
+            position: offset + position,
+            length,
+        });
+        position += length;
+    }
+    tokens.push(Token {
+        kind: TokenKind::End,
+        position: offset + expression.len(),
+        length: 0,
+    });
+    Ok(tokens)
+}
+
+/// A parsed expression. Names and calls keep where they are so that evaluation errors can point
+/// at them.
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Number(f64),
+    Name {
+        name: String,
+        position: usize,
+        length: usize,
+    },
+    Call {
+        name: String,
+        arguments: Vec<Expression>,
+        position: usize,
+        length: usize,
+    },
+    Negate(Box<Expression>),
+    /// The position is of the operator, or of what comes after it for implicit multiplication.
+    Binary {
+        operator: char,
+        left: Box<Expression>,
+        right: Box<Expression>,
+        position: usize,
+        length: usize,
+    },
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    /// How many `unary` calls deep the parser is. Every way of nesting goes through `unary`.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.index]
+    }
+
+    /// Moves on to the next token. The last token is always `End`, which is never moved past.
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.index].clone();
+        if token.kind != TokenKind::End {
+            self.index += 1;
+        }
+        token
+    }
+
+    fn sum(&mut self) -> Result<Expression, MathError> {
+        let mut left = self.product()?;
+        while let TokenKind::Operator(operator @ '+') | TokenKind::Operator(operator @ '-') =
+            self.peek().kind
+        {
+            let token = self.next();
+            left = Expression::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(self.product()?),
+                position: token.position,
+                length: token.length,
+            };
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expression, MathError> {
+        let mut left = self.implicit_product()?;
+        while let TokenKind::Operator(operator @ '*')
+        | TokenKind::Operator(operator @ '/')
+        | TokenKind::Operator(operator @ '%') = self.peek().kind
+        {
+            let token = self.next();
+            left = Expression::Binary {
+                operator,
+                left: Box::new(left),
+                right: Box::new(self.implicit_product()?),
+                position: token.position,
+                length: token.length,
+            };
+        }
+        Ok(left)
+    }
+
+    /// Things written next to each other, like `2pi` or `5 km`.
+    fn implicit_product(&mut self) -> Result<Expression, MathError> {
+        let mut left = self.unary()?;
+        while let TokenKind::Number(_) | TokenKind::Name(_) | TokenKind::Open = self.peek().kind {
+            let token = self.peek().clone();
+            left = Expression::Binary {
+                operator: '*',
+                left: Box::new(left),
+                right: Box::new(self.power()?),
+                position: token.position,
+                length: token.length,
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, MathError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            let token = self.peek();
+            return Err(MathError::new(
+                token.position,
+                token.length,
+                String::from("This is nested too deeply"),
+            ));
+        }
+        let result = match self.peek().kind {
+            TokenKind::Operator('-') => {
+                self.next();
+                self.unary()
+                    .map(|inner| Expression::Negate(Box::new(inner)))
+            }
+            TokenKind::Operator('+') => {
+                self.next();
+                self.unary()
+            }
+            _ => self.power(),
+        };
+        self.depth -= 1;
+        result
+    }
+
+    fn power(&mut self) -> Result<Expression, MathError> {
+        let base = self.primary()?;
+        if self.peek().kind == TokenKind::Operator('^') {
+            let token = self.next();
+            // Parsing the exponent as a unary expression makes `^` group from the right and allows
+            // `2^-1`
+            return Ok(Expression::Binary {
+                operator: '^',
+                left: Box::new(base),
+                right: Box::new(self.unary()?),
+                position: token.position,
+                length: token.length,
+            });
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Expression, MathError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Number(number) => Ok(Expression::Number(number)),
+            TokenKind::Name(name) => {
+                if self.peek().kind != TokenKind::Open {
+                    return Ok(Expression::Name {
+                        name,
+                        position: token.position,
+                        length: token.length,
+                    });
+                }
+                let open = self.next();
+                let mut arguments = Vec::new();
+                if self.peek().kind != TokenKind::Close {
+                    arguments.push(self.sum()?);
+                    while self.peek().kind == TokenKind::Comma {
+                        self.next();
+                        arguments.push(self.sum()?);
+                    }
+                }
+                self.close(&open)?;
+                Ok(Expression::Call {
+                    name,
+                    arguments,
+                    position: token.position,
+                    length: token.length,
+                })
+            }
+            TokenKind::Open => {
+                let inner = self.sum()?;
+                self.close(&token)?;
+                Ok(inner)
+            }
+            TokenKind::End => Err(MathError::new(
+                token.position,
+                1,
+                String::from("Expected a number at the end"),
+            )),
+            _ => Err(MathError::new(
+                token.position,
+                token.length,
+                String::from("Expected a number here"),
+            )),
+        }
+    }
+
+    /// Expects the `)` for an `(`.
+    fn close(&mut self, open: &Token) -> Result<(), MathError> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Close => Ok(()),
+            TokenKind::End => Err(MathError::new(
+                open.position,
+                open.length,
+                String::from("This `(` is never closed with a `)`"),
+            )),
+            _ => Err(MathError::new(
+                token.position,
+                token.length,
+                String::from("Expected a `)` here"),
+            )),
+        }
+    }
+}
+
+/// A function that a member defined.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub parameters: Vec<String>,
+    pub body: Expression,
+}
+
+/// The variables and functions that a member defined.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    pub variables: HashMap<String, Quantity>,
+    pub functions: HashMap<String, Function>,
+}
+
+impl Expression {
+    pub fn parse(expression: &str) -> Result<Self, MathError> {
+        Expression::parse_at(expression, 0)
+    }
+
+    /// Parses an expression that starts `offset` bytes into something longer, so that errors point
+    /// at the right place.
+    pub fn parse_at(expression: &str, offset: usize) -> Result<Self, MathError> {
+        let mut parser = Parser {
+            tokens: tokenize(expression, offset)?,
+            index: 0,
+            depth: 0,
+        };
+        let parsed = parser.sum()?;
+        let token = parser.next();
+        match token.kind {
+            TokenKind::End => Ok(parsed),
+            TokenKind::Close => Err(MathError::new(
+                token.position,
+                token.length,
+                String::from("This `)` doesn't close any `(`"),
+            )),
+            _ => Err(MathError::new(
+                token.position,
+                token.length,
+                String::from("Commas only go between the arguments of a function"),
+            )),
+        }
+    }
+
+    pub fn evaluate(&self, environment: &Environment) -> Result<Quantity, MathError> {
+        self.evaluate_with(environment, &HashMap::new(), &mut 0)
+    }
+
+    /// Looks up a name: the parameters of the function being evaluated, then the member's
+    /// variables, then the constants, then the units.
+    fn lookup(
+        name: &str,
+        environment: &Environment,
+        locals: &HashMap<String, Quantity>,
+    ) -> Option<Result<Quantity, String>> {
+        if let Some(value) = locals.get(name).or_else(|| environment.variables.get(name)) {
+            return Some(Ok(value.clone()));
+        }
+        if let Some((_, value)) = CONSTANTS.iter().find(|(constant, _)| *constant == name) {
+            return Some(Ok(Quantity::number(*value)));
+        }
+        Quantity::of_unit(name)
+    }
+
+    /// Evaluates the expression. `locals` are the arguments of the function being evaluated, and
+    /// `calls` counts how many times functions that the member defined have been called.
+    fn evaluate_with(
+        &self,
+        environment: &Environment,
+        locals: &HashMap<String, Quantity>,
+        calls: &mut usize,
+    ) -> Result<Quantity, MathError> {
+        match self {
+            Expression::Number(number) => Ok(Quantity::number(*number)),
+            Expression::Name {
+                name,
+                position,
+                length,
+            } => match Expression::lookup(name, environment, locals) {
+                Some(result) => result.map_err(|reason| MathError::new(*position, *length, reason)),
+                None if FUNCTIONS.iter().any(|(function, _)| function == name) => {
+                    Err(MathError::new(
+                        *position,
+                        *length,
+                        format!(
+                            "`{}` is a function, so it needs parentheses, like `{}(2)`",
+                            name, name
+                        ),
+                    ))
+                }
+                None => Err(MathError::new(
+                    *position,
+                    *length,
+                    format!("I don't know what `{}` is", name),
+                )),
+            },
+            Expression::Call {
+                name,
+                arguments,
+                position,
+                length,
+            } => {
+                let values = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate_with(environment, locals, calls))
+                    .collect::<Result<Vec<Quantity>, MathError>>()?;
+                let usage = match FUNCTIONS.iter().find(|(function, _)| function == name) {
+                    Some((_, usage)) => usage,
+                    None => {
+                        if let Some(function) = environment.functions.get(name) {
+                            return Expression::call(
+                                name,
+                                function,
+                                values,
+                                environment,
+                                calls,
+                                *position,
+                                *length,
+                            );
+                        }
+                        // Something like `x(2)` multiplies
+                        if let (Some(value), [argument]) = (
+                            Expression::lookup(name, environment, locals),
+                            values.as_slice(),
+                        ) {
+                            return value
+                                .and_then(|value| value.multiply(argument.clone()))
+                                .map_err(|reason| MathError::new(*position, *length, reason));
+                        }
+                        return Err(MathError::new(
+                            *position,
+                            *length,
+                            format!("I don't know a function named `{}`", name),
+                        ));
+                    }
+                };
+                // Some functions work on units too
+                let with_units = match (name.as_str(), values.as_slice()) {
+                    ("abs", [x]) => Some(Some(x.clone().map_in_units(f64::abs))),
+                    ("floor", [x]) => Some(Some(x.clone().map_in_units(f64::floor))),
+                    ("ceil", [x]) => Some(Some(x.clone().map_in_units(f64::ceil))),
+                    ("round", [x]) => Some(Some(x.clone().map_in_units(f64::round))),
+                    ("sqrt", [x]) => Some(x.clone().pow(0.5).ok()),
+                    ("cbrt", [x]) => Some(x.clone().pow(1.0 / 3.0).ok()),
+                    _ => None,
+                };
+                if let Some(result) = with_units {
+                    return result.ok_or_else(|| {
+                        MathError::new(
+                            *position,
+                            *length,
+                            format!("`{}` would leave fractional units", name),
+                        )
+                    });
+                }
+                if values.iter().any(|value| !value.is_number()) {
+                    return Err(MathError::new(
+                        *position,
+                        *length,
+                        format!("`{}` only works on numbers without units", name),
+                    ));
+                }
+                let numbers = values.iter().map(|value| value.value).collect::<Vec<f64>>();
+                apply_function(name, &numbers)
+                    .map(Quantity::number)
+                    .ok_or_else(|| {
+                        MathError::new(
+                            *position,
+                            *length,
+                            format!("`{}` is used like {}", name, usage),
+                        )
+                    })
+            }
+            Expression::Negate(inner) => {
+                let mut value = inner.evaluate_with(environment, locals, calls)?;
+                value.value = -value.value;
+                Ok(value)
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+                position,
+                length,
+            } => {
+                let left = left.evaluate_with(environment, locals, calls)?;
+                let right = right.evaluate_with(environment, locals, calls)?;
+                let mismatch = |left: &Quantity, right: &Quantity| {
+                    MathError::new(
+                        *position,
+                        *length,
+                        format!(
+                            "This mixes {} and {}",
+                            describe_dimension(&left.dimension),
+                            describe_dimension(&right.dimension)
+                        ),
+                    )
+                };
+                match operator {
+                    '+' => left
+                        .clone()
+                        .combine(right.clone(), |a, b| a + b)
+                        .ok_or_else(|| mismatch(&left, &right)),
+                    '-' => left
+                        .clone()
+                        .combine(right.clone(), |a, b| a - b)
+                        .ok_or_else(|| mismatch(&left, &right)),
+                    '%' => left
+                        .clone()
+                        .combine(right.clone(), |a, b| a % b)
+                        .ok_or_else(|| mismatch(&left, &right)),
+                    '*' => left
+                        .multiply(right)
+                        .map_err(|reason| MathError::new(*position, *length, reason)),
+                    '/' => left
+                        .divide(right)
+                        .map_err(|reason| MathError::new(*position, *length, reason)),
+                    _ if !right.is_number() => Err(MathError::new(
+                        *position,
+                        *length,
+                        String::from("Powers can't have units"),
+                    )),
+                    _ => left
+                        .pow(right.value)
+                        .map_err(|reason| MathError::new(*position, *length, reason)),
+                }
+            }
+        }
+    }
+
+    /// Calls a function that the member defined. Errors inside it point at where it was called.
+    fn call(
+        name: &str,
+        function: &Function,
+        values: Vec<Quantity>,
+        environment: &Environment,
+        calls: &mut usize,
+        position: usize,
+        length: usize,
+    ) -> Result<Quantity, MathError> {
+        if values.len() != function.parameters.len() {
+            return Err(MathError::new(
+                position,
+                length,
+                format!(
+                    "`{}` is used like `{}({})`",
+                    name,
+                    name,
+                    function.parameters.join(", ")
+                ),
+            ));
+        }
+        *calls += 1;
+        if *calls > MAX_CALLS {
+            return Err(MathError::new(
+                position,
+                length,
+                format!("`{}` was called too many times. Does it call itself?", name),
+            ));
+        }
+        let locals = function
+            .parameters
+            .iter()
+            .cloned()
+            .zip(values)
+            .collect::<HashMap<String, Quantity>>();
+        function
+            .body
+            .evaluate_with(environment, &locals, calls)
+            .map_err(|err| MathError {
+                position,
+                length: length.max(1),
+                reason: err.reason,
+                definition: err.definition.or_else(|| Some(String::from(name))),
+            })
+    }
+}
+
+/// What someone asked `:math` to do.
+#[derive(Debug, Clone)]
+pub enum Statement {
+    Evaluate(Expression),
+    /// Like `x = 3`. The value is evaluated when it's defined.
+    Variable {
+        name: String,
+        value: Expression,
+    },
+    /// Like `f(t) = t^2 + 1`. The body is kept as text so that it can be stored.
+    Function {
+        name: String,
+        parameters: Vec<String>,
+        body: String,
+    },
+}
+
+/// Makes sure that a member isn't defining something that's built in. Variables can't be named
+/// after units either, since they're looked up first, so `m = 5` would make `2 m` mean 10. Function
+/// names and parameters can, since they can only be used as a call or inside the function.
+fn check_name(token: &Token, name: &str, variable: bool) -> Result<(), MathError> {
+    if CONSTANTS.iter().any(|(constant, _)| *constant == name) {
+        Err(MathError::new(
+            token.position,
+            token.length,
+            format!("`{}` is already a constant", name),
+        ))
+    } else if FUNCTIONS.iter().any(|(function, _)| *function == name) {
+        Err(MathError::new(
+            token.position,
+            token.length,
+            format!("`{}` is already a function", name),
+        ))
+    } else if variable && is_unit(name) {
+        Err(MathError::new(
+            token.position,
+            token.length,
+            format!("`{}` is already a unit", name),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+impl Statement {
+    pub fn parse(input: &str) -> Result<Self, MathError> {
+        let equals = match input.find('=') {
+            Some(equals) => equals,
+            None => return Ok(Statement::Evaluate(Expression::parse(input)?)),
+        };
+        let body = &input[equals + 1..];
+        // Parsing the body first catches a second `=`
+        let value = Expression::parse_at(body, equals + 1)?;
+        // There's always an `End` token, and nothing reads past it
+        let mut tokens = tokenize(&input[..equals], 0)?.into_iter();
+        let first = tokens.next().unwrap();
+        let name = match &first.kind {
+            TokenKind::Name(name) => name.clone(),
+            _ => {
+                return Err(MathError::new(
+                    first.position,
+                    first.length,
+                    String::from(
+                        "Before the `=` should be a name, like `x = 3`, or a function, like `f(t) = t^2`",
+                    ),
+                ))
+            }
+        };
+        let open = tokens.next().unwrap();
+        check_name(&first, &name, matches!(open.kind, TokenKind::End))?;
+        match open.kind {
+            TokenKind::End => return Ok(Statement::Variable { name, value }),
+            TokenKind::Open => {}
+            _ => {
+                return Err(MathError::new(
+                    open.position,
+                    open.length,
+                    String::from("Expected a `(` or `=` here"),
+                ))
+            }
+        }
+
+        let mut parameters: Vec<String> = Vec::new();
+        loop {
+            let token = tokens.next().unwrap();
+            match &token.kind {
+                TokenKind::Name(parameter) if parameters.contains(parameter) => {
+                    return Err(MathError::new(
+                        token.position,
+                        token.length,
+                        format!("There's already a parameter named `{}`", parameter),
+                    ))
+                }
+                TokenKind::Name(parameter) => {
+                    check_name(&token, parameter, false)?;
+                    parameters.push(parameter.clone());
+                }
+                TokenKind::Close if parameters.is_empty() => break,
+                _ => {
+                    return Err(MathError::new(
+                        token.position,
+                        token.length,
+                        String::from("Expected a parameter name here"),
+                    ))
+                }
+            }
+            let separator = tokens.next().unwrap();
+            match separator.kind {
+                TokenKind::Comma => {}
+                TokenKind::Close => break,
+                _ => {
+                    return Err(MathError::new(
+                        separator.position,
+                        separator.length,
+                        String::from("Expected a `,` or `)` here"),
+                    ))
+                }
+            }
+        }
+        let end = tokens.next().unwrap();
+        if end.kind != TokenKind::End {
+            return Err(MathError::new(
+                end.position,
+                end.length,
+                String::from("Expected a `=` here"),
+            ));
+        }
+        Ok(Statement::Function {
+            name,
+            parameters,
+            body: String::from(body.trim()),
+        })
+    }
+}
+
+/// Formats a result to at most 12 significant digits, so that `0.1 + 0.2` is just 0.3. Very big
+/// and very small numbers use scientific notation.
+pub fn format_number(number: f64) -> String {
+    if number.is_nan() {
+        return String::from("undefined");
+    }
+    if number.is_infinite() {
+        return String::from(if number > 0.0 { "∞" } else { "-∞" });
+    }
+    let rounded = format!("{:.11e}", number).parse::<f64>().unwrap_or(number);
+    if rounded != 0.0 && (rounded.abs() >= 1e15 || rounded.abs() < 1e-6) {
+        format!("{:e}", rounded)
+    } else {
+        // Avoid showing -0
+        format!("{}", rounded + 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(input: &str) -> f64 {
+        Expression::parse(input)
+            .and_then(|expression| expression.evaluate(&Environment::default()))
+            .unwrap_or_else(|err| panic!("{} failed: {}", input, err.reason))
+            .value
+    }
+
+    /// Where the error is in the input, and how long the offending token is.
+    fn error_at(input: &str) -> (usize, usize) {
+        match Expression::parse(input)
+            .and_then(|expression| expression.evaluate(&Environment::default()))
+        {
+            Ok(_) => panic!("{} should have failed", input),
+            Err(err) => (err.position, err.length),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+        assert_eq!(evaluate("2 * 3 ^ 2"), 18.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(evaluate("10 - 4 - 3"), 3.0);
+        assert_eq!(evaluate("12 / 3 / 2"), 2.0);
+    }
+
+    #[test]
+    fn negation_is_after_powers() {
+        assert_eq!(evaluate("-2^2"), -4.0);
+        assert_eq!(evaluate("(-2)^2"), 4.0);
+        assert_eq!(evaluate("2^-1"), 0.5);
+    }
+
+    #[test]
+    fn implicit_multiplication() {
+        assert_eq!(evaluate("2pi"), 2.0 * PI);
+        assert_eq!(evaluate("2(3+4)"), 14.0);
+        assert_eq!(evaluate("(1 + 1)(2 + 2)"), 8.0);
+        assert_eq!(evaluate("2(3 + 4)^2 - sqrt(16)"), 94.0);
+    }
+
+    #[test]
+    fn error_columns() {
+        assert_eq!(error_at("2 + * 3"), (4, 1));
+        assert_eq!(error_at("1 + foo"), (4, 3));
+        assert_eq!(error_at("sqrt(4"), (4, 1));
+        assert_eq!(error_at("sqrt + 1"), (0, 4));
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let nested = format!("{}1{}", "(".repeat(1000), ")".repeat(1000));
+        assert_eq!(error_at(&nested), (200, 1));
+        assert_eq!(error_at(&format!("{}1", "-".repeat(1000))), (200, 1));
+        assert_eq!(
+            evaluate(&format!("{}1{}", "(".repeat(100), ")".repeat(100))),
+            1.0
+        );
+    }
+
+    #[test]
+    fn describe_underlines_the_token() {
+        let err = MathError::new(4, 3, String::from("I don't know what `foo` is"));
+        assert_eq!(
+            err.describe("1 + foo"),
+            "I don't know what `foo` is (at character 5):\n```\n1 + foo\n    ^^^\n```"
+        );
+    }
+
+    #[test]
+    fn statements() {
+        assert!(matches!(
+            Statement::parse("x = 3"),
+            Ok(Statement::Variable { .. })
+        ));
+        assert!(matches!(
+            Statement::parse("f(t) = t^2 + 1"),
+            Ok(Statement::Function { .. })
+        ));
+        assert!(Statement::parse("pi = 3").is_err());
+        assert!(Statement::parse("sqrt = 3").is_err());
+        assert!(Statement::parse("m = 5").is_err());
+    }
+
+    #[test]
+    fn formats_numbers() {
+        assert_eq!(format_number(0.1 + 0.2), "0.3");
+        assert_eq!(format_number(14.0), "14");
+        assert_eq!(format_number(-0.0), "0");
+        assert_eq!(format_number(1.0 / 3.0), "0.333333333333");
+        assert_eq!(format_number(1e20), "1e20");
+        assert_eq!(format_number(1.5e-7), "1.5e-7");
+        assert_eq!(format_number(f64::NAN), "undefined");
+        assert_eq!(format_number(f64::INFINITY), "∞");
+        assert_eq!(format_number(f64::NEG_INFINITY), "-∞");
+    }
+}
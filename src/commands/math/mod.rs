@@ -1,3 +1,7 @@
+use crate::db;
+use definitions::{FORGET_COMMAND, VARS_COMMAND};
+use expression::{Statement, CONSTANTS, FUNCTIONS};
+use mongodb::bson::doc;
 use serenity::{
     client::Context,
     framework::standard::{
@@ -6,16 +10,152 @@ use serenity::{
     },
     model::channel::Message,
 };
+use units::CONVERT_COMMAND;
+
+mod definitions;
+mod expression;
+mod units;
 
 #[group]
 // Sets a single prefix for this group.
 // So one has to call commands in this group
 // via `:math` instead of just `:`.
 #[prefix = "math"]
-#[commands(multiply)]
-#[description = "A very limited set of calculator commands. See `:help math multiply` for more info."]
+// Lets us call `:math 1 + 2` without naming a command.
+#[default_command(calc)]
+#[commands(calc, convert, local, vars, forget, multiply)]
+#[description = "A calculator. Try `:math 2(3 + 4)^2`, and see `:help math calc` for more info."]
 struct Math;
 
+/// Evaluates an expression or defines a variable or function. Definitions are saved for this guild
+/// only if `local_guild` is given.
+async fn calculate(
+    ctx: &Context,
+    msg: &Message,
+    input: &str,
+    local_guild: Option<u64>,
+) -> CommandResult {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let user_id = *msg.author.id.as_u64();
+    let environment =
+        definitions::load_environment(db, user_id, msg.guild_id.map(|id| id.as_u64().to_owned()))
+            .await?;
+    let statement = Statement::parse(input);
+    if let (Some(_), Ok(Statement::Evaluate(_))) = (local_guild, &statement) {
+        msg.channel_id
+            .say(&ctx.http, "Only definitions, like `x = 3`, can be local.")
+            .await?;
+        return Ok(());
+    }
+    let (definition, response) = match statement {
+        Ok(Statement::Evaluate(expression)) => match expression.evaluate(&environment) {
+            Ok(result) => (None, result.describe()),
+            Err(err) => (None, err.describe(input)),
+        },
+        Ok(Statement::Variable { name, value }) => match value.evaluate(&environment) {
+            Ok(value) => {
+                let response = format!("{} = {}", name, value.describe());
+                (
+                    Some((
+                        name,
+                        doc! { "value": value.value_in_units(), "unit": value.describe_units() },
+                    )),
+                    response,
+                )
+            }
+            Err(err) => (None, err.describe(input)),
+        },
+        Ok(Statement::Function {
+            name,
+            parameters,
+            body,
+        }) => {
+            let response = format!("{}({}) = {}", name, parameters.join(", "), body);
+            (
+                Some((name, doc! { "parameters": parameters, "body": body })),
+                response,
+            )
+        }
+        Err(err) => (None, err.describe(input)),
+    };
+
+    if let Some((name, definition)) = definition {
+        if !definitions::save(db, user_id, local_guild, &name, definition).await? {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "You've defined too many things. Use `:math forget` to make room.",
+                )
+                .await?;
+            return Ok(());
+        }
+    }
+    msg.channel_id.say(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+#[command]
+#[usage = "<expression>"]
+#[example = "2(3 + 4)^2 - sqrt(16)"]
+#[example = "-2^2 + sin(pi / 6)"]
+#[example = "3 km/h * 2 h"]
+#[example = "f(t) = t^2 + 1"]
+/// Evaluates an expression. You can use `+`, `-`, `*`, `/`, `%` (remainder), `^` (powers), and
+/// parentheses. Things next to each other are multiplied, so `2pi` is `2 * pi`. Trigonometry is in
+/// radians, and `log` is base 10 unless you give a base, like `log(8, 2)`. Units work too, like
+/// `3 km/h * 2 h`; see `:help math convert` for which ones. You can also define variables, like
+/// `x = 3`, and functions, like `f(t) = t^2 + 1`, to use later. They're remembered everywhere; see
+/// `:math local` to define them for one server only.
+async fn calc(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let input = args.rest().trim();
+    if input.is_empty() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "What should I calculate? I know the functions {}, and the constants {}.",
+                    FUNCTIONS
+                        .iter()
+                        .map(|(name, _)| format!("`{}`", name))
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                    CONSTANTS
+                        .iter()
+                        .map(|(name, _)| format!("`{}`", name))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    calculate(ctx, msg, input, None).await
+}
+
+#[command]
+#[only_in(guilds)]
+#[usage = "<definition>"]
+#[example = "rate = 0.0725"]
+/// Defines a variable or function that only works in this server. Here, it's used instead of one
+/// with the same name that works everywhere.
+async fn local(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    calculate(ctx, msg, args.rest().trim(), Some(guild_id)).await
+}
+
 #[command]
 // Lets us also call `:math *` instead of just `:math multiply`.
 #[aliases("*")]
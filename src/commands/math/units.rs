@@ -0,0 +1,596 @@
+//! Units of measurement for `:math`. Quantities are kept in base units (metres, kilograms,
+//! seconds, kelvins, and bits) along with the units they were written in, so that answers can be
+//! given in those units too.
+use super::{
+    definitions,
+    expression::{format_number, Environment, Expression, MathError},
+};
+use crate::db;
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+};
+
+/// How many of each base unit a quantity has: metres, kilograms, seconds, kelvins, and bits.
+pub type Dimension = [i32; 5];
+
+const NUMBER: Dimension = [0, 0, 0, 0, 0];
+const LENGTH: Dimension = [1, 0, 0, 0, 0];
+const MASS: Dimension = [0, 1, 0, 0, 0];
+const TIME: Dimension = [0, 0, 1, 0, 0];
+const TEMPERATURE: Dimension = [0, 0, 0, 1, 0];
+const DATA: Dimension = [0, 0, 0, 0, 1];
+const SPEED: Dimension = [1, 0, -1, 0, 0];
+const ENERGY: Dimension = [2, 1, -2, 0, 0];
+const POWER: Dimension = [2, 1, -3, 0, 0];
+
+/// The symbols of the base units, for quantities that aren't in any unit from the table.
+const BASE_UNITS: [&str; 5] = ["m", "kg", "s", "K", "b"];
+
+/// Names for the kinds of quantities, used when they can't be converted between.
+const DIMENSION_NAMES: [(&str, Dimension); 12] = [
+    ("a number", NUMBER),
+    ("a length", LENGTH),
+    ("an area", [2, 0, 0, 0, 0]),
+    ("a volume", [3, 0, 0, 0, 0]),
+    ("a mass", MASS),
+    ("a time", TIME),
+    ("a temperature", TEMPERATURE),
+    ("a data size", DATA),
+    ("a speed", SPEED),
+    ("an acceleration", [1, 0, -2, 0, 0]),
+    ("an energy", ENERGY),
+    ("a power", POWER),
+];
+
+/// A unit of measurement. A value in this unit is `value * factor + offset` in base units, but
+/// only temperatures in °C and °F have an offset.
+#[derive(Debug, PartialEq)]
+pub struct Unit {
+    pub names: &'static [&'static str],
+    pub factor: f64,
+    pub offset: f64,
+    pub dimension: Dimension,
+}
+
+const fn unit(names: &'static [&'static str], factor: f64, dimension: Dimension) -> Unit {
+    Unit {
+        names,
+        factor,
+        offset: 0.0,
+        dimension,
+    }
+}
+
+/// The units, with the name they're shown with first.
+pub const UNITS: [Unit; 48] = [
+    unit(&["m", "meter", "meters", "metre", "metres"], 1.0, LENGTH),
+    unit(&["km", "kilometer", "kilometers"], 1000.0, LENGTH),
+    unit(&["cm", "centimeter", "centimeters"], 0.01, LENGTH),
+    unit(&["mm", "millimeter", "millimeters"], 0.001, LENGTH),
+    unit(&["μm", "um", "micrometer", "micrometers"], 1e-6, LENGTH),
+    unit(&["nm", "nanometer", "nanometers"], 1e-9, LENGTH),
+    unit(&["in", "inch", "inches"], 0.0254, LENGTH),
+    unit(&["ft", "foot", "feet"], 0.3048, LENGTH),
+    unit(&["yd", "yard", "yards"], 0.9144, LENGTH),
+    unit(&["mi", "mile", "miles"], 1609.344, LENGTH),
+    unit(&["kg", "kilogram", "kilograms"], 1.0, MASS),
+    unit(&["g", "gram", "grams"], 0.001, MASS),
+    unit(&["mg", "milligram", "milligrams"], 1e-6, MASS),
+    unit(&["t", "tonne", "tonnes"], 1000.0, MASS),
+    unit(&["lb", "lbs", "pound", "pounds"], 0.453_592_37, MASS),
+    unit(&["oz", "ounce", "ounces"], 0.028_349_523_125, MASS),
+    unit(&["s", "sec", "second", "seconds"], 1.0, TIME),
+    unit(&["ms", "millisecond", "milliseconds"], 0.001, TIME),
+    unit(&["min", "minute", "minutes"], 60.0, TIME),
+    unit(&["h", "hr", "hour", "hours"], 3600.0, TIME),
+    unit(&["day", "days"], 86400.0, TIME),
+    unit(&["week", "weeks"], 604_800.0, TIME),
+    unit(&["yr", "year", "years"], 31_557_600.0, TIME),
+    unit(&["K", "kelvin"], 1.0, TEMPERATURE),
+    Unit {
+        names: &["°C", "C", "celsius"],
+        factor: 1.0,
+        offset: 273.15,
+        dimension: TEMPERATURE,
+    },
+    Unit {
+        names: &["°F", "F", "fahrenheit"],
+        factor: 5.0 / 9.0,
+        offset: 459.67 * 5.0 / 9.0,
+        dimension: TEMPERATURE,
+    },
+    unit(&["b", "bit", "bits"], 1.0, DATA),
+    unit(&["B", "byte", "bytes"], 8.0, DATA),
+    unit(&["kb", "kilobit", "kilobits"], 1e3, DATA),
+    unit(&["Mb", "megabit", "megabits"], 1e6, DATA),
+    unit(&["Gb", "gigabit", "gigabits"], 1e9, DATA),
+    unit(&["kB", "KB", "kilobyte", "kilobytes"], 8e3, DATA),
+    unit(&["MB", "megabyte", "megabytes"], 8e6, DATA),
+    unit(&["GB", "gigabyte", "gigabytes"], 8e9, DATA),
+    unit(&["TB", "terabyte", "terabytes"], 8e12, DATA),
+    unit(&["KiB", "kibibyte", "kibibytes"], 8.0 * 1024.0, DATA),
+    unit(&["MiB", "mebibyte", "mebibytes"], 8.0 * 1048576.0, DATA),
+    unit(&["GiB", "gibibyte", "gibibytes"], 8.0 * 1073741824.0, DATA),
+    unit(&["kn", "knot", "knots"], 1852.0 / 3600.0, SPEED),
+    unit(&["J", "joule", "joules"], 1.0, ENERGY),
+    unit(&["kJ", "kilojoule", "kilojoules"], 1e3, ENERGY),
+    unit(&["cal", "calorie", "calories"], 4.184, ENERGY),
+    unit(
+        &["kcal", "Cal", "kilocalorie", "kilocalories"],
+        4184.0,
+        ENERGY,
+    ),
+    unit(&["Wh"], 3600.0, ENERGY),
+    unit(&["kWh"], 3.6e6, ENERGY),
+    unit(
+        &["eV", "electronvolt", "electronvolts"],
+        1.602_176_634e-19,
+        ENERGY,
+    ),
+    unit(&["W", "watt", "watts"], 1.0, POWER),
+    unit(&["kW", "kilowatt", "kilowatts"], 1e3, POWER),
+];
+
+/// Units that are just other units put together, so they're shown that way.
+const COMPOUND_UNITS: [(&str, &str); 6] = [
+    ("mph", "mi/h"),
+    ("kph", "km/h"),
+    ("fps", "ft/s"),
+    ("kbps", "kb/s"),
+    ("Mbps", "Mb/s"),
+    ("Gbps", "Gb/s"),
+];
+
+pub fn find_unit(name: &str) -> Option<&'static Unit> {
+    UNITS.iter().find(|unit| unit.names.contains(&name))
+}
+
+/// Whether the name is a unit, including the compound ones like `mph`.
+pub fn is_unit(name: &str) -> bool {
+    find_unit(name).is_some() || COMPOUND_UNITS.iter().any(|(symbol, _)| *symbol == name)
+}
+
+/// How high units can be raised, like m^64. This keeps powers from overflowing.
+const MAX_UNIT_POWER: i32 = 64;
+
+fn too_big() -> String {
+    format!("Units can only go up to the power of {}", MAX_UNIT_POWER)
+}
+
+/// Adds the powers of a unit, like when multiplying m^2 by m, unless that makes it too big.
+fn add_powers(power: i32, other_power: i32) -> Result<i32, String> {
+    power
+        .checked_add(other_power)
+        .filter(|sum| sum.abs() <= MAX_UNIT_POWER)
+        .ok_or_else(too_big)
+}
+
+/// A number with units.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+    /// The value in base units.
+    pub value: f64,
+    pub dimension: Dimension,
+    /// The units it was written in and their powers, which it's shown in.
+    pub units: Vec<(&'static Unit, i32)>,
+}
+
+impl Quantity {
+    pub fn number(value: f64) -> Self {
+        Quantity {
+            value,
+            dimension: NUMBER,
+            units: Vec::new(),
+        }
+    }
+
+    /// One of the named unit, or `None` if there's no unit by that name. Units with an offset can't
+    /// be used like this, since `2 °C` isn't twice as hot as `1 °C`.
+    pub fn of_unit(name: &str) -> Option<Result<Self, String>> {
+        if let Some((_, compound)) = COMPOUND_UNITS.iter().find(|(symbol, _)| *symbol == name) {
+            return Some(
+                Expression::parse(compound)
+                    .and_then(|expression| expression.evaluate(&Environment::default()))
+                    .map_err(|err| err.reason),
+            );
+        }
+        let unit = find_unit(name)?;
+        if unit.offset != 0.0 {
+            return Some(Err(format!(
+                "`{}` can only be converted, like `:math convert 20 {} to K`, since it doesn't start at zero",
+                name, name
+            )));
+        }
+        Some(Ok(Quantity {
+            value: unit.factor,
+            dimension: unit.dimension,
+            units: vec![(unit, 1)],
+        }))
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.dimension == NUMBER
+    }
+
+    /// Multiplies the quantities, or gives a reason if the units would get too big, like m^64 * m.
+    pub fn multiply(mut self, other: Quantity) -> Result<Self, String> {
+        self.value *= other.value;
+        for (power, other_power) in self.dimension.iter_mut().zip(other.dimension.iter()) {
+            *power = add_powers(*power, *other_power)?;
+        }
+        // Units of the same kind are shown as one, so `km * m` is in km^2
+        for (unit, power) in other.units {
+            match self
+                .units
+                .iter_mut()
+                .find(|(existing, _)| existing.dimension == unit.dimension)
+            {
+                Some((_, existing_power)) => *existing_power = add_powers(*existing_power, power)?,
+                None => self.units.push((unit, power)),
+            }
+        }
+        self.units.retain(|(_, power)| *power != 0);
+        Ok(self)
+    }
+
+    pub fn divide(self, other: Quantity) -> Result<Self, String> {
+        let inverse = Quantity {
+            value: 1.0 / other.value,
+            dimension: [
+                -other.dimension[0],
+                -other.dimension[1],
+                -other.dimension[2],
+                -other.dimension[3],
+                -other.dimension[4],
+            ],
+            units: other
+                .units
+                .into_iter()
+                .map(|(unit, power)| (unit, -power))
+                .collect(),
+        };
+        self.multiply(inverse)
+    }
+
+    /// Raises the quantity to a power, or gives a reason if that would leave fractional units, like
+    /// the square root of a metre, or units that are too big.
+    pub fn pow(self, exponent: f64) -> Result<Self, String> {
+        let whole = |power: i32| {
+            let raised = power as f64 * exponent;
+            if raised.is_nan() || raised.abs() > MAX_UNIT_POWER as f64 {
+                Err(too_big())
+            } else if (raised - raised.round()).abs() >= 1e-9 {
+                Err(String::from("This power would leave fractional units"))
+            } else {
+                Ok(raised.round() as i32)
+            }
+        };
+        let mut dimension = NUMBER;
+        for (raised, power) in dimension.iter_mut().zip(self.dimension.iter()) {
+            *raised = whole(*power)?;
+        }
+        let units = self
+            .units
+            .iter()
+            .map(|(unit, power)| Ok((*unit, whole(*power)?)))
+            .collect::<Result<Vec<(&'static Unit, i32)>, String>>()?;
+        Ok(Quantity {
+            value: self.value.powf(exponent),
+            dimension,
+            units: units.into_iter().filter(|(_, power)| *power != 0).collect(),
+        })
+    }
+
+    /// Adds or subtracts a quantity of the same kind, keeping this one's units.
+    pub fn combine(self, other: Quantity, operation: fn(f64, f64) -> f64) -> Option<Self> {
+        if self.dimension != other.dimension {
+            return None;
+        }
+        let units = if self.units.is_empty() {
+            other.units
+        } else {
+            self.units
+        };
+        Some(Quantity {
+            value: operation(self.value, other.value),
+            dimension: self.dimension,
+            units,
+        })
+    }
+
+    /// The units it's shown in. Mixes of units that make up a unit of one base unit, like
+    /// `kg m^2/s^2`, are shown as that unit, like `J`.
+    fn shown_units(&self) -> Vec<(&'static Unit, i32)> {
+        if self.units.len() > 1 {
+            if let Some(unit) = UNITS.iter().find(|unit| {
+                unit.factor == 1.0 && unit.offset == 0.0 && unit.dimension == self.dimension
+            }) {
+                return vec![(unit, 1)];
+            }
+        }
+        self.units.clone()
+    }
+
+    /// How many base units each of the units it's shown in is.
+    fn scale(&self) -> f64 {
+        self.shown_units()
+            .iter()
+            .map(|(unit, power)| unit.factor.powi(*power))
+            .product()
+    }
+
+    /// The value in the units it's shown in.
+    pub fn value_in_units(&self) -> f64 {
+        self.value / self.scale()
+    }
+
+    /// Applies a function to the value in the units it's shown in, like rounding.
+    pub fn map_in_units(mut self, function: fn(f64) -> f64) -> Self {
+        self.value = function(self.value_in_units()) * self.scale();
+        self
+    }
+
+    /// The units it's shown in, written so that they can be parsed again, like `m/s^2`. Units of
+    /// quantities that weren't written with any are made from base units.
+    pub fn describe_units(&self) -> String {
+        let shown_units = self.shown_units();
+        let units = if shown_units.is_empty() {
+            BASE_UNITS
+                .iter()
+                .zip(self.dimension.iter())
+                .map(|(name, power)| (*name, *power))
+                .filter(|(_, power)| *power != 0)
+                .collect::<Vec<(&str, i32)>>()
+        } else {
+            shown_units
+                .iter()
+                .map(|(unit, power)| (unit.names[0], *power))
+                .collect()
+        };
+        let write = |name: &str, power: i32| {
+            if power == 1 {
+                String::from(name)
+            } else {
+                format!("{}^{}", name, power)
+            }
+        };
+        let numerator = units
+            .iter()
+            .filter(|(_, power)| *power > 0)
+            .map(|(name, power)| write(name, *power))
+            .collect::<Vec<String>>();
+        let denominator = units.iter().filter(|(_, power)| *power < 0);
+        if numerator.is_empty() {
+            return denominator
+                .map(|(name, power)| write(name, *power))
+                .collect::<Vec<String>>()
+                .join(" ");
+        }
+        let mut described = numerator.join(" ");
+        for (name, power) in denominator {
+            described.push('/');
+            described.push_str(&write(name, -power));
+        }
+        described
+    }
+
+    /// Formats the quantity with its units, like "6 km".
+    pub fn describe(&self) -> String {
+        if self.is_number() {
+            format_number(self.value)
+        } else {
+            format!(
+                "{} {}",
+                format_number(self.value_in_units()),
+                self.describe_units()
+            )
+        }
+    }
+}
+
+/// Names the kind of quantity, like "a length", or gives its base units if it doesn't have a name.
+pub fn describe_dimension(dimension: &Dimension) -> String {
+    match DIMENSION_NAMES.iter().find(|(_, named)| named == dimension) {
+        Some((name, _)) => String::from(*name),
+        None => format!(
+            "something in {}",
+            Quantity {
+                value: 1.0,
+                dimension: *dimension,
+                units: Vec::new(),
+            }
+            .describe_units()
+        ),
+    }
+}
+
+/// Converts between temperatures, which can't be done by scaling since °C and °F don't start at
+/// zero. Returns `None` if the amount doesn't end with a unit of temperature.
+fn convert_temperature(
+    amount: &str,
+    target: &Unit,
+    environment: &Environment,
+) -> Option<Result<f64, MathError>> {
+    let amount = amount.trim_end();
+    let (unit, name) = UNITS
+        .iter()
+        .filter(|unit| unit.dimension == TEMPERATURE)
+        .flat_map(|unit| unit.names.iter().map(move |name| (unit, *name)))
+        .find(|(_, name)| {
+            amount.ends_with(name)
+                && !matches!(
+                    amount[..amount.len() - name.len()].chars().last(),
+                    Some(c) if c.is_alphabetic()
+                )
+        })?;
+    let number = &amount[..amount.len() - name.len()];
+    let value = match Expression::parse(number).and_then(|number| number.evaluate(environment)) {
+        Ok(value) if value.is_number() => value.value,
+        Ok(_) => {
+            return Some(Err(MathError::new(
+                0,
+                number.len(),
+                format!("This should be a number of {}", name),
+            )))
+        }
+        Err(err) => return Some(Err(err)),
+    };
+    let kelvins = value * unit.factor + unit.offset;
+    Some(Ok((kelvins - target.offset) / target.factor))
+}
+
+/// Converts an amount to the target units, which start at `target_position` in the input.
+fn convert_amount(
+    amount: &str,
+    target: &str,
+    target_position: usize,
+    environment: &Environment,
+) -> Result<f64, MathError> {
+    if let Some(unit) = find_unit(target).filter(|unit| unit.dimension == TEMPERATURE) {
+        if let Some(result) = convert_temperature(amount, unit, environment) {
+            return result;
+        }
+    }
+    let amount_quantity = Expression::parse(amount)?.evaluate(environment)?;
+    let target_quantity = Expression::parse_at(target, target_position)?.evaluate(environment)?;
+    if amount_quantity.dimension != target_quantity.dimension {
+        return Err(MathError::new(
+            target_position,
+            target.len(),
+            format!(
+                "I can't convert {} to {}",
+                describe_dimension(&amount_quantity.dimension),
+                describe_dimension(&target_quantity.dimension)
+            ),
+        ));
+    }
+    Ok(amount_quantity.value / target_quantity.value)
+}
+
+#[command]
+#[usage = "<amount> to <units>"]
+#[example = "5 ft to m"]
+#[example = "60 mph to km/h"]
+#[example = "72 F to C"]
+/// Converts an amount to other units. I know units of length, mass, time, temperature, data size,
+/// speed, energy, and power, and you can combine them, like `km/h`. Units also work in other
+/// `:math` expressions, like `3 km/h * 2 h`, except for °C and °F, which can only be converted.
+async fn convert(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let input = args.rest().trim();
+    let (amount, target_position) = match input.rfind(" to ") {
+        Some(index) => (&input[..index], index + 4),
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "What should I convert to? Try something like `:math convert 5 ft to m`.",
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    let target = input[target_position..].trim_start();
+    let target_position = input.len() - target.len();
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let environment = definitions::load_environment(
+        db,
+        *msg.author.id.as_u64(),
+        msg.guild_id.map(|id| id.as_u64().to_owned()),
+    )
+    .await?;
+
+    let response = match convert_amount(amount, target, target_position, &environment) {
+        Ok(result) => format!("{} = {} {}", amount.trim(), format_number(result), target),
+        Err(err) => err.describe(input),
+    };
+    msg.channel_id.say(&ctx.http, response).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(input: &str) -> String {
+        let index = input.rfind(" to ").unwrap();
+        let target = &input[index + 4..];
+        match convert_amount(&input[..index], target, index + 4, &Environment::default()) {
+            Ok(result) => format_number(result),
+            Err(err) => panic!("{} failed: {}", input, err.reason),
+        }
+    }
+
+    fn describe(input: &str) -> String {
+        Expression::parse(input)
+            .and_then(|expression| expression.evaluate(&Environment::default()))
+            .map(|quantity| quantity.describe())
+            .unwrap_or_else(|err| err.reason)
+    }
+
+    #[test]
+    fn converts_temperatures() {
+        assert_eq!(convert("100 °C to °F"), "212");
+        assert_eq!(convert("-40C to F"), "-40");
+        assert_eq!(convert("72 F to C"), "22.2222222222");
+        assert_eq!(convert("0 C to K"), "273.15");
+        assert_eq!(convert("300 K to C"), "26.85");
+        assert_eq!(convert("(20 + 5) C to F"), "77");
+    }
+
+    #[test]
+    fn converts_other_units() {
+        assert_eq!(convert("5 ft to m"), "1.524");
+        assert_eq!(convert("60 mph to km/h"), "96.56064");
+        assert_eq!(convert("1 day to h"), "24");
+        assert_eq!(convert("1 kcal to kJ"), "4.184");
+    }
+
+    #[test]
+    fn refuses_to_convert_between_dimensions() {
+        let err = convert_amount("5 ft", "s", 8, &Environment::default()).unwrap_err();
+        assert_eq!(err.reason, "I can't convert a length to a time");
+        assert_eq!((err.position, err.length), (8, 1));
+    }
+
+    #[test]
+    fn keeps_units_in_expressions() {
+        assert_eq!(describe("3 km/h * 2 h"), "6 km");
+        assert_eq!(describe("2 m * 3 m"), "6 m^2");
+        assert_eq!(describe("1 kg m^2/s^2"), "1 J");
+        assert_eq!(describe("3 m / 2 s"), "1.5 m/s");
+    }
+
+    #[test]
+    fn refuses_units_that_are_too_big() {
+        assert_eq!(describe("m^64"), "1 m^64");
+        assert_eq!(
+            describe("m^1e10"),
+            "Units can only go up to the power of 64"
+        );
+        assert_eq!(
+            describe("m^64 * m"),
+            "Units can only go up to the power of 64"
+        );
+        assert_eq!(describe("m^0.5"), "This power would leave fractional units");
+    }
+
+    #[test]
+    fn temperatures_with_an_offset_only_convert() {
+        assert!(matches!(Quantity::of_unit("°C"), Some(Err(_))));
+        assert!(matches!(Quantity::of_unit("K"), Some(Ok(_))));
+        assert!(Quantity::of_unit("parsec").is_none());
+    }
+
+    #[test]
+    fn knows_unit_names() {
+        assert!(is_unit("m"));
+        assert!(is_unit("mph"));
+        assert!(is_unit("°F"));
+        assert!(!is_unit("x"));
+    }
+}
@@ -1,7 +1,9 @@
 pub mod emoji;
 pub mod general;
 pub mod math;
+pub mod mydata;
 pub mod owner;
+pub mod pings;
 pub mod test;
 pub mod webtoon;
 pub mod whois;
@@ -0,0 +1,254 @@
+//! Lets members see everything that's stored about them and delete the parts that are theirs to
+//! delete, like the pings they've received and their own whois edits.
+use crate::db;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::FindOptions,
+    Database,
+};
+use serde_json::{Map, Value};
+use serenity::{
+    client::Context,
+    framework::standard::{
+        macros::{command, group},
+        Args, CommandResult,
+    },
+    model::{channel::Message, id::UserId},
+    utils::Colour,
+};
+use tokio::stream::StreamExt;
+
+#[group]
+#[prefix = "mydata"]
+#[commands(export, delete)]
+#[description = "See or delete what I've stored about you."]
+struct MyData;
+
+/// Where things about the member are stored, as what it is, the collection, the filter, and which
+/// fields to leave out. Whois collections store IDs as text, and the ping collections store them
+/// as numbers.
+fn stored_data(user_id: UserId) -> Vec<(&'static str, &'static str, Document, Option<Document>)> {
+    let text_id = user_id.to_string();
+    let id = *user_id.as_u64();
+    vec![
+        (
+            "whois entries",
+            "whois-data",
+            doc! { "_user": &text_id },
+            None,
+        ),
+        (
+            "whois edits",
+            "whois-edits",
+            doc! { "_user": &text_id },
+            None,
+        ),
+        (
+            "whois opt-outs",
+            "whois-optouts",
+            doc! { "_user": &text_id },
+            None,
+        ),
+        (
+            "whois approvals waiting",
+            "whois-pending",
+            doc! { "_user": &text_id },
+            None,
+        ),
+        (
+            "whois name pins",
+            "whois-pins",
+            doc! { "user": &text_id },
+            None,
+        ),
+        (
+            "whois changes to you",
+            "whois-audit",
+            doc! { "user": &text_id },
+            None,
+        ),
+        // The changes are other people's data, so only what was done and when is included
+        (
+            "whois changes you made",
+            "whois-audit",
+            doc! { "actor": &text_id, "user": { "$ne": &text_id } },
+            Some(doc! { "changes": 0 }),
+        ),
+        (
+            "pings you got",
+            "pings",
+            doc! { "kind": { "$in": ["user", "reply"] }, "target": id },
+            None,
+        ),
+        ("pings you sent", "pings", doc! { "author": id }, None),
+        ("AFK status", "afk", doc! { "user": id }, None),
+        (
+            "math variables and functions",
+            "math-definitions",
+            doc! { "user": id },
+            None,
+        ),
+        (
+            "old pings",
+            "past-pings",
+            doc! { "$or": [{ "user": id }, { "author": id }] },
+            None,
+        ),
+    ]
+}
+
+/// Collects everything stored about the member by what it is.
+async fn collect(db: &Database, user_id: UserId) -> CommandResult<Map<String, Value>> {
+    let mut data = Map::new();
+    data.insert(String::from("user"), Value::String(user_id.to_string()));
+    for (name, collection, filter, projection) in stored_data(user_id) {
+        let mut cursor = db
+            .collection(collection)
+            .find(
+                filter,
+                projection.map(|projection| FindOptions::builder().projection(projection).build()),
+            )
+            .await?;
+        let mut docs = Vec::new();
+        while let Some(doc_result) = cursor.next().await {
+            docs.push(Bson::Document(doc_result?).into_relaxed_extjson());
+        }
+        data.insert(String::from(name), Value::Array(docs));
+    }
+    Ok(data)
+}
+
+#[command]
+#[usage = ""]
+#[example = ""]
+/// DM you a JSON file with everything I've stored about you in every server: whois entries and
+/// edits, whois settings that mention you, the pings you've sent and gotten, your AFK status, and
+/// your math variables and functions.
+async fn export(ctx: &Context, msg: &Message) -> CommandResult {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let json = serde_json::to_string_pretty(&Value::Object(collect(db, msg.author.id).await?))?;
+    let dm = msg.author.create_dm_channel(ctx).await?;
+    let sent = dm
+        .id
+        .send_files(
+            &ctx.http,
+            vec![(json.as_bytes(), "mydata.json")],
+            |message| message.content("Here's everything I've stored about you."),
+        )
+        .await;
+    match sent {
+        Ok(_) => {
+            if msg.guild_id.is_some() {
+                msg.react(&ctx.http, '👌').await?;
+            }
+        }
+        Err(_) => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "I couldn't DM you. Do you have DMs from server members turned off?",
+                )
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[command]
+#[usage = "[confirm]"]
+#[example = ""]
+#[example = "confirm"]
+/// Delete what you're allowed to delete of what I've stored about you in every server: the pings
+/// you've gotten, what your messages said in the pings you've sent, your AFK status, your math
+/// variables and functions, and your `:whois set` edits, which go away from your whois entry after
+/// the next `:whois fetch`. Whois entries come from the mods, so ask them or hide yours with
+/// `:whois optout`. Without `confirm`, this lists what would be deleted.
+async fn delete(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let text_id = msg.author.id.to_string();
+    let id = *msg.author.id.as_u64();
+    let pings = db.collection("pings");
+    let past_pings = db.collection("past-pings");
+    let afk = db.collection("afk");
+    let whois_edits = db.collection("whois-edits");
+    let math_definitions = db.collection("math-definitions");
+    let pings_got = doc! { "kind": { "$in": ["user", "reply"] }, "target": id };
+    let pings_sent = doc! { "author": id, "content": { "$exists": true } };
+    let past_pings_got = doc! { "user": id };
+    let past_pings_sent = doc! { "author": id, "content": { "$exists": true } };
+
+    if args.single::<String>().ok().as_deref() != Some("confirm") {
+        let counts = vec![
+            (
+                "pings you got",
+                pings.count_documents(pings_got, None).await?
+                    + past_pings.count_documents(past_pings_got, None).await?,
+            ),
+            (
+                "pings you sent, whose message I'll forget",
+                pings.count_documents(pings_sent, None).await?
+                    + past_pings.count_documents(past_pings_sent, None).await?,
+            ),
+            (
+                "AFK statuses",
+                afk.count_documents(doc! { "user": id }, None).await?,
+            ),
+            (
+                "math variables and functions",
+                math_definitions
+                    .count_documents(doc! { "user": id }, None)
+                    .await?,
+            ),
+            (
+                "servers with your whois edits",
+                whois_edits
+                    .count_documents(doc! { "_user": &text_id }, None)
+                    .await?,
+            ),
+        ];
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.colour(Colour::MAGENTA);
+                    embed.description(
+                        counts
+                            .iter()
+                            .map(|(name, count)| format!("{} {}", count, name))
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+                    );
+                    embed
+                });
+                message
+                    .content("Here's what I'd delete. Do `:mydata delete confirm` to delete it.");
+                message
+            })
+            .await?;
+        return Ok(());
+    }
+
+    pings.delete_many(pings_got, None).await?;
+    pings
+        .update_many(pings_sent, doc! { "$unset": { "content": "" } }, None)
+        .await?;
+    past_pings.delete_many(past_pings_got, None).await?;
+    past_pings
+        .update_many(past_pings_sent, doc! { "$unset": { "content": "" } }, None)
+        .await?;
+    afk.delete_many(doc! { "user": id }, None).await?;
+    math_definitions
+        .delete_many(doc! { "user": id }, None)
+        .await?;
+    whois_edits
+        .delete_many(doc! { "_user": &text_id }, None)
+        .await?;
+
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! Lets members say they're away. While they are, people who ping them are told so, and the pings
+//! are queued in `afk` so they can be sent to the member as a digest when they're back. The queue
+//! only links to the messages; what they said is looked up in `pings` for the digest, so that it
+//! goes away with the pinger's `:mydata delete` and the retention window.
+use super::{preview, Target, PREVIEW_LENGTH};
+use crate::{db, pagination::truncate};
+use chrono::{DateTime, Utc};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::UpdateOptions,
+    Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+    utils::Colour,
+};
+use std::collections::HashMap;
+use tokio::stream::StreamExt;
+
+/// The most pings kept in a member's queue. Older ones are dropped.
+const QUEUE_LIMIT: i32 = 50;
+
+/// Says roughly how long ago a time was, like "3 hours".
+fn describe_since(time: &DateTime<Utc>) -> String {
+    let duration = Utc::now() - *time;
+    let (amount, unit) = if duration.num_days() > 0 {
+        (duration.num_days(), "day")
+    } else if duration.num_hours() > 0 {
+        (duration.num_hours(), "hour")
+    } else {
+        (duration.num_minutes(), "minute")
+    };
+    format!("{} {}{}", amount, unit, if amount == 1 { "" } else { "s" })
+}
+
+/// Tells people who pinged AFK members that they're away, and adds the pings to the members'
+/// queues. Only pings that were just recorded are given, so this happens once per message.
+pub async fn forward(
+    ctx: &Context,
+    db: &Database,
+    guild_id: u64,
+    msg: &Message,
+    targets: &[Target],
+) -> CommandResult {
+    let user_ids = targets
+        .iter()
+        .filter(|(kind, _)| *kind == "user" || *kind == "reply")
+        .filter_map(|(_, target)| *target)
+        .filter(|user_id| user_id != msg.author.id.as_u64())
+        .collect::<Vec<u64>>();
+    if user_ids.is_empty() {
+        return Ok(());
+    }
+
+    let afk = db.collection("afk");
+    let mut notices = Vec::new();
+    for user_id in user_ids {
+        let status = match afk
+            .find_one(doc! { "guild": guild_id, "user": user_id }, None)
+            .await?
+        {
+            Some(status) => status,
+            None => continue,
+        };
+        afk.update_one(
+            doc! { "guild": guild_id, "user": user_id },
+            doc! {
+                "$push": {
+                    "queue": {
+                        "$each": [{
+                            "time": Bson::DateTime(msg.timestamp.with_timezone(&Utc)),
+                            "author": msg.author.id.as_u64(),
+                            "channel_id": msg.channel_id.as_u64(),
+                            "message_id": msg.id.as_u64(),
+                        }],
+                        "$slice": -QUEUE_LIMIT,
+                    },
+                },
+            },
+            None,
+        )
+        .await?;
+        // Use their name so that the notice doesn't ping them again
+        let name = msg
+            .mentions
+            .iter()
+            .find(|user| user.id.as_u64() == &user_id)
+            .map_or_else(|| String::from("They"), |user| user.name.clone());
+        let since = status
+            .get_datetime("since")
+            .map_or_else(|_| String::from("a while"), describe_since);
+        notices.push(match status.get_str("reason") {
+            Ok(reason) if !reason.is_empty() => {
+                format!("**{}** has been AFK for {}: {}", name, since, reason)
+            }
+            _ => format!("**{}** has been AFK for {}.", name, since),
+        });
+    }
+
+    // Bots don't need to be told
+    if !notices.is_empty() && !msg.author.bot {
+        notices.push(String::from("I'll pass your ping along when they're back."));
+        msg.channel_id.say(&ctx.http, notices.join("\n")).await?;
+    }
+    Ok(())
+}
+
+/// What the queued messages said by message ID, from their records in `pings`.
+async fn queued_contents(
+    db: &Database,
+    guild_id: u64,
+    queue: &[Document],
+) -> CommandResult<HashMap<i64, String>> {
+    let message_ids = queue
+        .iter()
+        .filter_map(|ping| ping.get_i64("message_id").ok())
+        .collect::<Vec<i64>>();
+    let mut cursor = db
+        .collection("pings")
+        .find(
+            doc! {
+                "guild": guild_id,
+                "message_id": { "$in": message_ids },
+                "content": { "$exists": true },
+            },
+            None,
+        )
+        .await?;
+    let mut contents = HashMap::new();
+    while let Some(doc_result) = cursor.next().await {
+        let doc = doc_result?;
+        if let (Ok(message_id), Ok(content)) = (doc.get_i64("message_id"), doc.get_str("content")) {
+            contents.insert(message_id, String::from(content));
+        }
+    }
+    Ok(contents)
+}
+
+/// Describes a queued ping in one line with a link to the message, and what it said if that's
+/// still stored.
+fn describe_queued(ping: &Document, guild_id: u64, contents: &HashMap<i64, String>) -> String {
+    let message_id = ping.get_i64("message_id").unwrap_or(0);
+    let mut line = format!(
+        "[<@{}> pinged you](https://discord.com/channels/{}/{}/{}) in <#{}>",
+        ping.get_i64("author").unwrap_or(0),
+        guild_id,
+        ping.get_i64("channel_id").unwrap_or(0),
+        message_id,
+        ping.get_i64("channel_id").unwrap_or(0)
+    );
+    if let Some(content) = contents.get(&message_id) {
+        line.push_str(": ");
+        line.push_str(&preview(content, PREVIEW_LENGTH));
+    }
+    line
+}
+
+/// Clears the status of an AFK member who spoke and DMs them the pings they missed.
+pub async fn check_return(
+    ctx: &Context,
+    db: &Database,
+    guild_id: u64,
+    msg: &Message,
+) -> CommandResult {
+    if msg.author.bot {
+        return Ok(());
+    }
+    // The message that set the status doesn't count as coming back
+    let status = match db
+        .collection("afk")
+        .find_one_and_delete(
+            doc! {
+                "guild": guild_id,
+                "user": msg.author.id.as_u64(),
+                "message_id": { "$ne": msg.id.as_u64() },
+            },
+            None,
+        )
+        .await?
+    {
+        Some(status) => status,
+        None => return Ok(()),
+    };
+
+    let queue = status.get_array("queue").map_or_else(
+        |_| Vec::new(),
+        |queue| {
+            queue
+                .iter()
+                .filter_map(|ping| ping.as_document())
+                .cloned()
+                .collect::<Vec<Document>>()
+        },
+    );
+    let contents = queued_contents(db, guild_id, &queue).await?;
+    let lines = queue
+        .iter()
+        .map(|ping| describe_queued(ping, guild_id, &contents))
+        .collect::<Vec<String>>();
+    let list = lines.join("\n");
+    let guild_name = msg
+        .guild_field(&ctx.cache, |guild| guild.name.clone())
+        .await
+        .unwrap_or_else(|| String::from("the server"));
+    let since = status
+        .get_datetime("since")
+        .map_or_else(|_| String::from("a while"), describe_since);
+
+    // They might not accept DMs, which is fine
+    let _ = msg
+        .author
+        .direct_message(ctx, |message| {
+            message.embed(|embed| {
+                embed.title(format!("Welcome back to {}!", guild_name));
+                embed.colour(Colour::MAGENTA);
+                embed.description(if lines.is_empty() {
+                    format!("Nobody pinged you in the {} you were away.", since)
+                } else {
+                    truncate(list, 2000)
+                });
+                embed.footer(|footer| {
+                    footer.text(format!(
+                        "{} pings in {}. I've cleared your AFK status.",
+                        lines.len(),
+                        since
+                    ))
+                });
+                embed
+            });
+            message
+        })
+        .await;
+    Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[usage = "[reason]"]
+#[example = ""]
+#[example = "eating lasagna"]
+/// Let people know you're away. When someone pings you, I'll tell them you're AFK and why, and
+/// when you next send a message here, I'll DM you a list of the pings you missed.
+async fn afk(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let filter = doc! { "guild": guild_id, "user": msg.author.id.as_u64() };
+    db.collection("afk")
+        .update_one(
+            filter.clone(),
+            doc! {
+                "$set": {
+                    "reason": args.rest().trim(),
+                    "since": Bson::DateTime(Utc::now()),
+                    "message_id": msg.id.as_u64(),
+                    "queue": [],
+                },
+                "$setOnInsert": filter,
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+
+    msg.react(&ctx.http, '👋').await?;
+
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! Catches ghost pings, where someone pings and then deletes the message or edits the ping out.
+//! Recent messages with pings are kept in memory for a few minutes so that there's something to
+//! compare against when they're deleted or edited.
+use super::{describe_target, preview, targets_of, Target};
+use crate::db;
+use lazy_static::lazy_static;
+use mongodb::{bson::doc, options::UpdateOptions, Database};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        event::MessageUpdateEvent,
+        id::{ChannelId, MessageId, UserId},
+    },
+    utils::Colour,
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long messages with pings are remembered. Deleting a ping after this isn't a ghost ping.
+const WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// How much of the message is shown in the notice.
+const PREVIEW_LENGTH: usize = 500;
+
+/// A recent message with pings.
+struct RecentPing {
+    guild_id: u64,
+    author: UserId,
+    content: String,
+    targets: Vec<Target>,
+    /// Edits can't change who a reply pings, so this is kept to check edits with.
+    replied_to: Option<UserId>,
+    time: Instant,
+}
+
+lazy_static! {
+    static ref RECENT_PINGS: Mutex<HashMap<MessageId, RecentPing>> = Mutex::new(HashMap::new());
+}
+
+/// Remembers a message with pings for a few minutes.
+pub fn remember(msg: &Message, guild_id: u64, targets: &[Target], replied_to: Option<UserId>) {
+    let mut recent_pings = RECENT_PINGS.lock().unwrap();
+    let now = Instant::now();
+    recent_pings.retain(|_, ping| now.duration_since(ping.time) < WINDOW);
+    recent_pings.insert(
+        msg.id,
+        RecentPing {
+            guild_id,
+            author: msg.author.id,
+            content: msg.content.clone(),
+            targets: targets.to_vec(),
+            replied_to,
+            time: now,
+        },
+    );
+}
+
+/// Forgets a remembered message, if it's recent enough.
+fn forget(message_id: MessageId) -> Option<RecentPing> {
+    RECENT_PINGS
+        .lock()
+        .unwrap()
+        .remove(&message_id)
+        .filter(|ping| ping.time.elapsed() < WINDOW)
+}
+
+/// Whether the guild has turned on ghost ping notices.
+async fn is_enabled(db: &Database, guild_id: u64) -> CommandResult<bool> {
+    Ok(db
+        .collection("ping-settings")
+        .find_one(doc! { "guild": guild_id }, None)
+        .await?
+        .and_then(|settings| settings.get_bool("ghost").ok())
+        .unwrap_or(false))
+}
+
+/// Posts a notice naming who pinged whom.
+async fn send_notice(
+    ctx: &Context,
+    channel_id: ChannelId,
+    ping: &RecentPing,
+    targets: &[Target],
+    what_happened: &str,
+) -> CommandResult {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    if !is_enabled(db, ping.guild_id).await? {
+        return Ok(());
+    }
+
+    let content = preview(&ping.content, PREVIEW_LENGTH);
+    let targets = targets
+        .iter()
+        .map(describe_target)
+        .collect::<Vec<String>>()
+        .join(", ");
+    channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.title("Ghost ping!");
+                embed.colour(Colour::MAGENTA);
+                embed.description(format!(
+                    "<@{}> pinged {} and then {}.\n\n{}",
+                    ping.author, targets, what_happened, content
+                ));
+                embed
+            });
+            message
+        })
+        .await?;
+    Ok(())
+}
+
+pub async fn on_message_delete(ctx: &Context, channel_id: ChannelId, message_id: MessageId) {
+    let ping = match forget(message_id) {
+        Some(ping) => ping,
+        None => return,
+    };
+    if let Err(why) =
+        send_notice(ctx, channel_id, &ping, &ping.targets, "deleted the message").await
+    {
+        println!("Sending a ghost ping notice had an error: {:?}", why);
+    }
+}
+
+pub async fn on_message_update(ctx: &Context, event: &MessageUpdateEvent) {
+    // Updates without new content are just embeds loading
+    let content = match &event.content {
+        Some(content) => content,
+        None => return,
+    };
+    let ping = match forget(event.id) {
+        Some(ping) => ping,
+        None => return,
+    };
+    let targets = targets_of(
+        content,
+        event.mention_everyone.unwrap_or(false),
+        event.mention_roles.as_deref().unwrap_or_default(),
+        event.mentions.as_deref().unwrap_or_default(),
+        ping.replied_to,
+    );
+    let removed = ping
+        .targets
+        .iter()
+        .filter(|target| !targets.contains(target))
+        .cloned()
+        .collect::<Vec<Target>>();
+    if !targets.is_empty() {
+        RECENT_PINGS.lock().unwrap().insert(
+            event.id,
+            RecentPing {
+                guild_id: ping.guild_id,
+                author: ping.author,
+                content: content.clone(),
+                targets,
+                replied_to: ping.replied_to,
+                time: ping.time,
+            },
+        );
+    }
+    if removed.is_empty() {
+        return;
+    }
+    if let Err(why) = send_notice(
+        ctx,
+        event.channel_id,
+        &ping,
+        &removed,
+        "edited the ping out",
+    )
+    .await
+    {
+        println!("Sending a ghost ping notice had an error: {:?}", why);
+    }
+}
+
+#[command]
+#[usage = "[on|off]"]
+#[example = ""]
+#[example = "on"]
+#[required_permissions("MANAGE_GUILD")]
+/// Turn ghost ping notices on or off. When they're on, I'll point it out when someone pings and
+/// then deletes their message or edits the ping out within 5 minutes. They're off at first.
+/// Without any arguments, this says whether they're on. Requires that you can manage the guild
+/// (the MANAGE_GUILD permission).
+async fn ghost(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let enabled = match args.single::<String>().ok().as_deref() {
+        Some("on") => true,
+        Some("off") => false,
+        Some(_) => {
+            msg.channel_id
+                .say(&ctx.http, "Ghost ping notices can only be `on` or `off`.")
+                .await?;
+            return Ok(());
+        }
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    if is_enabled(db, guild_id).await? {
+                        "Ghost ping notices are on."
+                    } else {
+                        "Ghost ping notices are off."
+                    },
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    db.collection("ping-settings")
+        .update_one(
+            doc! { "guild": guild_id },
+            doc! {
+                "$set": { "ghost": enabled },
+                "$setOnInsert": { "guild": guild_id },
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
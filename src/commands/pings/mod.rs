@@ -0,0 +1,621 @@
+//! Keeps track of who pinged whom. Each mention is stored in `pings` as its own record, and
+//! records older than the retention window are deleted every so often as new ones come in.
+use crate::{
+    commands::whois::parse_id,
+    db,
+    pagination::{truncate, Page},
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use ghost::GHOST_COMMAND;
+use lazy_static::lazy_static;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::{FindOptions, UpdateOptions},
+    Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{
+        macros::{command, group},
+        Args, CommandResult,
+    },
+    model::{
+        channel::Message,
+        id::{RoleId, UserId},
+        user::User,
+    },
+    utils::Colour,
+};
+use stats::STATS_COMMAND;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+use tokio::stream::StreamExt;
+
+pub use afk::AFK_COMMAND;
+pub use ghost::{on_message_delete, on_message_update};
+
+mod afk;
+mod ghost;
+mod stats;
+
+#[group]
+#[prefix = "pings"]
+#[only_in(guilds)]
+#[commands(stats, ghost, retention)]
+#[description = "Keep an eye on pings. See `:whopinged` for your own pings."]
+struct Pings;
+
+/// How long pings are kept unless the guild picks something else with `:pings retention`.
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+/// The longest that guilds can keep pings.
+const MAX_RETENTION_DAYS: i64 = 365;
+
+/// How often each guild's old pings are deleted, so that it isn't done for every mention.
+const PRUNE_INTERVAL_MINUTES: i64 = 60;
+
+/// When Discord's IDs start counting from, in milliseconds since 1970.
+const DISCORD_EPOCH_MILLIS: i64 = 1_420_070_400_000;
+
+/// How much of each message is shown in the timeline.
+const PREVIEW_LENGTH: usize = 150;
+
+const WHOPINGED_TITLE: &str = "Who DARED to ping thee?";
+
+const TIME_DISPLAY_FORMAT: &str = "%b %-d %H:%M";
+
+/// Shortens a message to show on one line.
+fn preview(content: &str, length: usize) -> String {
+    let content = content
+        // Insert zero width space between ] and ( to prevent hiding messages in link URLs
+        .replace("](", "]\u{200b}(")
+        .replace('\n', " ");
+    if content.chars().count() > length {
+        format!("{}...", content.chars().take(length).collect::<String>())
+    } else {
+        content
+    }
+}
+
+/// A record of one mention in a message.
+fn ping_record(msg: &Message, guild_id: u64, kind: &str, target: Option<u64>) -> Document {
+    let mut record = doc! {
+        "guild": guild_id,
+        "time": Bson::DateTime(msg.timestamp.with_timezone(&Utc)),
+        "kind": kind,
+        "content": &msg.content,
+        "author": msg.author.id.as_u64(),
+        "channel_id": msg.channel_id.as_u64(),
+        "message_id": msg.id.as_u64(),
+        // Bots, including me, ping people on purpose less often, so they can be left out
+        "bot": msg.author.bot,
+    };
+    if let Some(target) = target {
+        record.insert("target", target);
+    }
+    record
+}
+
+/// Who a message pings, as the kind of ping and the role or user ID.
+pub type Target = (&'static str, Option<u64>);
+
+/// Lists who a message pings. Replies ping the author of the message they reply to, unless the
+/// ping was turned off, and that's told apart from mentioning them in the message.
+fn targets_of(
+    content: &str,
+    mention_everyone: bool,
+    roles: &[RoleId],
+    users: &[User],
+    replied_to: Option<UserId>,
+) -> Vec<Target> {
+    let mut targets = Vec::new();
+    if mention_everyone {
+        // Discord says whether @everyone or @here was used, but not which, so check the message
+        let here = content.contains("@here");
+        if here {
+            targets.push(("here", None));
+        }
+        if !here || content.contains("@everyone") {
+            targets.push(("everyone", None));
+        }
+    }
+    for role_id in roles {
+        targets.push(("role", Some(*role_id.as_u64())));
+    }
+    for user in users {
+        let mentioned = content.contains(&format!("<@{}>", user.id))
+            || content.contains(&format!("<@!{}>", user.id));
+        let kind = if !mentioned && replied_to == Some(user.id) {
+            "reply"
+        } else {
+            "user"
+        };
+        targets.push((kind, Some(*user.id.as_u64())));
+    }
+    targets
+}
+
+/// The author of the message that the message replies to.
+fn replied_to(msg: &Message) -> Option<UserId> {
+    msg.referenced_message
+        .as_ref()
+        .map(|referenced| referenced.author.id)
+}
+
+/// How a target is written in a message, which doesn't ping anyone in an embed.
+fn describe_target((kind, target): &Target) -> String {
+    match *kind {
+        "everyone" => String::from("@everyone"),
+        "here" => String::from("@here"),
+        "role" => format!("<@&{}>", target.unwrap_or(0)),
+        _ => format!("<@{}>", target.unwrap_or(0)),
+    }
+}
+
+/// Records the pings in messages from bots, which the framework ignores.
+pub async fn on_message(ctx: &Context, msg: &Message) {
+    if !msg.author.bot {
+        return;
+    }
+    if let Err(why) = check_mentions(ctx, msg).await {
+        println!("Checking mentions had an error: {:?}", why);
+    }
+}
+
+/// Records the pings in a message. The hooks can check the same message more than once, so pings
+/// that were already recorded are skipped. This also welcomes back members who were AFK.
+pub async fn check_mentions(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => return Ok(()),
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    afk::check_return(ctx, db, guild_id, msg).await?;
+
+    let targets = targets_of(
+        &msg.content,
+        msg.mention_everyone,
+        &msg.mention_roles,
+        &msg.mentions,
+        replied_to(msg),
+    );
+    if targets.is_empty() {
+        return Ok(());
+    }
+    ghost::remember(msg, guild_id, &targets, replied_to(msg));
+
+    let pings = db.collection("pings");
+    let mut new_targets = Vec::new();
+    for (kind, target) in &targets {
+        let filter = doc! {
+            "guild": guild_id,
+            "message_id": msg.id.as_u64(),
+            "kind": kind,
+            "target": target.map_or(Bson::Null, Bson::from),
+        };
+        let result = pings
+            .update_one(
+                filter,
+                doc! { "$setOnInsert": ping_record(msg, guild_id, kind, *target) },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        if result.upserted_id.is_some() {
+            new_targets.push((*kind, *target));
+        }
+    }
+    afk::forward(ctx, db, guild_id, msg, &new_targets).await?;
+    if prune_due(guild_id) {
+        prune(db, guild_id, retention_days(db, guild_id).await?).await?;
+    }
+
+    Ok(())
+}
+
+/// Whether it's been long enough since the guild's old pings were last deleted. If so, it's
+/// counted as done now.
+fn prune_due(guild_id: u64) -> bool {
+    lazy_static! {
+        static ref LAST_PRUNED: Mutex<HashMap<u64, DateTime<Utc>>> = Mutex::new(HashMap::new());
+    }
+    let mut last_pruned = LAST_PRUNED.lock().unwrap();
+    let now = Utc::now();
+    if let Some(time) = last_pruned.get(&guild_id) {
+        if now - *time < Duration::minutes(PRUNE_INTERVAL_MINUTES) {
+            return false;
+        }
+    }
+    last_pruned.insert(guild_id, now);
+    true
+}
+
+/// The smallest message ID that Discord could give out at the time. Message IDs count up from the
+/// time they were sent, so older messages have smaller IDs.
+fn first_id_at(time: DateTime<Utc>) -> u64 {
+    ((time.timestamp_millis() - DISCORD_EPOCH_MILLIS).max(0) as u64) << 22
+}
+
+/// Deletes the guild's pings that are older than the retention window, including the ones queued
+/// for AFK members. Pings from before they were timed, in `past-pings`, are dated by their message
+/// ID instead.
+async fn prune(db: &Database, guild_id: u64, days: i64) -> CommandResult {
+    let cutoff = Utc::now() - Duration::days(days);
+    db.collection("pings")
+        .delete_many(
+            doc! {
+                "guild": guild_id,
+                "time": { "$lt": Bson::DateTime(cutoff) },
+            },
+            None,
+        )
+        .await?;
+    db.collection("past-pings")
+        .delete_many(
+            doc! {
+                "guild": guild_id,
+                "$or": [
+                    { "message_id": { "$lt": first_id_at(cutoff) } },
+                    { "message_id": { "$exists": false } },
+                ],
+            },
+            None,
+        )
+        .await?;
+    db.collection("afk")
+        .update_many(
+            doc! { "guild": guild_id },
+            doc! { "$pull": { "queue": { "time": { "$lt": Bson::DateTime(cutoff) } } } },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// How many days the guild keeps pings for.
+async fn retention_days(db: &Database, guild_id: u64) -> CommandResult<i64> {
+    Ok(db
+        .collection("ping-settings")
+        .find_one(doc! { "guild": guild_id }, None)
+        .await?
+        .and_then(|settings| settings.get_i64("retention").ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS))
+}
+
+#[command]
+#[usage = "[days]"]
+#[example = ""]
+#[example = "7"]
+#[required_permissions("MANAGE_GUILD")]
+/// Set how many days I keep pings for, up to 365. Older pings, including what the message said,
+/// are deleted about once an hour as new pings come in. It's 30 days at first. Without any
+/// arguments, this says how long it is. Requires that you can manage the guild (the MANAGE_GUILD
+/// permission).
+async fn retention(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    if args.is_empty() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "I keep pings for {} days.",
+                    retention_days(db, guild_id).await?
+                ),
+            )
+            .await?;
+        return Ok(());
+    }
+    let days = match args.single::<i64>() {
+        Ok(days) if (1..=MAX_RETENTION_DAYS).contains(&days) => days,
+        _ => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "The number of days should be between 1 and {}.",
+                        MAX_RETENTION_DAYS
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    db.collection("ping-settings")
+        .update_one(
+            doc! { "guild": guild_id },
+            doc! {
+                "$set": { "retention": days },
+                "$setOnInsert": { "guild": guild_id },
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    prune(db, guild_id, days).await?;
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
+
+/// Filters for the pings that pinged the message's author, to use with `$or`. Roles are pinged by
+/// the roles the member has now, since the old ones aren't known.
+fn pinged_member(msg: &Message) -> Vec<Document> {
+    let roles = msg.member.as_ref().map_or_else(Vec::new, |member| {
+        member
+            .roles
+            .iter()
+            .map(|role_id| *role_id.as_u64())
+            .collect::<Vec<u64>>()
+    });
+    vec![
+        doc! { "kind": "everyone" },
+        doc! { "kind": "here" },
+        doc! { "kind": "user", "target": msg.author.id.as_u64() },
+        doc! { "kind": "reply", "target": msg.author.id.as_u64() },
+        doc! { "kind": "role", "target": { "$in": roles } },
+    ]
+}
+
+/// Reads a time for `since` and `until`: either how long ago, like `3d` or `12h`, or a date like
+/// `2021-01-31`.
+fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Some(DateTime::from_utc(date.and_hms(0, 0, 0), Utc));
+    }
+    let unit = text.chars().last()?;
+    let amount = text[..text.len() - unit.len_utf8()].parse::<i64>().ok()?;
+    let minutes = match unit {
+        'm' => 1,
+        'h' => 60,
+        'd' => 60 * 24,
+        'w' => 60 * 24 * 7,
+        _ => return None,
+    };
+    // Checked so that huge amounts are refused instead of overflowing
+    let ago = Duration::milliseconds(amount.checked_mul(minutes * 60 * 1000)?);
+    Utc::now().checked_sub_signed(ago)
+}
+
+/// Which pings to list in the timeline.
+#[derive(Default)]
+struct TimelineFilter {
+    author: Option<u64>,
+    channel: Option<u64>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// Leave out pings from bots.
+    humans: bool,
+}
+
+impl TimelineFilter {
+    fn to_doc(&self, guild_id: u64, targets: Vec<Document>) -> Document {
+        let mut filter = doc! { "guild": guild_id, "$or": targets };
+        if let Some(author) = self.author {
+            filter.insert("author", author);
+        }
+        if let Some(channel) = self.channel {
+            filter.insert("channel_id", channel);
+        }
+        let mut time = Document::new();
+        if let Some(since) = self.since {
+            time.insert("$gte", Bson::DateTime(since));
+        }
+        if let Some(until) = self.until {
+            time.insert("$lt", Bson::DateTime(until));
+        }
+        if !time.is_empty() {
+            filter.insert("time", time);
+        }
+        if self.humans {
+            filter.insert("bot", doc! { "$ne": true });
+        }
+        filter
+    }
+}
+
+/// Describes a ping in one line with a link to the message.
+fn describe_ping(ping: &Document, guild_id: u64) -> String {
+    let content = preview(ping.get_str("content").unwrap_or_default(), PREVIEW_LENGTH);
+    let action = match ping.get_str("kind").unwrap_or_default() {
+        "everyone" => String::from("pinged @everyone"),
+        "here" => String::from("pinged @here"),
+        "role" => format!("pinged <@&{}>", ping.get_i64("target").unwrap_or(0)),
+        "reply" => String::from("replied to you"),
+        _ => String::from("pinged you"),
+    };
+    format!(
+        "**{}** [<@{}>{} {}](https://discord.com/channels/{}/{}/{}) in <#{}>: {}",
+        ping.get_datetime("time").map_or_else(
+            |_| String::from("?"),
+            |time| time.format(TIME_DISPLAY_FORMAT).to_string()
+        ),
+        ping.get_i64("author").unwrap_or(0),
+        if ping.get_bool("bot").unwrap_or(false) {
+            " (bot)"
+        } else {
+            ""
+        },
+        action,
+        guild_id,
+        ping.get_i64("channel_id").unwrap_or(0),
+        ping.get_i64("message_id").unwrap_or(0),
+        ping.get_i64("channel_id").unwrap_or(0),
+        content
+    )
+}
+
+#[command]
+#[only_in(guilds)]
+#[aliases("whoping", "quienmehahechoping")]
+#[usage = "[from <user>] [in <channel>] [since <time>] [until <time>] [humans] [page number]"]
+#[example = ""]
+#[example = "from @moofy"]
+#[example = "in #general since 3d humans"]
+#[example = "since 2021-01-01 until 2021-02-01 2"]
+/// Lists your pings, newest first (assuming Moofy has been paying attention). This includes
+/// @everyone, @here, and pings of the roles you have now, which are also grouped by role. You can
+/// list only the pings from someone, in a channel, or `since` and `until` a time, which is either
+/// how long ago, like `12h`, `3d`, or `2w`, or a date like `2021-01-31`. Pings are forgotten after
+/// 30 days, unless the mods change it with `:pings retention`. Replies that pinged you are listed
+/// too, and `humans` leaves out pings from bots.
+async fn whopinged(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut filter = TimelineFilter::default();
+    let mut page_number = 1;
+    while !args.is_empty() {
+        let current = args.single::<String>()?.to_lowercase();
+        let value = match current.as_str() {
+            "from" | "in" | "since" | "until" => args.single::<String>().ok(),
+            _ => None,
+        };
+        let valid = match (current.as_str(), value.as_deref()) {
+            ("from", Some(value)) => {
+                filter.author = parse_id(value);
+                filter.author.is_some()
+            }
+            ("in", Some(value)) => {
+                filter.channel = parse_id(value);
+                filter.channel.is_some()
+            }
+            ("since", Some(value)) => {
+                filter.since = parse_time(value);
+                filter.since.is_some()
+            }
+            ("until", Some(value)) => {
+                filter.until = parse_time(value);
+                filter.until.is_some()
+            }
+            ("humans", None) => {
+                filter.humans = true;
+                true
+            }
+            _ => match current.parse::<usize>() {
+                Ok(number) => {
+                    page_number = number;
+                    true
+                }
+                Err(_) => false,
+            },
+        };
+        if !valid {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "I don't understand. Do `:help whopinged` for what you can filter by.",
+                )
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let mut cursor = db
+        .collection("pings")
+        .find(
+            filter.to_doc(guild_id, pinged_member(msg)),
+            FindOptions::builder().sort(doc! { "time": -1 }).build(),
+        )
+        .await?;
+    let mut lines = Vec::new();
+    // The number of pings for each role and the latest one, which comes first
+    let mut by_role: BTreeMap<u64, (usize, String)> = BTreeMap::new();
+    while let Some(doc_result) = cursor.next().await {
+        let ping = doc_result?;
+        let line = describe_ping(&ping, guild_id);
+        if ping.get_str("kind").ok() == Some("role") {
+            let (count, _) = by_role
+                .entry(ping.get_i64("target").unwrap_or(0) as u64)
+                .or_insert_with(|| (0, line.clone()));
+            *count += 1;
+        }
+        lines.push(line);
+    }
+    let page = Page::of(&lines, page_number);
+    let mut role_fields = Vec::new();
+    for (role_id, (count, latest)) in by_role {
+        let name = match RoleId(role_id).to_role_cached(&ctx.cache).await {
+            Some(role) => format!("@{}", role.name),
+            None => format!("Role {}", role_id),
+        };
+        role_fields.push((
+            format!("{} ({} pings)", name, count),
+            truncate(format!("Latest: {}", latest), 1024),
+        ));
+    }
+    let footer_text = format!("{} pings. {}", lines.len(), page.footer());
+    let description = if lines.is_empty() {
+        String::from("Nobody, as far as I know.")
+    } else {
+        truncate(page.text, 2000)
+    };
+
+    // Embeds can only have 25 fields and 6000 characters in all, so the roles that don't fit are
+    // counted in a last field instead. 5900 leaves room for that field.
+    let role_count = role_fields.len();
+    let mut length =
+        WHOPINGED_TITLE.len() + description.chars().count() + footer_text.chars().count();
+    let mut shown_roles = Vec::new();
+    for (name, value) in role_fields {
+        let field_length = name.chars().count() + value.chars().count();
+        if shown_roles.len() == 24 || length + field_length > 5900 {
+            break;
+        }
+        length += field_length;
+        shown_roles.push((name, value));
+    }
+    let hidden_roles = role_count - shown_roles.len();
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.title(WHOPINGED_TITLE);
+                embed.colour(Colour::MAGENTA);
+                embed.description(description);
+                for (name, value) in shown_roles {
+                    embed.field(name, value, false);
+                }
+                if hidden_roles > 0 {
+                    embed.field(
+                        "More roles",
+                        format!("…and {} more roles", hidden_roles),
+                        false,
+                    );
+                }
+                embed.footer(|footer| footer.text(footer_text));
+                embed
+            });
+            message.content(
+                "Tip: Discord has an inbox (ctrl/command + i) with a list of your past mentions.",
+            );
+            message
+        })
+        .await?;
+
+    Ok(())
+}
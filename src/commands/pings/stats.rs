@@ -0,0 +1,283 @@
+//! Counts up the stored pings: who pings whom, which roles get pinged, and where @everyone is
+//! used. Only pings within the retention window are counted.
+use super::pinged_member;
+use crate::{db, pagination::truncate};
+use chrono::{Duration, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+    utils::Colour,
+};
+use std::{collections::HashMap, hash::Hash};
+use tokio::stream::StreamExt;
+
+/// How many people, roles, or channels are listed in each leaderboard.
+const TOP_COUNT: usize = 5;
+
+/// How many weeks the trend goes back, which is about the default retention window.
+const TREND_WEEKS: i64 = 5;
+
+/// The longest bar in the trend.
+const BAR_WIDTH: usize = 16;
+
+/// How many role pings in a week it takes to be flagged in the mod view.
+const ROLE_PING_LIMIT: usize = 10;
+
+/// Sorts the counts, most first. Ties are broken by the key so the order doesn't change between
+/// runs.
+fn sort_counts<K: Ord>(counts: HashMap<K, usize>) -> Vec<(K, usize)> {
+    let mut counts = counts.into_iter().collect::<Vec<(K, usize)>>();
+    counts.sort_by(|(a_key, a), (b_key, b)| b.cmp(a).then(a_key.cmp(b_key)));
+    counts
+}
+
+/// Counts how many times each key appears, most first.
+fn leaderboard<K: Hash + Ord>(keys: impl Iterator<Item = K>) -> Vec<(K, usize)> {
+    let mut counts = HashMap::new();
+    for key in keys {
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    sort_counts(counts)
+}
+
+/// Lists the top entries as lines, or says there aren't any.
+fn describe_leaderboard<K>(
+    counts: &[(K, usize)],
+    describe: impl Fn(&K, usize) -> String,
+) -> String {
+    if counts.is_empty() {
+        return String::from("Nothing yet.");
+    }
+    counts
+        .iter()
+        .take(TOP_COUNT)
+        .enumerate()
+        .map(|(index, (key, count))| format!("{}. {}", index + 1, describe(key, *count)))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+async fn find_pings(ctx: &Context, filter: Document) -> CommandResult<Vec<Document>> {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let mut cursor = db.collection("pings").find(filter, None).await?;
+    let mut pings = Vec::new();
+    while let Some(doc_result) = cursor.next().await {
+        pings.push(doc_result?);
+    }
+    Ok(pings)
+}
+
+fn id_of(ping: &Document, key: &str) -> u64 {
+    ping.get_i64(key).unwrap_or(0) as u64
+}
+
+/// Shows how many pings there were each week as bars, oldest first.
+fn describe_trend(pings: &[Document]) -> String {
+    let now = Utc::now();
+    let mut weeks = vec![0; TREND_WEEKS as usize];
+    for ping in pings {
+        if let Ok(time) = ping.get_datetime("time") {
+            let week = (now - *time).num_weeks();
+            if (0..TREND_WEEKS).contains(&week) {
+                weeks[week as usize] += 1;
+            }
+        }
+    }
+    let most = weeks.iter().copied().max().unwrap_or(0).max(1);
+    weeks
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(week, &count)| {
+            format!(
+                "`{}` {} {}",
+                (now - Duration::weeks(week as i64 + 1)).format("%b %d"),
+                "█".repeat(count * BAR_WIDTH / most),
+                count
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[command]
+#[usage = "[mod]"]
+#[example = ""]
+#[example = "mod"]
+/// Shows who pings you the most, which roles get pinged the most, where @everyone and @here get
+/// used, and how many times you've been pinged each week. `mod` instead lists the members who've
+/// pinged roles more than 10 times in the past week, which requires that you can manage the guild
+/// (the MANAGE_GUILD permission).
+async fn stats(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let guild_id = guild.id.as_u64().to_owned();
+
+    if args.single::<String>().ok().as_deref() == Some("mod") {
+        if !guild.member_permissions(msg.author.id).manage_guild() {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "Only people who can manage the server can see that.",
+                )
+                .await?;
+            return Ok(());
+        }
+        let since = Utc::now() - Duration::weeks(1);
+        let pings = find_pings(
+            ctx,
+            doc! {
+                "guild": guild_id,
+                "kind": { "$in": ["role", "everyone", "here"] },
+                "time": { "$gte": Bson::DateTime(since) },
+            },
+        )
+        .await?;
+        let role_pings = leaderboard(
+            pings
+                .iter()
+                .filter(|ping| ping.get_str("kind").ok() == Some("role"))
+                .map(|ping| id_of(ping, "author")),
+        );
+        let flagged = role_pings
+            .iter()
+            .filter(|(_, count)| *count > ROLE_PING_LIMIT)
+            .map(|(author, count)| {
+                let roles = leaderboard(
+                    pings
+                        .iter()
+                        .filter(|ping| {
+                            ping.get_str("kind").ok() == Some("role")
+                                && id_of(ping, "author") == *author
+                        })
+                        .map(|ping| id_of(ping, "target")),
+                );
+                format!(
+                    "<@{}> pinged roles {} times, most often <@&{}> ({} times)",
+                    author,
+                    count,
+                    roles.first().map_or(0, |(role, _)| *role),
+                    roles.first().map_or(0, |(_, count)| *count)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let everyone_pings = leaderboard(
+            pings
+                .iter()
+                .filter(|ping| ping.get_str("kind").ok() != Some("role"))
+                .map(|ping| id_of(ping, "author")),
+        );
+
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.title("Pings in the past week");
+                    embed.colour(Colour::MAGENTA);
+                    embed.description(if flagged.is_empty() {
+                        format!("Nobody pinged roles more than {} times.", ROLE_PING_LIMIT)
+                    } else {
+                        truncate(flagged, 2000)
+                    });
+                    embed.field(
+                        "Most role pings",
+                        describe_leaderboard(&role_pings, |author, count| {
+                            format!("<@{}> ({})", author, count)
+                        }),
+                        true,
+                    );
+                    embed.field(
+                        "Most @everyone and @here",
+                        describe_leaderboard(&everyone_pings, |author, count| {
+                            format!("<@{}> ({})", author, count)
+                        }),
+                        true,
+                    );
+                    embed
+                });
+                message
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let my_pings = find_pings(ctx, doc! { "guild": guild_id, "$or": pinged_member(msg) }).await?;
+    let top_pingers = leaderboard(
+        my_pings
+            .iter()
+            .filter(|ping| matches!(ping.get_str("kind"), Ok("user") | Ok("reply")))
+            .map(|ping| id_of(ping, "author")),
+    );
+    let guild_pings = find_pings(
+        ctx,
+        doc! { "guild": guild_id, "kind": { "$in": ["role", "everyone", "here"] } },
+    )
+    .await?;
+    let top_roles = leaderboard(
+        guild_pings
+            .iter()
+            .filter(|ping| ping.get_str("kind").ok() == Some("role"))
+            .map(|ping| id_of(ping, "target")),
+    );
+    let mut by_channel: HashMap<u64, (usize, usize)> = HashMap::new();
+    for ping in &guild_pings {
+        let channel = id_of(ping, "channel_id");
+        match ping.get_str("kind") {
+            Ok("everyone") => by_channel.entry(channel).or_insert((0, 0)).0 += 1,
+            Ok("here") => by_channel.entry(channel).or_insert((0, 0)).1 += 1,
+            _ => {}
+        }
+    }
+    let channels = sort_counts(
+        by_channel
+            .iter()
+            .map(|(channel, (everyone, here))| (*channel, everyone + here))
+            .collect(),
+    );
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.title("Ping stats");
+                embed.colour(Colour::MAGENTA);
+                embed.field(
+                    "Who pings you the most",
+                    describe_leaderboard(&top_pingers, |author, count| {
+                        format!("<@{}> ({})", author, count)
+                    }),
+                    true,
+                );
+                embed.field(
+                    "Most pinged roles",
+                    describe_leaderboard(&top_roles, |role, count| {
+                        format!("<@&{}> ({})", role, count)
+                    }),
+                    true,
+                );
+                embed.field(
+                    "@everyone and @here by channel",
+                    describe_leaderboard(&channels, |channel, _| {
+                        let (everyone, here) = by_channel[channel];
+                        format!("<#{}> ({} @everyone, {} @here)", channel, everyone, here)
+                    }),
+                    false,
+                );
+                embed.field("Your pings each week", describe_trend(&my_pings), false);
+                embed
+            });
+            message
+        })
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,413 @@
+//! Keeps a record of who changed the whois data and options, and when. Each record is stored in
+//! `whois-audit` with the fields that changed and their values before and after. Records are kept
+//! for the number of days in the `auditdays` option.
+use super::{
+    parse_id,
+    privacy::{Privacy, Viewer},
+    template::display_value,
+};
+use crate::{
+    db,
+    pagination::{truncate, Page},
+};
+use chrono::{Duration, Utc};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::FindOptions,
+    Collection, Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, id::UserId},
+    utils::Colour,
+};
+use std::collections::{BTreeSet, HashMap};
+use tokio::stream::StreamExt;
+
+/// How long records are kept unless the guild picks something else with the `auditdays` option.
+const DEFAULT_RETENTION_DAYS: i64 = 365;
+
+/// The longest that guilds can keep records, in days.
+pub const MAX_RETENTION_DAYS: i64 = 3650;
+
+/// The kinds of records, which `:whois audit` can filter by.
+const ACTIONS: [&str; 11] = [
+    "fetch", "config", "set", "unset", "dataset", "field", "schema", "rolemap", "pin", "optout",
+    "optin",
+];
+
+const TIME_DISPLAY_FORMAT: &str = "%b %-d, %Y %H:%M UTC";
+
+/// A field whose value changed. Missing values are stored as null.
+pub fn change(field: &str, before: Option<Bson>, after: Option<Bson>) -> Document {
+    doc! {
+        "field": field,
+        "before": before.unwrap_or(Bson::Null),
+        "after": after.unwrap_or(Bson::Null),
+    }
+}
+
+/// The fields that differ between two versions of a whois entry.
+pub fn diff(before: Option<&Document>, after: Option<&Document>) -> Vec<Document> {
+    let fields = before
+        .iter()
+        .chain(after.iter())
+        .flat_map(|entry| entry.keys())
+        .filter(|key| !key.starts_with('_'))
+        .collect::<BTreeSet<&String>>();
+    fields
+        .into_iter()
+        .filter_map(|field| {
+            let old = before.and_then(|entry| entry.get(field));
+            let new = after.and_then(|entry| entry.get(field));
+            if old == new {
+                None
+            } else {
+                Some(change(field, old.cloned(), new.cloned()))
+            }
+        })
+        .collect()
+}
+
+/// A record of something done to the whois data.
+pub struct Record {
+    pub action: &'static str,
+    pub actor: UserId,
+    /// The member whose entry changed, if it's about one entry.
+    pub user: Option<String>,
+    /// What happened, in words.
+    pub note: String,
+    pub changes: Vec<Document>,
+}
+
+impl Record {
+    fn to_doc(&self, guild_id: u64) -> Document {
+        let mut doc = doc! {
+            "_guild": guild_id,
+            "time": Bson::DateTime(Utc::now()),
+            "action": self.action,
+            "actor": self.actor.to_string(),
+            "note": &self.note,
+            "changes": self.changes.clone(),
+        };
+        if let Some(user) = &self.user {
+            doc.insert("user", user);
+        }
+        doc
+    }
+}
+
+/// Saves the records and deletes the guild's records that are older than `auditdays`. The
+/// change that was recorded has already been made, so this only prints errors instead of failing
+/// the command.
+pub async fn record(db: &Database, guild_id: u64, records: Vec<Record>) {
+    if records.is_empty() {
+        return;
+    }
+    let docs = records
+        .iter()
+        .map(|record| record.to_doc(guild_id))
+        .collect::<Vec<Document>>();
+    if let Err(why) = db.collection("whois-audit").insert_many(docs, None).await {
+        println!("Saving whois audit records had an error: {:?}", why);
+    }
+    if let Err(why) = prune(db, guild_id).await {
+        println!("Deleting old whois audit records had an error: {:?}", why);
+    }
+}
+
+/// How many days the guild keeps records for, from the `auditdays` option.
+async fn retention_days(db: &Database, guild_id: u64) -> CommandResult<i64> {
+    Ok(db
+        .collection("whois-settings")
+        .find_one(doc! { "_guild": guild_id }, None)
+        .await?
+        .and_then(|settings| {
+            settings
+                .get_str("auditdays")
+                .ok()
+                .and_then(|days| days.parse::<i64>().ok())
+        })
+        .unwrap_or(DEFAULT_RETENTION_DAYS))
+}
+
+/// Deletes the guild's records that are older than `auditdays`, since they can have copies of
+/// whois entries.
+async fn prune(db: &Database, guild_id: u64) -> CommandResult {
+    let days = retention_days(db, guild_id).await?;
+    db.collection("whois-audit")
+        .delete_many(
+            doc! {
+                "_guild": guild_id,
+                "time": { "$lt": Bson::DateTime(Utc::now() - Duration::days(days)) },
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// The entries matching the filter by member ID, for comparing them before and after a fetch.
+pub async fn load_entries(
+    whois_data: &Collection,
+    filter: Document,
+) -> CommandResult<HashMap<String, Document>> {
+    let mut cursor = whois_data.find(filter, None).await?;
+    let mut entries = HashMap::new();
+    while let Some(doc_result) = cursor.next().await {
+        let entry = doc_result?;
+        if let Ok(user_id) = entry.get_str("_user").map(String::from) {
+            entries.insert(user_id, entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Records a fetch: one record for the fetch itself and one for each entry that changed.
+pub fn fetch_records(
+    actor: UserId,
+    dataset: &str,
+    url: &str,
+    before: &HashMap<String, Document>,
+    after: &HashMap<String, Document>,
+) -> Vec<Record> {
+    let user_ids = before
+        .keys()
+        .chain(after.keys())
+        .collect::<BTreeSet<&String>>();
+    let (mut added, mut changed, mut removed) = (0, 0, 0);
+    let mut records = Vec::new();
+    for user_id in user_ids {
+        let (old, new) = (before.get(user_id), after.get(user_id));
+        let changes = diff(old, new);
+        if changes.is_empty() {
+            continue;
+        }
+        let note = match (old, new) {
+            (None, _) => {
+                added += 1;
+                "Added by a fetch."
+            }
+            (_, None) => {
+                removed += 1;
+                "Removed by a fetch."
+            }
+            _ => {
+                changed += 1;
+                "Changed by a fetch."
+            }
+        };
+        records.push(Record {
+            action: "fetch",
+            actor,
+            user: Some(user_id.clone()),
+            note: format!("{} (`{}` dataset)", note, dataset),
+            changes,
+        });
+    }
+    records.insert(
+        0,
+        Record {
+            action: "fetch",
+            actor,
+            user: None,
+            note: format!(
+                "Fetched the `{}` dataset from <{}>: {} added, {} changed, {} removed.",
+                dataset, url, added, changed, removed
+            ),
+            changes: Vec::new(),
+        },
+    );
+    records
+}
+
+/// Whether the viewer can see the field in the member's entry. Fields that aren't in the entry
+/// anymore are checked against the current field settings.
+fn can_see_field(privacy: &Privacy, user_id: &str, field: &str) -> bool {
+    privacy
+        .visible_document(&doc! { "_user": user_id, field: Bson::Null })
+        .contains_key(field)
+}
+
+fn describe_value(value: Option<&Bson>) -> String {
+    match value.and_then(display_value) {
+        Some(value) => format!("`{}`", value.replace('`', "'")),
+        None => String::from("*nothing*"),
+    }
+}
+
+/// Describes a record and its changes in a few lines.
+fn describe_record(record: &Document, privacy: &Privacy, show_user: bool) -> String {
+    let user_id = record.get_str("user").ok();
+    let mut text = format!(
+        "**{}** <@{}> `{}`",
+        record.get_datetime("time").map_or_else(
+            |_| String::from("?"),
+            |time| time.format(TIME_DISPLAY_FORMAT).to_string()
+        ),
+        record.get_str("actor").unwrap_or_default(),
+        record.get_str("action").unwrap_or_default()
+    );
+    if let (Some(user_id), true) = (user_id, show_user) {
+        text.push_str(&format!(" for <@{}>", user_id));
+    }
+    let note = record.get_str("note").unwrap_or_default();
+    if !note.is_empty() {
+        text.push_str(": ");
+        text.push_str(note);
+    }
+    let mut hidden = 0;
+    if let Ok(changes) = record.get_array("changes") {
+        for change in changes.iter().filter_map(|change| change.as_document()) {
+            let field = change.get_str("field").unwrap_or_default();
+            // Options aren't whois fields, so only entry changes are hidden
+            if let Some(user_id) = user_id {
+                if !can_see_field(privacy, user_id, field) {
+                    hidden += 1;
+                    continue;
+                }
+            }
+            text.push_str(&format!(
+                "\n- `{}`: {} → {}",
+                field,
+                describe_value(change.get("before")),
+                describe_value(change.get("after"))
+            ));
+        }
+    }
+    if hidden > 0 {
+        text.push_str(&format!("\n- {} hidden fields", hidden));
+    }
+    text
+}
+
+/// Lists the records matching the filter, newest first.
+async fn send_records(
+    ctx: &Context,
+    msg: &Message,
+    db: &Database,
+    privacy: &Privacy,
+    filter: Document,
+    show_user: bool,
+    page_number: usize,
+) -> CommandResult {
+    let mut cursor = db
+        .collection("whois-audit")
+        .find(
+            filter,
+            FindOptions::builder().sort(doc! { "time": -1 }).build(),
+        )
+        .await?;
+    let mut lines = Vec::new();
+    while let Some(doc_result) = cursor.next().await {
+        lines.push(describe_record(&doc_result?, privacy, show_user));
+    }
+    let page = Page::of(&lines, page_number);
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.colour(Colour::MAGENTA);
+                embed.description(if lines.is_empty() {
+                    String::from("Nothing has been recorded yet.")
+                } else {
+                    truncate(page.text, 2000)
+                });
+                embed.footer(|footer| {
+                    footer.text(format!("{} records. {}", lines.len(), page.footer()))
+                });
+                embed
+            });
+            message
+        })
+        .await?;
+    Ok(())
+}
+
+#[command]
+#[usage = "<user id or mention> [page number]"]
+#[example = "393248490739859458"]
+#[example = "@moofy 2"]
+#[required_permissions("MANAGE_GUILD")]
+/// List the changes to someone's whois entry, newest first, with who made them and when. This
+/// includes fetches that changed their entry, their own `:whois set` and `:whois unset` changes,
+/// names pinned to them, and opting out and back in. Fields you can't see are left out. Requires
+/// that you can manage the guild (the MANAGE_GUILD permission).
+async fn history(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let guild_id = guild.id.as_u64().to_owned();
+
+    let user_id = match args.single::<String>().ok().as_deref().and_then(parse_id) {
+        Some(user_id) => user_id.to_string(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "Whose history? Mention them or give their ID.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let page_number = args.single::<usize>().unwrap_or(1);
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let privacy = Privacy::load(db, guild_id, Viewer::from_message(&guild, msg)).await?;
+
+    send_records(
+        ctx,
+        msg,
+        db,
+        &privacy,
+        doc! { "_guild": guild_id, "user": user_id },
+        false,
+        page_number,
+    )
+    .await
+}
+
+#[command]
+#[usage = "[fetch|config|set|unset|dataset|field|schema|rolemap|pin|optout|optin] [page number]"]
+#[example = ""]
+#[example = "config"]
+#[example = "fetch 2"]
+#[required_permissions("MANAGE_GUILD")]
+/// List every recorded fetch, option change, entry edit, field setting, column type, rolemap rule,
+/// pin, and opt-out, newest first, with who made them and when. You can only list one kind of
+/// change. Fields you can't see are left out. Records are kept for a year unless the `auditdays`
+/// option (see `:help whois config`) says otherwise. Requires that you can manage the guild (the
+/// MANAGE_GUILD permission).
+async fn audit(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let guild_id = guild.id.as_u64().to_owned();
+
+    let mut filter = doc! { "_guild": guild_id };
+    if let Some(action) = args.current().map(|action| action.to_lowercase()) {
+        if ACTIONS.contains(&action.as_str()) {
+            args.advance();
+            filter.insert("action", action);
+        }
+    }
+    let page_number = args.single::<usize>().unwrap_or(1);
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let privacy = Privacy::load(db, guild_id, Viewer::from_message(&guild, msg)).await?;
+
+    send_records(ctx, msg, db, &privacy, filter, true, page_number).await
+}
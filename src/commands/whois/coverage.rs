@@ -0,0 +1,232 @@
+//! Compares the whois data against who's actually in the guild.
+use super::{
+    get_all_members,
+    privacy::{Privacy, Viewer},
+    send_csv, send_csv_privately,
+};
+use crate::{db, pagination::Page};
+use mongodb::bson::{doc, Document};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, guild::Member},
+    utils::Colour,
+};
+use std::collections::{HashMap, HashSet};
+use tokio::stream::StreamExt;
+
+#[command]
+#[usage = "[missing|stale] [page number|csv]"]
+#[example = ""]
+#[example = "missing"]
+#[example = "missing 2"]
+#[example = "stale csv"]
+#[required_permissions("MANAGE_GUILD")]
+/// See how much of the server is in the whois data. `missing` lists the members without a whois
+/// entry, and `stale` lists the entries for people who aren't in the server anymore. Either list
+/// can be paged through or downloaded as a CSV file. Like `:whois export`, the `stale` CSV only has
+/// the fields you're allowed to see and leaves out members who have opted out, and I'll DM it to
+/// you. Requires that you can manage the guild (the MANAGE_GUILD permission).
+async fn coverage(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let list = args.single::<String>().unwrap_or_default().to_lowercase();
+    let page_or_csv = args.single::<String>().unwrap_or_default().to_lowercase();
+    let as_csv = page_or_csv == "csv";
+    let page_number = page_or_csv.parse::<usize>().unwrap_or(1);
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let mut cursor = db
+        .collection("whois-data")
+        .find(doc! { "_guild": guild.id.0 }, None)
+        .await?;
+    let mut entries = HashMap::new();
+    while let Some(doc_result) = cursor.next().await {
+        let doc = doc_result?;
+        if let Ok(user_id) = doc.get_str("_user") {
+            entries.insert(String::from(user_id), doc.clone());
+        }
+    }
+
+    let members = get_all_members(ctx, guild.id)
+        .await?
+        .into_iter()
+        .filter(|member| !member.user.bot)
+        .collect::<Vec<Member>>();
+    let member_ids = members
+        .iter()
+        .map(|member| member.user.id.to_string())
+        .collect::<HashSet<String>>();
+
+    let missing = members
+        .iter()
+        .filter(|member| !entries.contains_key(&member.user.id.to_string()))
+        .collect::<Vec<&Member>>();
+    let mut stale = entries
+        .iter()
+        .filter(|(user_id, _)| !member_ids.contains(*user_id))
+        .collect::<Vec<(&String, &Document)>>();
+    stale.sort_by_key(|(user_id, _)| *user_id);
+
+    match (list.as_str(), as_csv) {
+        ("missing", true) => {
+            let rows = missing
+                .iter()
+                .map(|member| {
+                    vec![
+                        member.user.id.to_string(),
+                        member.user.tag(),
+                        member.nick.clone().unwrap_or_default(),
+                    ]
+                })
+                .collect::<Vec<Vec<String>>>();
+            send_csv(
+                ctx,
+                msg,
+                "missing.csv",
+                "Here are the members without a whois entry.",
+                &[
+                    String::from("ID"),
+                    String::from("Username"),
+                    String::from("Nickname"),
+                ],
+                &rows,
+            )
+            .await?;
+        }
+        ("stale", true) => {
+            let privacy = Privacy::load(db, guild.id.0, Viewer::from_message(&guild, msg)).await?;
+            let visible = stale
+                .iter()
+                .filter(|(user_id, _)| !privacy.is_opted_out(user_id))
+                .map(|(user_id, entry)| (*user_id, privacy.visible_fields(entry)))
+                .collect::<Vec<(&String, Vec<(String, String, bool)>)>>();
+            // The fields the viewer can see, in the order they first appear
+            let mut fields: Vec<String> = Vec::new();
+            for (_, entry_fields) in &visible {
+                for (name, _, _) in entry_fields {
+                    if !fields.contains(name) {
+                        fields.push(name.clone());
+                    }
+                }
+            }
+            let rows = visible
+                .iter()
+                .map(|(user_id, entry_fields)| {
+                    let mut row = vec![(*user_id).clone()];
+                    row.extend(fields.iter().map(|field| {
+                        entry_fields
+                            .iter()
+                            .find(|(name, _, _)| name == field)
+                            .map(|(_, value, _)| value.clone())
+                            .unwrap_or_default()
+                    }));
+                    row
+                })
+                .collect::<Vec<Vec<String>>>();
+            let mut header = vec![String::from("ID")];
+            header.extend(fields);
+            send_csv_privately(
+                ctx,
+                msg,
+                "stale.csv",
+                "Here are the whois entries for people who aren't in the server.",
+                &header,
+                &rows,
+            )
+            .await?;
+        }
+        ("missing", false) | ("stale", false) => {
+            let (title, lines) = if list == "missing" {
+                (
+                    "Members without a whois entry",
+                    missing
+                        .iter()
+                        .map(|member| format!("<@{}> ({})", member.user.id, member.user.tag()))
+                        .collect::<Vec<String>>(),
+                )
+            } else {
+                (
+                    "Whois entries for people not in the server",
+                    stale
+                        .iter()
+                        .map(|(user_id, _)| format!("`{}`", user_id))
+                        .collect::<Vec<String>>(),
+                )
+            };
+            let page = Page::of(&lines, page_number);
+            msg.channel_id
+                .send_message(&ctx.http, |message| {
+                    message.embed(|embed| {
+                        embed.colour(Colour::MAGENTA);
+                        embed.title(title);
+                        embed.description(if lines.is_empty() {
+                            String::from("Nobody!")
+                        } else {
+                            page.text
+                        });
+                        embed.footer(|footer| {
+                            footer.text(format!(
+                                "{}. Do `:whois coverage {} <page>` for another page, or `:whois coverage {} csv` for a CSV file.",
+                                page.footer(),
+                                list,
+                                list
+                            ))
+                        });
+                        embed
+                    });
+                    message
+                })
+                .await?;
+        }
+        _ => {
+            let covered = members.len() - missing.len();
+            msg.channel_id
+                .send_message(&ctx.http, |message| {
+                    message.embed(|embed| {
+                        embed.colour(Colour::MAGENTA);
+                        embed.title("Whois coverage");
+                        embed.field(
+                            "Members with an entry",
+                            format!(
+                                "{} of {} ({:.1}%)",
+                                covered,
+                                members.len(),
+                                if members.is_empty() {
+                                    0.0
+                                } else {
+                                    covered as f64 / members.len() as f64 * 100.0
+                                }
+                            ),
+                            false,
+                        );
+                        embed.field(
+                            "Members without an entry",
+                            format!("{} (see `:whois coverage missing`)", missing.len()),
+                            false,
+                        );
+                        embed.field(
+                            "Entries for people not in the server",
+                            format!("{} (see `:whois coverage stale`)", stale.len()),
+                            false,
+                        );
+                        embed
+                    });
+                    message
+                })
+                .await?;
+        }
+    }
+
+    Ok(())
+}
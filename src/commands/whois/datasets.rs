@@ -0,0 +1,371 @@
+//! Lets a guild keep more than one whois dataset, like separate staff and student rosters. The
+//! main dataset's options are the `url`, `id`, and `display` options in `whois-settings`, and its
+//! entries don't have a `_dataset`. Named datasets keep their options in `whois-datasets`, and
+//! their entries have the dataset name in `_dataset`.
+use super::{audit, dialect::Dialect, template::Template};
+use crate::{db, pagination::truncate};
+use lazy_static::lazy_static;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::UpdateOptions,
+    Database,
+};
+use regex::Regex;
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+    utils::Colour,
+};
+use std::collections::HashMap;
+use tokio::stream::StreamExt;
+
+/// What the dataset without a name is called in commands.
+pub const MAIN_DATASET: &str = "main";
+
+/// The default display template, which just mentions the member.
+const DEFAULT_DISPLAY: &str = "<@{{_user}}>";
+
+/// Whether the text can be a dataset name, as opposed to a URL.
+pub fn is_dataset_name(text: &str) -> bool {
+    lazy_static! {
+        static ref NAME: Regex = Regex::new(r"^[a-z0-9_-]{1,32}$").unwrap();
+    }
+    NAME.is_match(text)
+}
+
+/// Converts a dataset name to what's stored in `_dataset`, where the main dataset has none.
+pub fn stored_name(name: &str) -> Option<&str> {
+    if name == MAIN_DATASET {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// The name of the dataset that a whois entry came from.
+pub fn dataset_of(entry: &Document) -> &str {
+    entry.get_str("_dataset").unwrap_or(MAIN_DATASET)
+}
+
+/// A filter for the whois entries in a dataset.
+pub fn entry_filter(guild_id: u64, name: &str) -> Document {
+    match stored_name(name) {
+        Some(name) => doc! { "_guild": guild_id, "_dataset": name },
+        None => doc! { "_guild": guild_id, "_dataset": { "$exists": false } },
+    }
+}
+
+/// A dataset's fetch options.
+pub struct Dataset {
+    pub name: String,
+    pub url: Option<String>,
+    pub id_field: Option<String>,
+    pub display: Option<String>,
+    pub dialect: Dialect,
+}
+
+impl Dataset {
+    fn from_doc(name: &str, doc: &Document) -> Self {
+        Dataset {
+            name: String::from(name),
+            url: doc.get_str("url").ok().map(String::from),
+            id_field: doc.get_str("id").ok().map(String::from),
+            display: doc.get_str("display").ok().map(String::from),
+            dialect: Dialect::from_doc(doc),
+        }
+    }
+
+    /// Gets a dataset's options. Named datasets that haven't been fetched yet have none.
+    pub async fn load(db: &Database, guild_id: u64, name: &str) -> CommandResult<Self> {
+        let doc = match stored_name(name) {
+            Some(name) => {
+                db.collection("whois-datasets")
+                    .find_one(doc! { "_guild": guild_id, "name": name }, None)
+                    .await?
+            }
+            None => {
+                db.collection("whois-settings")
+                    .find_one(doc! { "_guild": guild_id }, None)
+                    .await?
+            }
+        };
+        Ok(Dataset::from_doc(name, &doc.unwrap_or_else(Document::new)))
+    }
+
+    /// Gets every dataset in the guild, starting with the main one.
+    pub async fn load_all(db: &Database, guild_id: u64) -> CommandResult<Vec<Self>> {
+        let mut datasets = vec![Dataset::load(db, guild_id, MAIN_DATASET).await?];
+        let mut cursor = db
+            .collection("whois-datasets")
+            .find(doc! { "_guild": guild_id }, None)
+            .await?;
+        while let Some(doc_result) = cursor.next().await {
+            let doc = doc_result?;
+            if let Ok(name) = doc.get_str("name") {
+                datasets.push(Dataset::from_doc(name, &doc));
+            }
+        }
+        Ok(datasets)
+    }
+
+    /// Remembers the URL, ID field, and CSV options used to fetch the dataset.
+    pub async fn save_fetch(
+        db: &Database,
+        guild_id: u64,
+        name: &str,
+        url: &str,
+        id_field: &str,
+        dialect: &Dialect,
+    ) -> CommandResult {
+        let (collection, filter) = match stored_name(name) {
+            Some(name) => ("whois-datasets", doc! { "_guild": guild_id, "name": name }),
+            None => ("whois-settings", doc! { "_guild": guild_id }),
+        };
+        db.collection(collection)
+            .update_one(
+                filter.clone(),
+                doc! {
+                    "$set": {
+                        "url": url,
+                        "id": id_field,
+                        "dialect": dialect.to_doc(),
+                    },
+                    "$setOnInsert": filter,
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// The display template of each dataset, for showing whois entries in a list.
+pub struct DisplayTemplates {
+    templates: HashMap<String, Template>,
+}
+
+impl DisplayTemplates {
+    pub async fn load(db: &Database, guild_id: u64) -> CommandResult<Self> {
+        let mut templates = HashMap::new();
+        for dataset in Dataset::load_all(db, guild_id).await? {
+            let display = dataset.display.as_deref().unwrap_or(DEFAULT_DISPLAY);
+            // Templates saved before the template language might not parse, so they're read the old
+            // way
+            let template = Template::parse(display).unwrap_or_else(|_| Template::literal(display));
+            templates.insert(dataset.name, template);
+        }
+        Ok(DisplayTemplates { templates })
+    }
+
+    /// Gets the template for the dataset that the entry came from.
+    pub fn for_entry(&self, entry: &Document) -> &Template {
+        self.templates
+            .get(dataset_of(entry))
+            .or_else(|| self.templates.get(MAIN_DATASET))
+            .expect("The main dataset always has a template.")
+    }
+}
+
+/// Gets the entries for a member from every dataset, or only the one named.
+pub async fn find_entries(
+    db: &Database,
+    guild_id: u64,
+    user_id: &str,
+    dataset: Option<&str>,
+) -> CommandResult<Vec<Document>> {
+    let mut filter = match dataset {
+        Some(name) => entry_filter(guild_id, name),
+        None => doc! { "_guild": guild_id },
+    };
+    filter.insert("_user", Bson::String(String::from(user_id)));
+    let mut cursor = db.collection("whois-data").find(filter, None).await?;
+    let mut entries = Vec::new();
+    while let Some(doc_result) = cursor.next().await {
+        entries.push(doc_result?);
+    }
+    // The main dataset goes first
+    entries.sort_by_key(|entry| entry.contains_key("_dataset"));
+    Ok(entries)
+}
+
+/// Combines a member's entries from every dataset into one, for the role and nickname syncs. If
+/// more than one dataset has a field, the first entry's value is used, so put the main dataset
+/// first.
+pub fn merge_entries(entries: &[Document]) -> Option<Document> {
+    let mut merged = entries.first()?.clone();
+    for entry in &entries[1..] {
+        for (key, value) in entry {
+            if !merged.contains_key(key) {
+                merged.insert(key, value.clone());
+            }
+        }
+    }
+    Some(merged)
+}
+
+#[command]
+#[usage = "[name] [display \"[template]\" or remove]"]
+#[example = ""]
+#[example = "staff display \"{{Title}} {{Last Name}}\""]
+#[example = "staff remove"]
+#[required_permissions("MANAGE_GUILD")]
+/// Manage the server's whois datasets. Without any arguments, this lists them. A dataset is made
+/// by fetching it, like `:whois fetch staff "<url>" "Discord"`, and each dataset has its own URL,
+/// ID field, and `display` template (see `:help whois config`). The dataset fetched without a name
+/// is called `main`, and its options are set with `:whois config`. `remove` deletes a named dataset
+/// and its entries. Requires that you can manage the guild (the MANAGE_GUILD permission).
+async fn dataset(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_data = db.collection("whois-data");
+
+    if args.is_empty() {
+        let mut list = String::new();
+        for dataset in Dataset::load_all(db, guild_id).await? {
+            let count = whois_data
+                .count_documents(entry_filter(guild_id, &dataset.name), None)
+                .await?;
+            list.push_str(&format!(
+                "**{}**: {} entries from {} (ID field `{}`)\n",
+                dataset.name,
+                count,
+                dataset.url.as_deref().unwrap_or("nowhere yet"),
+                dataset.id_field.as_deref().unwrap_or("ID")
+            ));
+        }
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.colour(Colour::MAGENTA);
+                    embed.description(truncate(list, 2000));
+                    embed
+                });
+                message
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let name = args.single::<String>()?.to_lowercase();
+    if name == MAIN_DATASET {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "The main dataset's options are set with `:whois config`.",
+            )
+            .await?;
+        return Ok(());
+    }
+    let whois_datasets = db.collection("whois-datasets");
+    let filter = doc! { "_guild": guild_id, "name": &name };
+    if whois_datasets
+        .find_one(filter.clone(), None)
+        .await?
+        .is_none()
+    {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "There's no dataset named `{}`. Make one with `:whois fetch {} \"<url>\" \"<id field>\"`.",
+                    name, name
+                ),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    match args.single::<String>().unwrap_or_default().as_str() {
+        "display" => match args.single_quoted::<String>() {
+            Ok(display) => {
+                if let Err(err) = Template::parse(&display) {
+                    msg.channel_id
+                        .say(
+                            &ctx.http,
+                            format!("I can't use that format. {}", err.describe(&display)),
+                        )
+                        .await?;
+                    return Ok(());
+                }
+                let before = Dataset::load(db, guild_id, &name).await?.display;
+                whois_datasets
+                    .update_one(filter, doc! { "$set": { "display": &display } }, None)
+                    .await?;
+                audit::record(
+                    db,
+                    guild_id,
+                    vec![audit::Record {
+                        action: "dataset",
+                        actor: msg.author.id,
+                        user: None,
+                        note: format!("Changed the `{}` dataset.", name),
+                        changes: vec![audit::change(
+                            "display",
+                            before.map(Bson::String),
+                            Some(Bson::String(display)),
+                        )],
+                    }],
+                )
+                .await;
+                msg.react(&ctx.http, '👌').await?;
+            }
+            Err(_) => {
+                let dataset = Dataset::load(db, guild_id, &name).await?;
+                msg.channel_id
+                    .send_message(&ctx.http, |message| {
+                        message.embed(|embed| {
+                            embed.colour(Colour::MAGENTA);
+                            embed
+                                .description(dataset.display.as_deref().unwrap_or(DEFAULT_DISPLAY));
+                            embed
+                        });
+                        message
+                    })
+                    .await?;
+            }
+        },
+        "remove" => {
+            whois_datasets.delete_one(filter, None).await?;
+            let result = whois_data
+                .delete_many(doc! { "_guild": guild_id, "_dataset": &name }, None)
+                .await?;
+            audit::record(
+                db,
+                guild_id,
+                vec![audit::Record {
+                    action: "dataset",
+                    actor: msg.author.id,
+                    user: None,
+                    note: format!(
+                        "Removed the `{}` dataset and its {} entries.",
+                        name, result.deleted_count
+                    ),
+                    changes: Vec::new(),
+                }],
+            )
+            .await;
+            msg.react(&ctx.http, '👌').await?;
+        }
+        _ => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "You can set the dataset's `display` template or `remove` it.",
+                )
+                .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,448 @@
+//! Reads CSV files that aren't plain comma-separated UTF-8, like semicolon-separated exports,
+//! Latin-1 spreadsheets, and sheets with a title above the header row.
+use crate::error_with_reason::ErrorWithReason;
+use csv::{ReaderBuilder, StringRecord, Trim};
+use mongodb::bson::{doc, Document};
+use serenity::framework::standard::CommandResult;
+
+/// The option names that `:whois fetch` accepts as `name=value`.
+pub const OPTION_NAMES: [&str; 6] = [
+    "delimiter",
+    "quote",
+    "header",
+    "encoding",
+    "trim",
+    "skipblank",
+];
+
+/// Delimiters to guess between when one isn't given.
+const SNIFFED_DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// UTF-8, unless it isn't valid UTF-8, in which case it's Latin-1.
+    Auto,
+    Utf8,
+    Latin1,
+}
+
+impl Encoding {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(Encoding::Auto),
+            "utf8" | "utf-8" => Some(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Auto => "auto",
+            Encoding::Utf8 => "utf8",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+}
+
+/// How to read a dataset's CSV file. Options that aren't given are guessed or use the usual
+/// defaults.
+#[derive(Debug, Clone)]
+pub struct Dialect {
+    delimiter: Option<u8>,
+    quote: u8,
+    /// The row the field names are on, starting at 1.
+    header_row: u64,
+    encoding: Encoding,
+    trim: bool,
+    skip_blank: bool,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: None,
+            quote: b'"',
+            header_row: 1,
+            encoding: Encoding::Auto,
+            trim: false,
+            skip_blank: true,
+        }
+    }
+}
+
+/// Reads a single ASCII character, allowing `tab` for tabs since they're hard to type in Discord.
+fn parse_char(value: &str) -> Option<u8> {
+    match value {
+        "tab" | "\\t" => Some(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() => Some(value.as_bytes()[0]),
+        _ => None,
+    }
+}
+
+/// The line that a record at the byte offset starts on. The CSV reader puts blank lines before a
+/// record at the start of it, and it doesn't count lines ending in `\r\n` right, so this skips
+/// them and counts the lines itself.
+fn line_at(text: &str, byte: u64) -> u64 {
+    let bytes = text.as_bytes();
+    let start = byte as usize
+        + bytes[byte as usize..]
+            .iter()
+            .position(|&c| c != b'\r' && c != b'\n')
+            .unwrap_or(bytes.len() - byte as usize);
+    1 + bytes[..start].iter().filter(|&&c| c == b'\n').count() as u64
+}
+
+fn describe_char(c: u8) -> String {
+    match c {
+        b'\t' => String::from("tabs"),
+        _ => format!("`{}`", c as char),
+    }
+}
+
+impl Dialect {
+    /// Reads the options saved with a dataset.
+    pub fn from_doc(dataset: &Document) -> Self {
+        let mut dialect = Dialect::default();
+        if let Ok(options) = dataset.get_document("dialect") {
+            for (name, value) in options {
+                if let Some(value) = value.as_str() {
+                    // Saved options were checked before they were saved
+                    let _ = dialect.set(name, value);
+                }
+            }
+        }
+        dialect
+    }
+
+    /// The options to save with the dataset.
+    pub fn to_doc(&self) -> Document {
+        let mut doc = doc! {
+            "quote": (self.quote as char).to_string(),
+            "header": self.header_row.to_string(),
+            "encoding": self.encoding.name(),
+            "trim": if self.trim { "on" } else { "off" },
+            "skipblank": if self.skip_blank { "on" } else { "off" },
+        };
+        if let Some(delimiter) = self.delimiter {
+            doc.insert("delimiter", (delimiter as char).to_string());
+        }
+        doc
+    }
+
+    /// Sets an option, returning why the value isn't allowed if it isn't.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let on_off = |value: &str| match value {
+            "on" | "yes" | "true" => Ok(true),
+            "off" | "no" | "false" => Ok(false),
+            _ => Err(format!("`{}` can only be `on` or `off`.", name)),
+        };
+        match name {
+            "delimiter" => {
+                self.delimiter = if value == "auto" {
+                    None
+                } else {
+                    Some(parse_char(value).ok_or_else(|| {
+                        String::from("The delimiter should be one character, `tab`, or `auto`.")
+                    })?)
+                }
+            }
+            "quote" => {
+                self.quote = parse_char(value)
+                    .ok_or_else(|| String::from("The quote should be one character."))?
+            }
+            "header" => {
+                self.header_row = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|row| *row > 0)
+                    .ok_or_else(|| String::from("The header row should be a number, like `1`."))?
+            }
+            "encoding" => {
+                self.encoding = Encoding::from_name(&value.to_lowercase()).ok_or_else(|| {
+                    String::from("The encoding can be `auto`, `utf8`, or `latin1`.")
+                })?
+            }
+            "trim" => self.trim = on_off(value)?,
+            "skipblank" => self.skip_blank = on_off(value)?,
+            _ => return Err(format!("There's no `{}` option.", name)),
+        }
+        Ok(())
+    }
+
+    /// Sets an option from a `name=value` argument. Returns `None` if the argument isn't an
+    /// option, such as a URL.
+    pub fn set_from_arg(&mut self, arg: &str) -> Option<Result<(), String>> {
+        let equals = arg.find('=')?;
+        let name = arg[..equals].to_lowercase();
+        if OPTION_NAMES.contains(&name.as_str()) {
+            Some(self.set(&name, &arg[equals + 1..]))
+        } else {
+            None
+        }
+    }
+
+    /// Decodes the file, returning the text and a description of the encoding used.
+    fn decode(&self, bytes: &[u8]) -> (String, &'static str) {
+        if bytes.starts_with(b"\xEF\xBB\xBF") {
+            return (
+                String::from_utf8_lossy(&bytes[3..]).into_owned(),
+                "UTF-8 with a BOM",
+            );
+        }
+        if bytes.starts_with(b"\xFF\xFE") || bytes.starts_with(b"\xFE\xFF") {
+            let little_endian = bytes[0] == 0xFF;
+            let units = bytes[2..]
+                .chunks_exact(2)
+                .map(|pair| {
+                    if little_endian {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    }
+                })
+                .collect::<Vec<u16>>();
+            return (String::from_utf16_lossy(&units), "UTF-16");
+        }
+        let latin1 = || bytes.iter().map(|&byte| byte as char).collect::<String>();
+        match self.encoding {
+            Encoding::Latin1 => (latin1(), "Latin-1"),
+            Encoding::Utf8 => (String::from_utf8_lossy(bytes).into_owned(), "UTF-8"),
+            Encoding::Auto => match std::str::from_utf8(bytes) {
+                Ok(text) => (String::from(text), "UTF-8"),
+                Err(_) => (latin1(), "Latin-1 (it wasn't valid UTF-8)"),
+            },
+        }
+    }
+
+    /// Guesses the delimiter from the character that appears the most outside of quotes in the
+    /// header row.
+    fn sniff_delimiter(&self, text: &str) -> u8 {
+        let header = text
+            .lines()
+            .nth(self.header_row as usize - 1)
+            .unwrap_or_default();
+        let mut counts = [0; SNIFFED_DELIMITERS.len()];
+        let mut in_quotes = false;
+        for byte in header.bytes() {
+            if byte == self.quote {
+                in_quotes = !in_quotes;
+            } else if !in_quotes {
+                if let Some(index) = SNIFFED_DELIMITERS.iter().position(|&c| c == byte) {
+                    counts[index] += 1;
+                }
+            }
+        }
+        // Ties go to the earlier delimiter, so a header without any is read with commas
+        let best =
+            counts.iter().enumerate().fold(
+                0,
+                |best, (index, &count)| {
+                    if count > counts[best] {
+                        index
+                    } else {
+                        best
+                    }
+                },
+            );
+        SNIFFED_DELIMITERS[best]
+    }
+
+    /// Reads the file into its header and rows.
+    pub fn read(&self, bytes: &[u8]) -> CommandResult<Table> {
+        let (text, encoding) = self.decode(bytes);
+        let (delimiter, guessed) = match self.delimiter {
+            Some(delimiter) => (delimiter, false),
+            None => (self.sniff_delimiter(&text), true),
+        };
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(delimiter)
+            .quote(self.quote)
+            .trim(if self.trim { Trim::All } else { Trim::None })
+            .from_reader(text.as_bytes());
+
+        let mut headers = None;
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            let line = record
+                .position()
+                .map_or(0, |position| line_at(&text, position.byte()));
+            if line < self.header_row {
+                continue;
+            }
+            if self.skip_blank && record.iter().all(|cell| cell.trim().is_empty()) {
+                continue;
+            }
+            if headers.is_none() {
+                headers = Some((line, record));
+            } else {
+                rows.push((line, record));
+            }
+        }
+        let (header_line, headers) = headers.ok_or_else(|| {
+            ErrorWithReason(format!(
+                "I couldn't find the field names on row {} or after it.",
+                self.header_row
+            ))
+        })?;
+
+        let mut description = format!(
+            "I read it as {}, with {} between fields{} and {} for quotes. The field names are on row {}.",
+            encoding,
+            describe_char(delimiter),
+            if guessed { " (my guess)" } else { "" },
+            describe_char(self.quote),
+            header_line
+        );
+        if self.trim {
+            description.push_str(" Spaces around values were trimmed.");
+        }
+        if self.skip_blank {
+            description.push_str(" Blank rows were skipped.");
+        }
+        Ok(Table {
+            headers,
+            rows,
+            description,
+        })
+    }
+}
+
+/// A CSV file that's been read.
+pub struct Table {
+    pub headers: StringRecord,
+    /// Each row with the line it starts on.
+    pub rows: Vec<(u64, StringRecord)>,
+    /// How the file was read, to tell the mods.
+    pub description: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dialect(args: &[&str]) -> Dialect {
+        let mut dialect = Dialect::default();
+        for arg in args {
+            dialect.set_from_arg(arg).unwrap().unwrap();
+        }
+        dialect
+    }
+
+    fn rows(table: &Table) -> Vec<Vec<&str>> {
+        table
+            .rows
+            .iter()
+            .map(|(_, row)| row.iter().collect())
+            .collect()
+    }
+
+    #[test]
+    fn sets_options_from_args() {
+        let mut dialect = Dialect::default();
+        assert!(dialect.set_from_arg("https://example.com/a=b").is_none());
+        assert!(dialect.set_from_arg("delimiter=tab").unwrap().is_ok());
+        assert_eq!(dialect.delimiter, Some(b'\t'));
+        assert!(dialect.set_from_arg("Header=3").unwrap().is_ok());
+        assert_eq!(dialect.header_row, 3);
+        assert!(dialect.set_from_arg("header=0").unwrap().is_err());
+        assert!(dialect.set_from_arg("delimiter=;;").unwrap().is_err());
+        assert!(dialect.set_from_arg("trim=maybe").unwrap().is_err());
+        assert!(dialect.set_from_arg("encoding=shift-jis").unwrap().is_err());
+    }
+
+    #[test]
+    fn saves_and_loads_options() {
+        let saved = dialect(&["delimiter=;", "header=2", "encoding=latin1", "trim=on"]);
+        let loaded = Dialect::from_doc(&doc! { "dialect": saved.to_doc() });
+        assert_eq!(loaded.delimiter, Some(b';'));
+        assert_eq!(loaded.header_row, 2);
+        assert_eq!(loaded.encoding, Encoding::Latin1);
+        assert!(loaded.trim);
+        assert!(loaded.skip_blank);
+        assert_eq!(Dialect::from_doc(&doc! {}).delimiter, None);
+    }
+
+    #[test]
+    fn decodes_files() {
+        let dialect = Dialect::default();
+        assert_eq!(
+            dialect.decode(b"\xEF\xBB\xBFName"),
+            (String::from("Name"), "UTF-8 with a BOM")
+        );
+        assert_eq!(
+            dialect.decode(b"\xFF\xFEN\0o\0"),
+            (String::from("No"), "UTF-16")
+        );
+        assert_eq!(dialect.decode(b"Jos\xE9").0, "José");
+        assert_eq!(dialect.decode("José".as_bytes()).0, "José");
+        assert_eq!(
+            self::dialect(&["encoding=latin1"]).decode("é".as_bytes()).0,
+            "Ã©"
+        );
+    }
+
+    #[test]
+    fn sniffs_delimiters() {
+        let dialect = Dialect::default();
+        assert_eq!(dialect.sniff_delimiter("Name;Grade;\"A,B\"\n"), b';');
+        assert_eq!(dialect.sniff_delimiter("Name\tGrade\n"), b'\t');
+        assert_eq!(dialect.sniff_delimiter("Name\n"), b',');
+        assert_eq!(
+            self::dialect(&["header=2"]).sniff_delimiter("Roster, 2020\nName|Grade\n"),
+            b'|'
+        );
+    }
+
+    #[test]
+    fn reads_tables() {
+        let table = dialect(&["header=2", "trim=on"])
+            .read(b"Roster, 2020\nName; Grade\n\nBilly; 10\n ;\nJoe;11\n")
+            .unwrap();
+        assert_eq!(
+            table.headers.iter().collect::<Vec<&str>>(),
+            ["Name", "Grade"]
+        );
+        assert_eq!(rows(&table), [["Billy", "10"], ["Joe", "11"]]);
+        assert_eq!(
+            table
+                .rows
+                .iter()
+                .map(|(line, _)| *line)
+                .collect::<Vec<u64>>(),
+            [4, 6]
+        );
+        assert!(table.description.contains("`;` between fields (my guess)"));
+    }
+
+    #[test]
+    fn counts_lines_after_blank_lines() {
+        let table = Dialect::default()
+            .read(b"Name\r\n\r\nBilly\r\n\"Joe\r\nSmith\"\r\nAl\r\n")
+            .unwrap();
+        assert_eq!(
+            table
+                .rows
+                .iter()
+                .map(|(line, _)| *line)
+                .collect::<Vec<u64>>(),
+            [3, 4, 6]
+        );
+    }
+
+    #[test]
+    fn keeps_blank_rows_when_asked() {
+        let table = dialect(&["skipblank=off"])
+            .read(b"Name\n\"\"\nBilly\n")
+            .unwrap();
+        assert_eq!(rows(&table), [vec![""], vec!["Billy"]]);
+    }
+
+    #[test]
+    fn needs_a_header_row() {
+        assert!(dialect(&["header=5"]).read(b"Name\nBilly\n").is_err());
+    }
+}
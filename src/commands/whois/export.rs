@@ -0,0 +1,182 @@
+//! Gets the whois data back out as a file, with any edits from `:whois set` included.
+use super::{
+    datasets::{dataset_of, MAIN_DATASET},
+    get_all_members,
+    privacy::{Privacy, Viewer},
+    send_csv_privately, send_file_privately,
+};
+use crate::db;
+use mongodb::bson::{doc, Document};
+use serde_json::{Map, Value};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, guild::Member},
+};
+use std::collections::HashMap;
+use tokio::stream::StreamExt;
+
+/// Columns that come from Discord rather than the whois data.
+const DISCORD_COLUMNS: [&str; 3] = ["@username", "@nickname", "@roles"];
+
+#[command]
+#[usage = "[csv|json] [fields...]"]
+#[example = ""]
+#[example = "json"]
+#[example = "Name Grade @username @nickname @roles"]
+#[example = "csv \"Homeroom Teacher\" @roles"]
+#[required_permissions("MANAGE_GUILD")]
+/// Download the whois data as a CSV (the default) or JSON file. You only get the fields you're
+/// allowed to see, and members who have opted out are left out. List fields to only include those,
+/// in that order. You can also add columns from Discord at the end: `@username` for their current
+/// username, `@nickname` for their nickname in this server, and `@roles` for their roles, which is
+/// handy for checking the sheet against the server. The first column, `Discord ID`, is always
+/// included. I'll DM you the file, since it can have fields that not everyone here can see. Requires
+/// that you can manage the guild (the MANAGE_GUILD permission).
+async fn export(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let format = args.current().map(|format| format.to_lowercase());
+    let as_json = format.as_deref() == Some("json");
+    if as_json || format.as_deref() == Some("csv") {
+        args.advance();
+    }
+    let mut requested = Vec::new();
+    let mut discord_columns = Vec::new();
+    while !args.is_empty() {
+        let column = args.single_quoted::<String>()?;
+        if DISCORD_COLUMNS.contains(&column.as_str()) {
+            discord_columns.push(column);
+        } else {
+            requested.push(column);
+        }
+    }
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let privacy = Privacy::load(db, guild.id.0, Viewer::from_message(&guild, msg)).await?;
+
+    let mut cursor = db
+        .collection("whois-data")
+        .find(doc! { "_guild": guild.id.0 }, None)
+        .await?;
+    let mut entries: Vec<(String, String, Vec<(String, String, bool)>)> = Vec::new();
+    while let Some(doc_result) = cursor.next().await {
+        let doc: Document = doc_result?;
+        if let Ok(user_id) = doc.get_str("_user") {
+            if !privacy.is_opted_out(user_id) {
+                entries.push((
+                    String::from(user_id),
+                    String::from(dataset_of(&doc)),
+                    privacy.visible_fields(&doc),
+                ));
+            }
+        }
+    }
+
+    // Without a list of fields, use every field the viewer can see in the order they're shown
+    let mut columns = if requested.is_empty() {
+        let mut columns: Vec<String> = Vec::new();
+        for (_, _, fields) in &entries {
+            for (name, _, _) in fields {
+                if !columns.contains(name) {
+                    columns.push(name.clone());
+                }
+            }
+        }
+        columns
+    } else {
+        requested
+    };
+    let needs_members = !discord_columns.is_empty();
+    columns.extend(discord_columns);
+
+    let members = if needs_members {
+        get_all_members(ctx, guild.id)
+            .await?
+            .into_iter()
+            .map(|member| (member.user.id.to_string(), member))
+            .collect::<HashMap<String, Member>>()
+    } else {
+        HashMap::new()
+    };
+
+    // Members can be in more than one dataset, so say which one each row is from
+    let has_datasets = entries
+        .iter()
+        .any(|(_, dataset, _)| dataset != MAIN_DATASET);
+
+    let rows = entries
+        .iter()
+        .map(|(user_id, dataset, fields)| {
+            let member = members.get(user_id);
+            let mut row = vec![user_id.clone()];
+            if has_datasets {
+                row.push(dataset.clone());
+            }
+            row.extend(columns.iter().map(|column| {
+                match column.as_str() {
+                    "@username" => member.map(|member| member.user.tag()).unwrap_or_default(),
+                    "@nickname" => member
+                        .and_then(|member| member.nick.clone())
+                        .unwrap_or_default(),
+                    "@roles" => member
+                        .map(|member| {
+                            member
+                                .roles
+                                .iter()
+                                .filter_map(|role_id| guild.roles.get(role_id))
+                                .map(|role| role.name.clone())
+                                .collect::<Vec<String>>()
+                                .join(", ")
+                        })
+                        .unwrap_or_default(),
+                    _ => fields
+                        .iter()
+                        .find(|(name, _, _)| name == column)
+                        .map(|(_, value, _)| value.clone())
+                        .unwrap_or_default(),
+                }
+            }));
+            row
+        })
+        .collect::<Vec<Vec<String>>>();
+    // Not just "ID", which is what the ID field is called by default
+    let mut header = vec![String::from("Discord ID")];
+    if has_datasets {
+        header.push(String::from("Dataset"));
+    }
+    header.extend(columns);
+
+    let content = format!("Here's the whois data for {} members.", rows.len());
+    if as_json {
+        let json = Value::Array(
+            rows.into_iter()
+                .map(|row| {
+                    Value::Object(
+                        header
+                            .iter()
+                            .cloned()
+                            .zip(row.into_iter().map(Value::String))
+                            .collect::<Map<String, Value>>(),
+                    )
+                })
+                .collect(),
+        );
+        let bytes = serde_json::to_vec_pretty(&json)?;
+        send_file_privately(ctx, msg, "whois.json", &content, &bytes).await?;
+    } else {
+        send_csv_privately(ctx, msg, "whois.csv", &content, &header, &rows).await?;
+    }
+
+    Ok(())
+}
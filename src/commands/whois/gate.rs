@@ -0,0 +1,406 @@
+//! Only lets in new members who are in the whois data. Members who aren't wait in a restricted
+//! state until a mod approves them.
+use super::{log_change, parse_id};
+use crate::{db, pagination::truncate};
+use mongodb::{
+    bson::{doc, Document},
+    options::UpdateOptions,
+    Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    http::error::Error as HttpError,
+    model::{
+        channel::Message,
+        guild::Member,
+        id::{ChannelId, GuildId, RoleId, UserId},
+    },
+    utils::Colour,
+    Error as SerenityError,
+};
+use tokio::stream::StreamExt;
+
+/// The guild's gate options. There's only a gate if `gate` is `on` and the `verified` role is set.
+struct Gate {
+    verified: RoleId,
+    unverified: Option<RoleId>,
+    mod_channel: Option<ChannelId>,
+}
+
+impl Gate {
+    async fn load(db: &Database, guild_id: GuildId) -> CommandResult<Option<Self>> {
+        let settings = db
+            .collection("whois-settings")
+            .find_one(doc! { "_guild": guild_id.0 }, None)
+            .await?
+            .unwrap_or_else(|| Document::new());
+        if settings.get_str("gate").ok() != Some("on") {
+            return Ok(None);
+        }
+        let option = |name: &str| settings.get_str(name).ok().and_then(parse_id);
+        Ok(option("verified").map(|verified| Gate {
+            verified: RoleId(verified),
+            unverified: option("unverified").map(RoleId),
+            mod_channel: option("modchannel").map(ChannelId),
+        }))
+    }
+
+    /// Gives the member the verified role and takes away the unverified role.
+    async fn verify(
+        &self,
+        ctx: &Context,
+        db: &Database,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> CommandResult {
+        ctx.http
+            .add_member_role(guild_id.0, user_id.0, self.verified.0)
+            .await?;
+        if let Some(unverified) = self.unverified {
+            ctx.http
+                .remove_member_role(guild_id.0, user_id.0, unverified.0)
+                .await?;
+        }
+        forget_pending(db, guild_id, user_id).await?;
+        log_change(ctx, db, guild_id.0, format!("Verified <@{}>", user_id)).await;
+        Ok(())
+    }
+
+    /// Asks the mods to approve a member who isn't in the whois data.
+    async fn request_approval(
+        &self,
+        ctx: &Context,
+        db: &Database,
+        guild_id: GuildId,
+        user_id: UserId,
+    ) -> CommandResult {
+        db.collection("whois-pending")
+            .update_one(
+                doc! {
+                    "_guild": guild_id.0,
+                    "_user": user_id.to_string(),
+                },
+                doc! {
+                    "$setOnInsert": {
+                        "_guild": guild_id.0,
+                        "_user": user_id.to_string(),
+                    },
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        if let Some(mod_channel) = self.mod_channel {
+            mod_channel
+                .send_message(&ctx.http, |message| {
+                    message.embed(|embed| {
+                        embed.colour(Colour::MAGENTA);
+                        embed.description(format!(
+                            "<@{}> ({}) joined, but they aren't in the whois data.\n\nDo `:whois approve {}` to let them in anyways, or `:whois verify {}` once they've been added and fetched.",
+                            user_id, user_id, user_id, user_id
+                        ));
+                        embed
+                    });
+                    message.content("Someone's waiting to be approved.");
+                    message
+                })
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Takes a member off the list of members waiting to be approved.
+async fn forget_pending(db: &Database, guild_id: GuildId, user_id: UserId) -> CommandResult {
+    db.collection("whois-pending")
+        .delete_one(
+            doc! {
+                "_guild": guild_id.0,
+                "_user": user_id.to_string(),
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Whether a request failed because the member isn't in the server anymore.
+fn is_unknown_member(error: &SerenityError) -> bool {
+    if let SerenityError::Http(http_error) = error {
+        if let HttpError::UnsuccessfulRequest(response) = http_error.as_ref() {
+            return response.status_code.as_u16() == 404;
+        }
+    }
+    false
+}
+
+/// Whether the member has an entry from `:whois fetch`. Only fetched entries count, so that
+/// members can't get themselves verified by editing their own entry.
+async fn in_whois_data(db: &Database, guild_id: GuildId, user_id: UserId) -> CommandResult<bool> {
+    Ok(db
+        .collection("whois-data")
+        .find_one(
+            doc! {
+                "_guild": guild_id.0,
+                "_user": user_id.to_string(),
+                "_fetched": true,
+            },
+            None,
+        )
+        .await?
+        .is_some())
+}
+
+/// Puts a new member in the restricted state, then verifies them if they're in the whois data or
+/// asks the mods to approve them otherwise.
+pub async fn on_join(
+    ctx: &Context,
+    db: &Database,
+    guild_id: GuildId,
+    member: &Member,
+) -> CommandResult {
+    if member.user.bot {
+        return Ok(());
+    }
+    let gate = match Gate::load(db, guild_id).await? {
+        Some(gate) => gate,
+        None => return Ok(()),
+    };
+    let user_id = member.user.id;
+    if let Some(unverified) = gate.unverified {
+        ctx.http
+            .add_member_role(guild_id.0, user_id.0, unverified.0)
+            .await?;
+    }
+    if in_whois_data(db, guild_id, user_id).await? {
+        gate.verify(ctx, db, guild_id, user_id).await?;
+    } else {
+        gate.request_approval(ctx, db, guild_id, user_id).await?;
+    }
+    Ok(())
+}
+
+/// Adds a mention to a list for an embed field, cutting it off before it gets too long.
+fn push_mention(list: &mut String, user_id: UserId) {
+    if list.ends_with("[...]") {
+        return;
+    }
+    let line = format!("<@{}>\n", user_id);
+    // 1000 to leave room for the [...] in a 1024-character field
+    if list.len() + line.len() > 1000 {
+        list.push_str("[...]");
+    } else {
+        list.push_str(&line);
+    }
+}
+
+#[command]
+#[usage = "[user id or mention, or all]"]
+#[example = ""]
+#[example = "393248490739859458"]
+#[example = "all"]
+/// Check whether you're in the whois data now, and if so, get verified. Mods (who can manage
+/// roles) can also check someone else, or `all` the members waiting to be approved, which also
+/// takes members who left off the waiting list. This only works if the mods have set up the `gate`
+/// option (see `:help whois config`).
+async fn verify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let gate = match Gate::load(db, guild.id).await? {
+        Some(gate) => gate,
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "This server doesn't have a whois gate. The mods can set one up with `:whois config gate on` and `:whois config verified <role>`.",
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let target = args.rest().trim();
+    let is_mod = guild.member_permissions(msg.author.id).manage_roles();
+    if !target.is_empty() && !is_mod {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "Only mods who can manage roles can verify other people.",
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let user_ids = if target.is_empty() {
+        vec![msg.author.id]
+    } else if target.eq_ignore_ascii_case("all") {
+        let mut cursor = db
+            .collection("whois-pending")
+            .find(doc! { "_guild": guild.id.0 }, None)
+            .await?;
+        let mut user_ids = Vec::new();
+        while let Some(doc_result) = cursor.next().await {
+            if let Some(user_id) = doc_result?.get_str("_user").ok().and_then(parse_id) {
+                user_ids.push(UserId(user_id));
+            }
+        }
+        user_ids
+    } else {
+        match parse_id(target) {
+            Some(user_id) => vec![UserId(user_id)],
+            None => {
+                msg.channel_id
+                    .say(&ctx.http, "Who? Mention them or give their ID.")
+                    .await?;
+                return Ok(());
+            }
+        }
+    };
+
+    // One member failing shouldn't stop the rest from getting verified, so failures are collected
+    // like in `rolesync::apply_changes`
+    let mut verified = String::new();
+    let mut missing = String::new();
+    let mut left = String::new();
+    let mut failures = Vec::new();
+    for user_id in user_ids {
+        match guild.member(ctx, user_id).await {
+            Ok(_) => {}
+            Err(why) if is_unknown_member(&why) => {
+                forget_pending(db, guild.id, user_id).await?;
+                push_mention(&mut left, user_id);
+                continue;
+            }
+            Err(why) => {
+                failures.push(format!("Couldn't find <@{}>: {}", user_id, why));
+                continue;
+            }
+        }
+        if !in_whois_data(db, guild.id, user_id).await? {
+            push_mention(&mut missing, user_id);
+        } else if let Err(why) = gate.verify(ctx, db, guild.id, user_id).await {
+            failures.push(format!("Couldn't verify <@{}>: {}", user_id, why));
+        } else {
+            push_mention(&mut verified, user_id);
+        }
+    }
+    let nobody =
+        verified.is_empty() && missing.is_empty() && left.is_empty() && failures.is_empty();
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.colour(if failures.is_empty() {
+                    Colour::MAGENTA
+                } else {
+                    Colour::RED
+                });
+                if !verified.is_empty() {
+                    embed.field("Verified", verified, false);
+                }
+                if !missing.is_empty() {
+                    embed.field("Still not in the whois data", missing, false);
+                }
+                if !left.is_empty() {
+                    embed.field("Left the server, so I stopped waiting on them", left, false);
+                }
+                if !failures.is_empty() {
+                    embed.field("Errors", truncate(failures.join("\n"), 1024), false);
+                }
+                if nobody {
+                    embed.description("Nobody is waiting to be approved.");
+                }
+                embed
+            });
+            message
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[command]
+#[usage = "[user id or mention]"]
+#[example = ""]
+#[example = "393248490739859458"]
+#[required_permissions("MANAGE_ROLES")]
+/// Let someone in through the whois gate even though they aren't in the whois data. Without a
+/// user, this lists the members waiting to be approved. Requires that you can manage roles (the
+/// MANAGE_ROLES permission).
+async fn approve(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let gate = match Gate::load(db, guild_id).await? {
+        Some(gate) => gate,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "This server doesn't have a whois gate.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    match parse_id(args.rest()) {
+        Some(user_id) => {
+            gate.verify(ctx, db, guild_id, UserId(user_id)).await?;
+            msg.react(&ctx.http, '👌').await?;
+        }
+        None => {
+            let mut cursor = db
+                .collection("whois-pending")
+                .find(doc! { "_guild": guild_id.0 }, None)
+                .await?;
+            let mut pending = String::new();
+            while let Some(doc_result) = cursor.next().await {
+                if let Ok(user_id) = doc_result?.get_str("_user") {
+                    let line = format!("<@{}> ({})\n", user_id, user_id);
+                    // 1900 to allow for other text
+                    if pending.len() + line.len() > 1900 {
+                        pending.push_str("[...]");
+                        break;
+                    }
+                    pending.push_str(&line);
+                }
+            }
+            msg.channel_id
+                .send_message(&ctx.http, |message| {
+                    message.embed(|embed| {
+                        embed.colour(Colour::MAGENTA);
+                        embed.description(if pending.is_empty() {
+                            String::from("Nobody is waiting to be approved.")
+                        } else {
+                            pending
+                        });
+                        embed
+                    });
+                    message.content("Do `:whois approve <user id>` to let someone in.");
+                    message
+                })
+                .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,89 +1,355 @@
-use crate::{db, error_with_reason::ErrorWithReason};
+use crate::db;
+use audit::{AUDIT_COMMAND, HISTORY_COMMAND};
+use coverage::COVERAGE_COMMAND;
+use datasets::{Dataset, DisplayTemplates, DATASET_COMMAND, MAIN_DATASET};
+use export::EXPORT_COMMAND;
+use gate::{APPROVE_COMMAND, VERIFY_COMMAND};
 use lazy_static::lazy_static;
 use mongodb::{
     bson::{doc, Bson, Document},
     options::UpdateOptions,
-    Collection,
+    Database,
 };
-use regex::{Captures, Regex};
+use nicksync::NICKNAMES_COMMAND;
+use privacy::{Privacy, Viewer, FIELD_COMMAND, OPTIN_COMMAND, OPTOUT_COMMAND};
+use profile::{SET_COMMAND, UNSET_COMMAND};
+use regex::Regex;
 use reqwest::{get, Url};
+use resolve::{Resolution, Resolver, PIN_COMMAND};
+use rolesync::{ROLEMAP_COMMAND, ROLESYNC_COMMAND};
+use schema::{Schema, SCHEMA_COMMAND};
+use search::SEARCH_COMMAND;
 use serenity::{
     client::{bridge::gateway::ChunkGuildFilter, Context},
     framework::standard::{
         macros::{command, group},
-        ArgError, Args, CommandResult,
+        Args, CommandResult,
+    },
+    model::{
+        channel::{Message, Reaction},
+        guild::{Guild, Member},
+        id::{ChannelId, GuildId, UserId},
+        user::User,
     },
-    model::channel::Message,
     utils::Colour,
 };
+use std::collections::HashMap;
+use template::{DiscordInfo, Template};
+use tokio::stream::StreamExt;
+
+mod audit;
+mod coverage;
+mod datasets;
+mod dialect;
+mod export;
+mod gate;
+mod nicksync;
+mod privacy;
+mod profile;
+mod reactions;
+mod resolve;
+mod rolesync;
+mod schema;
+mod search;
+mod template;
 
 #[group]
 #[prefixes("whois", "who")]
 #[only_in(guilds)]
 #[default_command(identify)]
-#[commands(fetch, identify, here, config)]
+#[commands(
+    fetch, identify, here, config, set, unset, field, optout, optin, rolemap, rolesync, nicknames,
+    vc, verify, approve, coverage, export, dataset, schema, search, pin, history, audit
+)]
 #[description = "Give information about a user from a CSV file."]
 struct Whois;
 
+/// Gets the first number in some text, such as the ID in a mention.
+pub fn parse_id(text: &str) -> Option<u64> {
+    lazy_static! {
+        static ref ID: Regex = Regex::new(r"\d+").unwrap();
+    }
+    ID.find(text).and_then(|id| id.as_str().parse::<u64>().ok())
+}
+
+/// Whether the text is a Discord ID. Even the oldest IDs are 17 digits long.
+pub fn is_snowflake(text: &str) -> bool {
+    (17..=20).contains(&text.len()) && text.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Gets every member of the guild from Discord, since the cache might not have all of them.
+pub async fn get_all_members(ctx: &Context, guild_id: GuildId) -> CommandResult<Vec<Member>> {
+    let mut members = Vec::new();
+    let mut after: Option<UserId> = None;
+    loop {
+        let page = guild_id.members(&ctx.http, Some(1000), after).await?;
+        let done = page.len() < 1000;
+        after = page.last().map(|member| member.user.id);
+        members.extend(page);
+        if done {
+            break;
+        }
+    }
+    Ok(members)
+}
+
+/// Gets the guild's whois entries by member ID, with each member's entries from every dataset
+/// combined.
+pub async fn entries_by_member(
+    db: &Database,
+    guild_id: GuildId,
+) -> CommandResult<HashMap<String, Document>> {
+    let mut cursor = db
+        .collection("whois-data")
+        .find(doc! { "_guild": guild_id.0 }, None)
+        .await?;
+    let mut entries = HashMap::new();
+    while let Some(doc_result) = cursor.next().await {
+        let doc = doc_result?;
+        if let Ok(user_id) = doc.get_str("_user") {
+            entries
+                .entry(String::from(user_id))
+                .or_insert_with(Vec::new)
+                .push(doc);
+        }
+    }
+    Ok(entries
+        .into_iter()
+        .filter_map(|(user_id, mut member_entries)| {
+            // The main dataset goes first
+            member_entries.sort_by_key(|entry| entry.contains_key("_dataset"));
+            datasets::merge_entries(&member_entries).map(|entry| (user_id, entry))
+        })
+        .collect())
+}
+
+/// Lists the changes that the syncs made and then the ones that failed for an embed, cutting it
+/// off before it gets too long.
+pub fn describe_changes(changes: impl Iterator<Item = String>, failures: &[String]) -> String {
+    let mut description = String::new();
+    for line in changes.chain(failures.iter().cloned()) {
+        // 2000 minus room for the [...]
+        if description.len() + line.len() + 1 > 2000 - 6 {
+            description.push_str("[...]");
+            break;
+        }
+        description.push_str(&line);
+        description.push('\n');
+    }
+    description
+}
+
+/// Sends whois cards to members who react with the `reaction` emoji.
+pub async fn on_reaction_add(ctx: &Context, reaction: &Reaction) {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    if let Err(why) = reactions::on_reaction_add(ctx, db, reaction).await {
+        println!(
+            "Sending a whois card for a reaction had an error: {:?}",
+            why
+        );
+    }
+}
+
+/// Called when someone joins a guild so that whois can set them up.
+pub async fn on_member_join(ctx: &Context, guild_id: GuildId, member: &Member) {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    if let Err(why) = gate::on_join(ctx, db, guild_id, member).await {
+        println!("The whois gate had an error: {:?}", why);
+    }
+    if let Err(why) = rolesync::sync_member(ctx, db, guild_id, member).await {
+        println!("Syncing roles for a new member had an error: {:?}", why);
+    }
+    if let Err(why) = nicksync::sync_member(ctx, db, guild_id, member).await {
+        println!(
+            "Syncing the nickname of a new member had an error: {:?}",
+            why
+        );
+    }
+}
+
+/// Prints a change that whois made on its own and posts it to the channel set by the `log`
+/// option, if there is one.
+pub async fn log_change(ctx: &Context, db: &Database, guild_id: u64, text: String) {
+    println!("[whois {}] {}", guild_id, text);
+
+    let channel_id = match db
+        .collection("whois-settings")
+        .find_one(doc! { "_guild": guild_id }, None)
+        .await
+    {
+        Ok(Some(settings)) => settings.get_str("log").ok().and_then(parse_id),
+        Ok(None) => None,
+        Err(why) => {
+            println!("Getting the whois log channel had an error: {:?}", why);
+            None
+        }
+    };
+    if let Some(channel_id) = channel_id {
+        if let Err(why) = ChannelId(channel_id).say(&ctx.http, text).await {
+            println!("Posting to the whois log channel had an error: {:?}", why);
+        }
+    }
+}
+
+/// Writes the rows as a CSV file.
+fn csv_bytes(header: &[String], rows: &[Vec<String>]) -> CommandResult<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(header)?;
+    for row in rows {
+        writer.write_record(row)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// Sends the rows as a CSV file attachment.
+pub async fn send_csv(
+    ctx: &Context,
+    msg: &Message,
+    file_name: &str,
+    content: &str,
+    header: &[String],
+    rows: &[Vec<String>],
+) -> CommandResult {
+    let bytes = csv_bytes(header, rows)?;
+    msg.channel_id
+        .send_files(&ctx.http, vec![(bytes.as_slice(), file_name)], |message| {
+            message.content(content)
+        })
+        .await?;
+    Ok(())
+}
+
+/// DMs a file to the member who ran the command, for files with fields that not everyone in the
+/// channel is allowed to see.
+pub async fn send_file_privately(
+    ctx: &Context,
+    msg: &Message,
+    file_name: &str,
+    content: &str,
+    bytes: &[u8],
+) -> CommandResult {
+    let dm = msg.author.create_dm_channel(ctx).await?;
+    let sent = dm
+        .id
+        .send_files(&ctx.http, vec![(bytes, file_name)], |message| {
+            message.content(content)
+        })
+        .await;
+    match sent {
+        Ok(_) => {
+            msg.react(&ctx.http, '👌').await?;
+        }
+        Err(_) => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "I couldn't DM you. Do you have DMs from server members turned off?",
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Like `send_csv`, but DMs the file with `send_file_privately`.
+pub async fn send_csv_privately(
+    ctx: &Context,
+    msg: &Message,
+    file_name: &str,
+    content: &str,
+    header: &[String],
+    rows: &[Vec<String>],
+) -> CommandResult {
+    send_file_privately(ctx, msg, file_name, content, &csv_bytes(header, rows)?).await
+}
+
+/// The fields of someone's entries that the viewer can see, labelled with their dataset unless they
+/// all come from the main one.
+pub fn card_fields(entries: &[Document], privacy: &Privacy) -> Vec<(String, String, bool)> {
+    let label = entries
+        .iter()
+        .any(|entry| datasets::dataset_of(entry) != MAIN_DATASET);
+    let mut fields = Vec::new();
+    for entry in entries {
+        for (key, value, inline) in privacy.visible_fields(entry) {
+            fields.push(if label {
+                (
+                    format!("{} ({})", key, datasets::dataset_of(entry)),
+                    value,
+                    inline,
+                )
+            } else {
+                (key, value, inline)
+            });
+        }
+    }
+    fields
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn display_whois_entry(
     ctx: &Context,
     msg: &Message,
-    whois_data: &Collection,
+    db: &Database,
     guild_id: &u64,
     id: &String,
     other_users: Option<&String>,
+    privacy: &Privacy,
+    dataset: Option<&str>,
 ) -> CommandResult<bool> {
-    match whois_data
-        .find_one(
-            doc! {
-                "_guild": guild_id,
-                "_user": id,
-            },
-            None,
-        )
-        .await?
-    {
-        Some(doc) => {
-            msg.channel_id
-                .send_message(&ctx.http, |message| {
-                    message.embed(|embed| {
-                        embed.colour(Colour::MAGENTA);
-                        embed.description(match other_users {
-                            Some(others) => if others.is_empty() {
-                                format!("What I know about <@{}> (whom I'm guessing you're referring to)", id)
-                            } else {
-                                format!("Other users you may have meant:\n{}\nBut here's what we know about <@{}>", others, id)
-                            },
-                            None => format!("What I know about <@{}>", id)
-                        });
-                        for (key, value) in doc.iter() {
-                            if !key.starts_with("_") {
-                                if let Bson::String(str) = value {
-                                    if !str.is_empty() {
-                                        embed.field(key, str, true);
-                                    }
-                                }
-                            }
-                        }
-                        embed
-                    });
-                    message.content("Fresh from the FBI's kitchen!");
-                    message
-                })
-                .await?;
-            Ok(true)
-        }
-        None => Ok(false),
+    if privacy.is_opted_out(id) {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!("<@{}> has chosen not to share their whois entry.", id),
+            )
+            .await?;
+        return Ok(true);
     }
+    let entries = datasets::find_entries(db, *guild_id, id, dataset).await?;
+    if entries.is_empty() {
+        return Ok(false);
+    }
+    let fields = card_fields(&entries, privacy);
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.colour(Colour::MAGENTA);
+                embed.description(match other_users {
+                    Some(others) => if others.is_empty() {
+                        format!("What I know about <@{}> (whom I'm guessing you're referring to)", id)
+                    } else {
+                        format!("Other users you may have meant:\n{}\nBut here's what we know about <@{}>", others, id)
+                    },
+                    None => format!("What I know about <@{}>", id)
+                });
+                for (key, value, inline) in fields {
+                    embed.field(key, value, inline);
+                }
+                embed
+            });
+            message.content("Fresh from the FBI's kitchen!");
+            message
+        })
+        .await?;
+    Ok(true)
 }
 
 #[command]
 #[aliases("is")]
-#[usage = "<user id or name>"]
+#[usage = "[--dataset <name>] <user id or name>"]
 #[example = "393248490739859458"]
 #[example = "moofy-bot"]
-/// List information about the given user from a CSV file.
-async fn identify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+#[example = "--dataset staff moofy-bot"]
+/// List information about the given user from a CSV file. Every dataset is searched unless you
+/// pick one with `--dataset`, and fields from named datasets are labelled with the dataset they
+/// came from. If you reply to a message with just `:whois`, I'll introduce its author like
+/// `:whois here` does.
+async fn identify(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild = match msg.guild(&ctx.cache).await {
         Some(guild) => guild,
         None => {
@@ -95,10 +361,45 @@ async fn identify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
     };
     let guild_id = guild.id.as_u64();
 
+    if args.is_empty() {
+        if let Some(message_id) = msg
+            .message_reference
+            .as_ref()
+            .and_then(|reference| reference.message_id)
+        {
+            let replied_to = msg.channel_id.message(&ctx.http, message_id).await?;
+            return introduce(ctx, msg, &guild, &[replied_to.author], false).await;
+        }
+    }
+
     let data = ctx.data.read().await;
     let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
 
-    let whois_data = db.collection("whois-data");
+    let privacy = Privacy::load(db, *guild_id, Viewer::from_message(&guild, msg)).await?;
+
+    let dataset = if args.current() == Some("--dataset") {
+        args.advance();
+        let name = args.single::<String>()?.to_lowercase();
+        if !Dataset::load_all(db, *guild_id)
+            .await?
+            .iter()
+            .any(|dataset| dataset.name == name)
+        {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "There's no dataset named `{}`. Do `:whois dataset` to list them.",
+                        name
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+        Some(name)
+    } else {
+        None
+    };
 
     let username_search = args.rest();
 
@@ -106,13 +407,19 @@ async fn identify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
     let mut tried_member_match = false;
     let mut tried_first_search = false;
 
-    lazy_static! {
-        static ref USER_ID: Regex = Regex::new(r"\d+").unwrap();
-    }
-
-    if let Some(matched_id) = USER_ID.find(username_search) {
-        let id = matched_id.as_str();
-        if display_whois_entry(ctx, msg, &whois_data, guild_id, &id.to_string(), None).await? {
+    if let Some(id) = parse_id(username_search) {
+        if display_whois_entry(
+            ctx,
+            msg,
+            db,
+            guild_id,
+            &id.to_string(),
+            None,
+            &privacy,
+            dataset.as_deref(),
+        )
+        .await?
+        {
             return Ok(());
         }
         tried_id = true;
@@ -124,10 +431,12 @@ async fn identify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
         if display_whois_entry(
             ctx,
             msg,
-            &whois_data,
+            db,
             guild_id,
             &member.user.id.to_string(),
             None,
+            &privacy,
+            dataset.as_deref(),
         )
         .await?
         {
@@ -151,10 +460,12 @@ async fn identify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
         if display_whois_entry(
             ctx,
             msg,
-            &whois_data,
+            db,
             guild_id,
             &search_result.user.id.to_string(),
             Some(&display_matches),
+            &privacy,
+            dataset.as_deref(),
         )
         .await?
         {
@@ -212,33 +523,26 @@ async fn identify(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
     Ok(())
 }
 
+/// Renders the member's entry with its dataset's display template. Members in more than one
+/// dataset are shown using the first, starting with the main dataset.
 async fn get_display_form(
-    whois_data: &Collection,
-    display_field: &str,
+    db: &Database,
+    templates: &DisplayTemplates,
     guild_id: &u64,
-    id: &String,
+    discord: &DiscordInfo,
+    privacy: &Privacy,
 ) -> CommandResult<String> {
-    match whois_data
-        .find_one(
-            doc! {
-                "_guild": guild_id,
-                "_user": id,
-            },
-            None,
-        )
+    if privacy.is_opted_out(&discord.id) {
+        return Ok(format!("[<@{}> not shared]", discord.id));
+    }
+    match datasets::find_entries(db, *guild_id, &discord.id, None)
         .await?
+        .first()
     {
-        Some(doc) => {
-            lazy_static! {
-                static ref DISPLAY_FIELD: Regex = Regex::new(r"\{\{(.+?)\}\}").unwrap();
-            }
-            let display = DISPLAY_FIELD.replace_all(display_field, |captures: &Captures| {
-                let field_name = captures.get(1).map_or("", |m| m.as_str());
-                doc.get_str(field_name).unwrap_or("")
-            });
-            Ok(String::from(display))
-        }
-        None => Ok(format!("[<@{}> not known]", id)),
+        Some(doc) => Ok(templates
+            .for_entry(doc)
+            .render(Some(&privacy.visible_document(doc)), discord)),
+        None => Ok(format!("[<@{}> not known]", discord.id)),
     }
 }
 
@@ -250,8 +554,8 @@ async fn get_display_form(
 /// the last 5 messages are checked. The number of messages is limited by the maximum message
 /// length and the maximum number of messages I can fetch.
 async fn here(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let guild_id = match msg.guild_id {
-        Some(id) => id.as_u64().to_owned(),
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
         None => {
             msg.channel_id
                 .say(&ctx.http, "You aren't in a server.")
@@ -265,26 +569,86 @@ async fn here(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
         .channel_id
         .messages(&ctx.http, |retriever| retriever.before(msg.id).limit(limit))
         .await?;
+    let authors = messages
+        .into_iter()
+        .map(|message| message.author)
+        .collect::<Vec<User>>();
+
+    // The messages are newest first, so cut off the oldest and then put them in chat order
+    introduce(ctx, msg, &guild, &authors, true).await
+}
+
+#[command]
+#[aliases("voice")]
+/// Get information about everyone in the voice channel you're in.
+async fn vc(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let channel_id = match guild
+        .voice_states
+        .get(&msg.author.id)
+        .and_then(|voice_state| voice_state.channel_id)
+    {
+        Some(channel_id) => channel_id,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a voice channel.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let mut users = Vec::new();
+    for (user_id, _) in guild
+        .voice_states
+        .iter()
+        .filter(|(_, voice_state)| voice_state.channel_id == Some(channel_id))
+    {
+        users.push(match guild.members.get(user_id) {
+            Some(member) => member.user.clone(),
+            None => user_id.to_user(ctx).await?,
+        });
+    }
+    users.sort_by_key(|user| user.name.to_lowercase());
+
+    introduce(ctx, msg, &guild, &users, false).await
+}
+
+/// Introduces the users with their dataset's `display` template, cutting off the list before it
+/// gets too long. If `reverse` is true, the end of the list is cut off and then the list is
+/// reversed.
+async fn introduce(
+    ctx: &Context,
+    msg: &Message,
+    guild: &Guild,
+    users: &[User],
+    reverse: bool,
+) -> CommandResult {
+    let guild_id = guild.id.as_u64().to_owned();
 
     let data = ctx.data.read().await;
     let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
-    let whois_data = db.collection("whois-data");
-    let whois_settings = db.collection("whois-settings");
 
-    let settings = whois_settings
-        .find_one(doc! { "_guild": guild_id }, None)
-        .await?
-        .unwrap_or_else(|| Document::new());
-    let display_field = settings.get_str("display").unwrap_or("<@{{_user}}>");
+    let display_templates = DisplayTemplates::load(db, guild_id).await?;
+    let privacy = Privacy::load(db, guild_id, Viewer::from_message(guild, msg)).await?;
 
     let mut names = Vec::new();
     let mut total_length: usize = 0;
-    for msg in &messages {
+    for user in users {
         let display = get_display_form(
-            &whois_data,
-            display_field,
+            db,
+            &display_templates,
             &guild_id,
-            &msg.author.id.to_string(),
+            &DiscordInfo::from_guild(guild, user),
+            &privacy,
         )
         .await?;
         // Include an extra character for the newline
@@ -296,7 +660,9 @@ async fn here(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
             break;
         }
     }
-    names.reverse();
+    if reverse {
+        names.reverse();
+    }
 
     msg.channel_id
         .send_message(&ctx.http, |message| {
@@ -314,13 +680,30 @@ async fn here(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
 }
 
 #[command]
-#[usage = "\"[url]\" [id field]"]
+#[usage = "[dataset] \"[url]\" [id field] [option=value...]"]
 #[example = "\"https://example.com/users.csv\" \"User ID\""]
 #[example = ""]
+#[example = "staff \"https://example.com/staff.csv\" \"Discord\""]
+#[example = "staff"]
+#[example = "\"https://example.com/export.csv\" ID delimiter=; header=3 encoding=latin1"]
 #[required_permissions("MANAGE_GUILD")]
 /// Fetch whois informaton from the given URL to a CSV file. The ID field will be used to identify
-/// which Discord user corresponds to which entry. Both arguments are optional and will use the
-/// last given URL/ID field. Requires that you can manage the guild (the MANAGE_GUILD permission).
+/// which Discord user corresponds to which entry. It can have Discord IDs, usernames, or
+/// `name#1234` tags, which I'll look up in the member list (see `:help whois pin`). Both arguments
+/// are optional and will use the last given URL/ID field. Give a dataset name first to fetch into
+/// a separate dataset, which replaces only that dataset's entries (see `:help whois dataset`). If
+/// any rows don't fit the column types set with `:whois schema`, nothing is replaced and I'll list
+/// every bad row. Requires that you can manage the guild (the MANAGE_GUILD permission).
+///
+/// These options change how the CSV file is read, and they're remembered for the next fetch:
+///
+/// - `delimiter` The character between fields, like `;` or `tab`. By default, I'll guess.
+/// - `quote` The character around fields with delimiters in them. By default, `"`.
+/// - `header` The row with the field names, if there are rows above it. By default, 1.
+/// - `encoding` `utf8`, `latin1`, or `auto`, the default, which uses Latin-1 if the file isn't
+/// valid UTF-8. Byte order marks are always handled.
+/// - `trim` Whether to remove spaces around values (`on` or `off`, the default).
+/// - `skipblank` Whether to skip rows without any values (`on`, the default, or `off`).
 async fn fetch(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild_id = match msg.guild_id {
         Some(id) => id.as_u64().to_owned(),
@@ -336,101 +719,229 @@ async fn fetch(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
 
     let whois_data = db.collection("whois-data");
-    let whois_settings = db.collection("whois-settings");
 
-    let settings = whois_settings
-        .find_one(doc! { "_guild": guild_id }, None)
-        .await?
-        .unwrap_or_else(|| Document::new());
+    let dataset_name = match args.current().map(|name| name.to_lowercase()) {
+        Some(name) if datasets::is_dataset_name(&name) => {
+            args.advance();
+            name
+        }
+        _ => String::from(MAIN_DATASET),
+    };
+    let dataset = Dataset::load(db, guild_id, &dataset_name).await?;
 
-    let url_str = match args.single_quoted::<String>() {
-        Ok(url) => url,
-        Err(ArgError::Eos) => {
-            if let Ok(url) = settings.get_str("url") {
-                String::from(url)
-            } else {
-                msg.channel_id
-                    .say(
-                        &ctx.http,
-                        "I don't remember the last URL you used, so you'll have to specify it.",
-                    )
-                    .await?;
+    // CSV options like `delimiter=;` can go anywhere, and the rest are the URL and ID field
+    let mut dialect = dataset.dialect;
+    let mut positional = Vec::new();
+    while !args.is_empty() {
+        let arg = args.single_quoted::<String>()?;
+        match dialect.set_from_arg(&arg) {
+            Some(Ok(())) => {}
+            Some(Err(reason)) => {
+                msg.channel_id.say(&ctx.http, reason).await?;
                 return Ok(());
             }
+            None => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+
+    let url_str = match positional.next().or(dataset.url) {
+        Some(url) => url,
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "I don't remember the last URL you used, so you'll have to specify it.",
+                )
+                .await?;
+            return Ok(());
         }
-        Err(err) => Err(err)?,
     };
 
-    let id_field = match args.single_quoted::<String>() {
-        Ok(id_field) => id_field,
-        Err(ArgError::Eos) => {
-            if let Ok(id_field) = settings.get_str("id") {
-                String::from(id_field)
-            } else {
-                String::from("ID")
+    let id_field = positional
+        .next()
+        .or(dataset.id_field)
+        .unwrap_or_else(|| String::from("ID"));
+
+    let bytes = get(Url::parse(&url_str)?.as_str()).await?.bytes().await?;
+    let table = dialect.read(&bytes)?;
+    let headers = table.headers;
+    let schema = Schema::load(&db.collection("whois-schema"), guild_id, &dataset_name).await?;
+    // Each row's line, ID column, and entry
+    let mut rows = Vec::new();
+    let mut problems = Vec::new();
+
+    for (row, record) in table.rows {
+        // `_fetched` tells the whois gate that the entry didn't come from somewhere else
+        let mut doc = doc! { "_guild": guild_id, "_fetched": true };
+        if let Some(name) = datasets::stored_name(&dataset_name) {
+            doc.insert("_dataset", name);
+        }
+        for (key, value) in headers.iter().zip(record.iter()) {
+            match schema.get(key).parse_value(value) {
+                Ok(value) => {
+                    doc.insert(key, value);
+                }
+                Err(problem) => problems.push((row, format!("`{}` {}", key, problem))),
             }
         }
-        Err(err) => Err(err)?,
-    };
+        match doc.get(id_field.as_str()).and_then(template::display_value) {
+            Some(id) => rows.push((row, id, doc)),
+            None => problems.push((row, format!("There's no value for `{}`", id_field))),
+        }
+    }
 
-    let csv = get(Url::parse(&url_str)?.as_str()).await?.text().await?;
-    let mut reader = csv::Reader::from_reader(csv.as_bytes());
-    let headers = reader.headers()?.clone();
-    let mut data = Vec::new();
+    if !problems.is_empty() {
+        schema::send_report(
+            ctx,
+            msg,
+            &format!(
+                "I didn't replace the whois data because {} rows have problems. Fix them or change the column types with `:whois schema`, then fetch again.\n{}",
+                schema::bad_row_count(&problems),
+                table.description
+            ),
+            &problems,
+        )
+        .await?;
+        return Ok(());
+    }
 
-    for (i, record) in reader.records().enumerate() {
-        let mut doc = doc! { "_guild": guild_id };
-        for (key, value) in headers.iter().zip(record?.iter()) {
-            doc.insert(key, Bson::String(String::from(value)));
-        }
-        let id_value = doc
-            .get(id_field.as_str())
-            .ok_or_else(|| {
-                ErrorWithReason(format!(
-                    "Row {} does not have a value for {}",
-                    i + 1,
-                    id_field
-                ))
-            })?
-            .clone();
-        doc.insert("_user", id_value);
-        data.push(doc);
+    // Rows whose ID column has a name instead of an ID are matched to members. Rows that can't be
+    // are left out and listed after the fetch. The member list is also used to sync roles and
+    // nicknames afterwards.
+    let members = get_all_members(ctx, GuildId(guild_id)).await?;
+    let resolver = Resolver::load(db, GuildId(guild_id), &members).await?;
+    let mut data = Vec::new();
+    let mut unresolved = Vec::new();
+    for (row, id, mut doc) in rows {
+        match resolver.resolve(&id) {
+            Resolution::Found(user_id) => {
+                doc.insert("_user", user_id.to_string());
+                data.push(doc);
+            }
+            resolution => unresolved.extend(
+                resolve::describe_problem(id.trim(), &resolution).map(|problem| (row, problem)),
+            ),
+        }
     }
 
+    let before =
+        audit::load_entries(&whois_data, datasets::entry_filter(guild_id, &dataset_name)).await?;
     whois_data
-        .delete_many(
-            doc! {
-                "_guild": guild_id,
-            },
-            None,
-        )
+        .delete_many(datasets::entry_filter(guild_id, &dataset_name), None)
         .await?;
-    whois_data.insert_many(data, None).await?;
-
-    whois_settings
-        .update_one(
-            doc! {
-                "_guild": guild_id,
-            },
-            doc! {
-                "$set": {
-                    "url": url_str,
-                    "id": id_field,
-                },
-                "$setOnInsert": {
-                    "_guild": &guild_id,
-                },
-            },
-            UpdateOptions::builder().upsert(true).build(),
+    if !data.is_empty() {
+        whois_data.insert_many(data, None).await?;
+    }
+    profile::apply_edits(
+        &whois_data,
+        &db.collection("whois-edits"),
+        &schema,
+        guild_id,
+        &dataset_name,
+    )
+    .await?;
+    let after =
+        audit::load_entries(&whois_data, datasets::entry_filter(guild_id, &dataset_name)).await?;
+
+    let role_changes = rolesync::plan_guild(db, GuildId(guild_id), &members).await?;
+    let role_failures = rolesync::apply_changes(ctx, GuildId(guild_id), &role_changes).await;
+    let nickname_changes = nicksync::plan_guild(ctx, db, GuildId(guild_id), &members).await?;
+    let nickname_failures =
+        nicksync::apply_changes(ctx, db, GuildId(guild_id), &nickname_changes).await;
+
+    Dataset::save_fetch(db, guild_id, &dataset_name, &url_str, &id_field, &dialect).await?;
+    audit::record(
+        db,
+        guild_id,
+        audit::fetch_records(msg.author.id, &dataset_name, &url_str, &before, &after),
+    )
+    .await;
+
+    msg.react(&ctx.http, '👌').await?;
+    msg.channel_id.say(&ctx.http, &table.description).await?;
+
+    if !unresolved.is_empty() {
+        schema::send_report(
+            ctx,
+            msg,
+            &format!(
+                "I left out {} rows because I couldn't tell who they're for.",
+                unresolved.len()
+            ),
+            &unresolved,
         )
         .await?;
+    }
 
-    msg.react(&ctx.http, '👌').await?;
+    if !role_changes.is_empty() {
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.colour(Colour::MAGENTA);
+                    embed.description(describe_changes(
+                        role_changes.iter().map(|change| change.describe()),
+                        &role_failures,
+                    ));
+                    embed
+                });
+                message.content(format!(
+                    "I also made {} of {} role changes from the `:whois rolemap` rules.",
+                    role_changes.len() - role_failures.len(),
+                    role_changes.len()
+                ));
+                message
+            })
+            .await?;
+    }
+    if !nickname_changes.is_empty() {
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.colour(Colour::MAGENTA);
+                    embed.description(describe_changes(
+                        nickname_changes.iter().map(|change| change.describe()),
+                        &nickname_failures,
+                    ));
+                    embed
+                });
+                message.content(format!(
+                    "I also made {} of {} nickname changes from the `nickname` template.",
+                    nickname_changes.len() - nickname_failures.len(),
+                    nickname_changes.len()
+                ));
+                message
+            })
+            .await?;
+    }
 
     Ok(())
 }
 
-const VALID_OPTION_NAMES: [&str; 3] = ["id", "url", "display"];
+const VALID_OPTION_NAMES: [&str; 13] = [
+    "id",
+    "url",
+    "display",
+    "editable",
+    "nickname",
+    "nicksync",
+    "log",
+    "gate",
+    "verified",
+    "unverified",
+    "modchannel",
+    "reaction",
+    "auditdays",
+];
+
+/// Options that can only be `on` or `off`.
+const ON_OFF_OPTION_NAMES: [&str; 2] = ["nicksync", "gate"];
+
+/// Options that hold a role or channel mention.
+const MENTION_OPTION_NAMES: [&str; 4] = ["log", "verified", "unverified", "modchannel"];
+
+/// Options that hold a template, which are checked before they're saved.
+const TEMPLATE_OPTION_NAMES: [&str; 2] = ["display", "nickname"];
 
 #[command]
 #[usage = r#"<option name> "[option value]""#]
@@ -438,12 +949,39 @@ const VALID_OPTION_NAMES: [&str; 3] = ["id", "url", "display"];
 #[example = "display"]
 #[required_permissions("MANAGE_GUILD")]
 /// Set server-wide configuration options for whois output. If the option value isn't given, then
-/// the option will be returned instead of set. Here's a list of option names:
+/// the option will be returned instead of set. The `id`, `url`, and `display` options are for the
+/// main dataset; other datasets have their own (see `:help whois dataset`). Here's a list of option
+/// names:
 ///
 /// - `id` The field name that contains the Discord ID.
 /// - `url` The last used fetch URL for `:whois fetch`.
 /// - `display` Define the format for a summary of the whois data for a person. Use `{{field
 /// name}}` to denote field names.
+/// - `editable` A comma-separated list of fields that members can change in their own entry with
+/// `:whois set`.
+/// - `nickname` Like `display`, but for setting members' nicknames. Only public fields are used,
+/// and nicknames longer than 32 characters are cut off.
+/// - `nicksync` Whether to set members' nicknames from the `nickname` template after each fetch
+/// and when they join (`on` or `off`).
+/// - `log` The channel where I'll post the changes I make on my own, like nicknames.
+/// - `gate` Whether new members have to be in the whois data to be verified (`on` or `off`).
+/// Members who aren't have to be approved with `:whois approve`.
+/// - `verified` The role given to verified members. The gate won't work without it.
+/// - `unverified` The role given to new members until they're verified, if any.
+/// - `modchannel` The channel where I'll ask the mods to approve new members.
+/// - `reaction` An emoji that members can react to a message with to have me DM them what I know
+/// about its author. It can be a custom emoji from this server.
+/// - `auditdays` How many days I keep records of whois changes (see `:help whois audit`), up to
+/// 3650. It's 365 at first.
+///
+/// The `display` and `nickname` formats can also do the following:
+///
+/// - `{{Nickname|First Name|"???"}}` Use the first field that isn't empty, or some quoted text.
+/// - `{{@nickname}}` Use what Discord knows about the member: `@id`, `@mention`, `@username`,
+/// `@nickname`, `@top_role`, or `@joined`.
+/// - `{{Last Name:initials}}` Apply filters: `upper`, `lower`, `initials`, or `truncate(length)`.
+/// - `{{#if Pronouns}}({{Pronouns}}){{else}}(ask){{/if}}` Only show something if a field isn't
+/// empty.
 async fn config(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let guild_id = match msg.guild_id {
         Some(id) => id.as_u64().to_owned(),
@@ -469,7 +1007,100 @@ async fn config(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
     let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
     let whois_settings = db.collection("whois-settings");
 
+    if let (Ok(value), true) = (
+        &option_value,
+        ON_OFF_OPTION_NAMES.contains(&option_name.as_str()),
+    ) {
+        if value != "on" && value != "off" {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("`{}` can only be `on` or `off`.", option_name),
+                )
+                .await?;
+            return Ok(());
+        }
+    }
+
+    if let (Ok(value), true) = (
+        &option_value,
+        MENTION_OPTION_NAMES.contains(&option_name.as_str()),
+    ) {
+        if parse_id(value).is_none() {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("`{}` should be a mention or an ID.", option_name),
+                )
+                .await?;
+            return Ok(());
+        }
+    }
+
+    if let (Ok(value), "auditdays") = (&option_value, option_name.as_str()) {
+        let days = value.parse::<i64>().unwrap_or(0);
+        if !(1..=audit::MAX_RETENTION_DAYS).contains(&days) {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "`auditdays` should be a number of days between 1 and {}.",
+                        audit::MAX_RETENTION_DAYS
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+    }
+
+    if let (Ok(value), true) = (
+        &option_value,
+        TEMPLATE_OPTION_NAMES.contains(&option_name.as_str()),
+    ) {
+        let template = match Template::parse(value) {
+            Ok(template) => template,
+            Err(err) => {
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("I can't use that format. {}", err.describe(value)),
+                    )
+                    .await?;
+                return Ok(());
+            }
+        };
+        let whois_data = db.collection("whois-data");
+        if let Some(sample) = whois_data
+            .find_one(datasets::entry_filter(guild_id, MAIN_DATASET), None)
+            .await?
+        {
+            if let Some(field) = template
+                .fields()
+                .iter()
+                .find(|field| !sample.contains_key(&field.name))
+            {
+                let err = template::TemplateError {
+                    position: field.position,
+                    length: field.length,
+                    reason: format!("The whois data doesn't have a field named `{}`", field.name),
+                };
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        format!("I can't use that format. {}", err.describe(value)),
+                    )
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+
     if let Ok(value) = option_value {
+        let before = whois_settings
+            .find_one(doc! { "_guild": guild_id }, None)
+            .await?
+            .and_then(|settings| settings.get(&option_name).cloned());
+        let change = audit::change(&option_name, before, Some(Bson::String(value.clone())));
         whois_settings
             .update_one(
                 doc! {
@@ -486,6 +1117,18 @@ async fn config(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
                 UpdateOptions::builder().upsert(true).build(),
             )
             .await?;
+        audit::record(
+            db,
+            guild_id,
+            vec![audit::Record {
+                action: "config",
+                actor: msg.author.id,
+                user: None,
+                note: String::new(),
+                changes: vec![change],
+            }],
+        )
+        .await;
         msg.react(&ctx.http, '👌').await?;
     } else {
         let settings = whois_settings
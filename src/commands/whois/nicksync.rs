@@ -0,0 +1,285 @@
+//! Sets members' server nicknames from the whois `nickname` template when the `nicksync` option is
+//! on.
+use super::{
+    datasets, describe_changes, entries_by_member, get_all_members, log_change,
+    privacy::{load_opted_out, FieldRules, Viewer},
+    template::{DiscordInfo, Template},
+};
+use crate::db;
+use mongodb::{
+    bson::{doc, Document},
+    Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        guild::{Member, Role},
+        id::{GuildId, RoleId, UserId},
+    },
+    utils::Colour,
+};
+use std::collections::HashMap;
+
+/// The longest nickname Discord allows.
+const MAX_NICKNAME_LENGTH: usize = 32;
+
+/// Trims the nickname and cuts it off with an ellipsis if it's too long for Discord. Returns
+/// `None` if there's nothing left.
+fn fit_nickname(nickname: &str) -> Option<String> {
+    let nickname = nickname.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if nickname.is_empty() {
+        None
+    } else if nickname.chars().count() > MAX_NICKNAME_LENGTH {
+        let mut truncated = nickname
+            .chars()
+            .take(MAX_NICKNAME_LENGTH - 1)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        truncated.push('…');
+        Some(truncated)
+    } else {
+        Some(nickname)
+    }
+}
+
+pub struct NicknameChange {
+    user_id: UserId,
+    old: Option<String>,
+    new: String,
+}
+
+impl NicknameChange {
+    pub fn describe(&self) -> String {
+        format!(
+            "<@{}>: {} → `{}`",
+            self.user_id,
+            self.old
+                .as_ref()
+                .map_or_else(|| String::from("(no nickname)"), |old| format!("`{}`", old)),
+            self.new
+        )
+    }
+}
+
+/// Everything needed to work out members' nicknames.
+struct NicknameSync {
+    template: Template,
+    rules: FieldRules,
+    roles: HashMap<RoleId, Role>,
+}
+
+impl NicknameSync {
+    /// Returns `None` if the guild hasn't turned on `nicksync` or set a `nickname` template.
+    async fn load(ctx: &Context, db: &Database, guild_id: GuildId) -> CommandResult<Option<Self>> {
+        let settings = db
+            .collection("whois-settings")
+            .find_one(doc! { "_guild": guild_id.0 }, None)
+            .await?
+            .unwrap_or_else(|| Document::new());
+        if settings.get_str("nicksync").ok() != Some("on") {
+            return Ok(None);
+        }
+        let template = match settings
+            .get_str("nickname")
+            .ok()
+            .and_then(|nickname| Template::parse(nickname).ok())
+        {
+            Some(template) => template,
+            None => return Ok(None),
+        };
+        Ok(Some(NicknameSync {
+            template,
+            rules: FieldRules::load(&db.collection("whois-fields"), guild_id.0).await?,
+            roles: guild_id.roles(&ctx.http).await?,
+        }))
+    }
+
+    /// Nicknames are seen by everyone, so only public fields are used.
+    fn plan_member(&self, entry: &Document, member: &Member) -> Option<NicknameChange> {
+        if member.user.bot {
+            return None;
+        }
+        let entry = self.rules.visible_document(entry, &Viewer::public());
+        let discord = DiscordInfo::from_member(&self.roles, member);
+        let new = fit_nickname(&self.template.render(Some(&entry), &discord))?;
+        if member.nick.as_ref() == Some(&new) {
+            None
+        } else {
+            Some(NicknameChange {
+                user_id: member.user.id,
+                old: member.nick.clone(),
+                new,
+            })
+        }
+    }
+}
+
+/// Works out the nickname changes for every member of the guild who has a whois entry and hasn't
+/// opted out.
+pub async fn plan_guild(
+    ctx: &Context,
+    db: &Database,
+    guild_id: GuildId,
+    members: &[Member],
+) -> CommandResult<Vec<NicknameChange>> {
+    let sync = match NicknameSync::load(ctx, db, guild_id).await? {
+        Some(sync) => sync,
+        None => return Ok(Vec::new()),
+    };
+    let opted_out = load_opted_out(&db.collection("whois-optouts"), guild_id.0).await?;
+
+    let entries = entries_by_member(db, guild_id).await?;
+    let mut changes = Vec::new();
+    for member in members {
+        let user_id = member.user.id.to_string();
+        if opted_out.contains(&user_id) {
+            continue;
+        }
+        if let Some(entry) = entries.get(&user_id) {
+            changes.extend(sync.plan_member(entry, member));
+        }
+    }
+    Ok(changes)
+}
+
+/// Changes the nicknames and logs each change. Returns a description of each change that Discord
+/// refused, such as for members above Moofy's highest role or the server owner.
+pub async fn apply_changes(
+    ctx: &Context,
+    db: &Database,
+    guild_id: GuildId,
+    changes: &[NicknameChange],
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    for change in changes {
+        if let Err(why) = guild_id
+            .edit_member(&ctx.http, change.user_id, |member| {
+                member.nickname(&change.new)
+            })
+            .await
+        {
+            failures.push(format!(
+                "Couldn't change <@{}>'s nickname: {}",
+                change.user_id, why
+            ));
+        } else {
+            log_change(
+                ctx,
+                db,
+                guild_id.0,
+                format!("Changed nickname of {}", change.describe()),
+            )
+            .await;
+        }
+    }
+    failures
+}
+
+/// Sets the nickname of a member who just joined.
+pub async fn sync_member(
+    ctx: &Context,
+    db: &Database,
+    guild_id: GuildId,
+    member: &Member,
+) -> CommandResult {
+    let sync = match NicknameSync::load(ctx, db, guild_id).await? {
+        Some(sync) => sync,
+        None => return Ok(()),
+    };
+    let user_id = member.user.id.to_string();
+    if load_opted_out(&db.collection("whois-optouts"), guild_id.0)
+        .await?
+        .contains(&user_id)
+    {
+        return Ok(());
+    }
+    let entries = datasets::find_entries(db, guild_id.0, &user_id, None).await?;
+    if let Some(entry) = datasets::merge_entries(&entries) {
+        let changes = sync
+            .plan_member(&entry, member)
+            .into_iter()
+            .collect::<Vec<_>>();
+        for failure in apply_changes(ctx, db, guild_id, &changes).await {
+            println!("Nickname sync for a new member had an error: {}", failure);
+        }
+    }
+    Ok(())
+}
+
+#[command]
+#[usage = "[dry]"]
+#[example = ""]
+#[example = "dry"]
+#[required_permissions("MANAGE_NICKNAMES")]
+/// Set everyone's nickname from the `nickname` template now. This only works if the `nicksync`
+/// option is `on` (see `:help whois config`). With `dry`, I'll only list the changes I would make.
+/// Requires that you can manage nicknames (the MANAGE_NICKNAMES permission).
+async fn nicknames(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let dry_run = args.rest().trim().eq_ignore_ascii_case("dry");
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    if NicknameSync::load(ctx, db, guild_id).await?.is_none() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "Nickname syncing is off. The mods can turn it on with `:whois config nickname \"<template>\"` and `:whois config nicksync on`.",
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let members = get_all_members(ctx, guild_id).await?;
+    let changes = plan_guild(ctx, db, guild_id, &members).await?;
+    let failures = if dry_run {
+        Vec::new()
+    } else {
+        apply_changes(ctx, db, guild_id, &changes).await
+    };
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.colour(if failures.is_empty() {
+                    Colour::MAGENTA
+                } else {
+                    Colour::RED
+                });
+                embed.description(if changes.is_empty() {
+                    String::from("Everyone already has the right nickname.")
+                } else {
+                    describe_changes(changes.iter().map(|change| change.describe()), &failures)
+                });
+                embed
+            });
+            message.content(if dry_run {
+                format!(
+                    "Here are the {} nickname changes I would make:",
+                    changes.len()
+                )
+            } else {
+                format!(
+                    "I made {} of {} nickname changes.",
+                    changes.len() - failures.len(),
+                    changes.len()
+                )
+            });
+            message
+        })
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,539 @@
+//! Who gets to see which whois fields, in what order, and whether members have opted out of whois
+//! entirely.
+use super::{audit, parse_id, template::display_value};
+use crate::db;
+use mongodb::{
+    bson::{doc, Document},
+    options::UpdateOptions,
+    Collection, Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, ArgError, Args, CommandResult},
+    model::{
+        channel::Message,
+        guild::Guild,
+        id::{RoleId, UserId},
+    },
+    utils::Colour,
+};
+use std::collections::HashSet;
+use tokio::stream::StreamExt;
+
+/// Who can see a whois field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Visibility {
+    Public,
+    /// Only members with the role (and mods) can see it.
+    Role(u64),
+    /// Only members who can manage the server can see it.
+    Mods,
+    Hidden,
+}
+
+impl Visibility {
+    fn from_doc(doc: &Document) -> Self {
+        match doc.get_str("visibility").unwrap_or("public") {
+            "role" => match doc.get_i64("role") {
+                Ok(role) => Visibility::Role(role as u64),
+                Err(_) => Visibility::Mods,
+            },
+            "mods" => Visibility::Mods,
+            "hidden" => Visibility::Hidden,
+            _ => Visibility::Public,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Visibility::Public => String::from("everyone"),
+            Visibility::Role(role) => format!("members with <@&{}> and mods", role),
+            Visibility::Mods => String::from("mods"),
+            Visibility::Hidden => String::from("nobody"),
+        }
+    }
+}
+
+/// The person looking at whois entries.
+pub struct Viewer {
+    id: UserId,
+    roles: Vec<RoleId>,
+    is_mod: bool,
+}
+
+impl Viewer {
+    pub fn new(guild: &Guild, id: UserId, roles: Vec<RoleId>) -> Self {
+        Viewer {
+            id,
+            roles,
+            is_mod: guild.member_permissions(id).manage_guild(),
+        }
+    }
+
+    /// Someone who can only see public fields, for when whois data is shown to everyone.
+    pub fn public() -> Self {
+        Viewer {
+            id: UserId(0),
+            roles: Vec::new(),
+            is_mod: false,
+        }
+    }
+
+    pub fn from_message(guild: &Guild, msg: &Message) -> Self {
+        Viewer::new(
+            guild,
+            msg.author.id,
+            msg.member
+                .as_ref()
+                .map_or_else(Vec::new, |member| member.roles.clone()),
+        )
+    }
+
+    /// Whether the viewer can see a field with the given visibility in `subject`'s entry. Members
+    /// can always see their own fields unless they're hidden.
+    fn can_see(&self, visibility: Visibility, subject: &str) -> bool {
+        match visibility {
+            Visibility::Public => true,
+            Visibility::Hidden => false,
+            _ if self.is_mod || self.id.to_string() == subject => true,
+            Visibility::Role(role) => self.roles.contains(&RoleId(role)),
+            Visibility::Mods => false,
+        }
+    }
+}
+
+/// A mod's settings for a whois field.
+#[derive(Debug, Clone)]
+pub struct FieldSettings {
+    pub name: String,
+    pub visibility: Visibility,
+    pub order: Option<i64>,
+    pub inline: bool,
+}
+
+impl FieldSettings {
+    fn new(name: &str) -> Self {
+        FieldSettings {
+            name: String::from(name),
+            visibility: Visibility::Public,
+            order: None,
+            inline: true,
+        }
+    }
+
+    fn from_doc(doc: &Document) -> Option<Self> {
+        Some(FieldSettings {
+            name: String::from(doc.get_str("name").ok()?),
+            visibility: Visibility::from_doc(doc),
+            order: doc
+                .get_i64("order")
+                .ok()
+                .or_else(|| doc.get_i32("order").ok().map(|order| order as i64)),
+            inline: doc.get_bool("inline").unwrap_or(true),
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "Visible to {}. {}. {}.",
+            self.visibility.describe(),
+            match self.order {
+                Some(order) => format!("Shown in position {}", order),
+                None => String::from("Shown in CSV order"),
+            },
+            if self.inline {
+                "Inline"
+            } else {
+                "Takes up a whole row"
+            }
+        )
+    }
+}
+
+/// All the field settings for a guild.
+pub struct FieldRules {
+    fields: Vec<FieldSettings>,
+}
+
+impl FieldRules {
+    pub async fn load(whois_fields: &Collection, guild_id: u64) -> CommandResult<Self> {
+        let mut cursor = whois_fields.find(doc! { "_guild": guild_id }, None).await?;
+        let mut fields = Vec::new();
+        while let Some(doc_result) = cursor.next().await {
+            if let Some(settings) = FieldSettings::from_doc(&doc_result?) {
+                fields.push(settings);
+            }
+        }
+        Ok(FieldRules { fields })
+    }
+
+    pub fn get(&self, name: &str) -> FieldSettings {
+        self.fields
+            .iter()
+            .find(|settings| settings.name == name)
+            .cloned()
+            .unwrap_or_else(|| FieldSettings::new(name))
+    }
+
+    fn subject(doc: &Document) -> &str {
+        doc.get_str("_user").unwrap_or("")
+    }
+
+    /// The fields of a whois entry that the viewer can see, as `(name, value, inline)` in display
+    /// order.
+    pub fn visible_fields(&self, doc: &Document, viewer: &Viewer) -> Vec<(String, String, bool)> {
+        let subject = FieldRules::subject(doc);
+        let mut fields = doc
+            .iter()
+            .filter(|(key, _)| !key.starts_with('_'))
+            .filter_map(|(key, value)| {
+                let settings = self.get(key);
+                if viewer.can_see(settings.visibility, subject) {
+                    display_value(value).map(|value| (settings, value))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<(FieldSettings, String)>>();
+        // Fields without an order go last. The sort is stable, so they stay in CSV order.
+        fields.sort_by_key(|(settings, _)| settings.order.unwrap_or(i64::MAX));
+        fields
+            .into_iter()
+            .map(|(settings, value)| (settings.name, value, settings.inline))
+            .collect()
+    }
+
+    /// A copy of the whois entry without the fields the viewer can't see, for rendering templates.
+    pub fn visible_document(&self, doc: &Document, viewer: &Viewer) -> Document {
+        let subject = FieldRules::subject(doc);
+        doc.iter()
+            .filter(|(key, _)| {
+                key.starts_with('_') || viewer.can_see(self.get(key).visibility, subject)
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// The IDs of the members who have opted out of having their whois entry shown.
+pub async fn load_opted_out(
+    whois_optouts: &Collection,
+    guild_id: u64,
+) -> CommandResult<HashSet<String>> {
+    let mut cursor = whois_optouts
+        .find(doc! { "_guild": guild_id }, None)
+        .await?;
+    let mut opted_out = HashSet::new();
+    while let Some(doc_result) = cursor.next().await {
+        if let Ok(user_id) = doc_result?.get_str("_user") {
+            opted_out.insert(String::from(user_id));
+        }
+    }
+    Ok(opted_out)
+}
+
+/// Everything needed to decide what the viewer gets to see.
+pub struct Privacy {
+    pub rules: FieldRules,
+    pub viewer: Viewer,
+    opted_out: HashSet<String>,
+}
+
+impl Privacy {
+    pub async fn load(db: &Database, guild_id: u64, viewer: Viewer) -> CommandResult<Self> {
+        Ok(Privacy {
+            rules: FieldRules::load(&db.collection("whois-fields"), guild_id).await?,
+            viewer,
+            opted_out: load_opted_out(&db.collection("whois-optouts"), guild_id).await?,
+        })
+    }
+
+    pub fn is_opted_out(&self, user_id: &str) -> bool {
+        self.opted_out.contains(user_id)
+    }
+
+    pub fn visible_fields(&self, doc: &Document) -> Vec<(String, String, bool)> {
+        self.rules.visible_fields(doc, &self.viewer)
+    }
+
+    pub fn visible_document(&self, doc: &Document) -> Document {
+        self.rules.visible_document(doc, &self.viewer)
+    }
+}
+
+#[command]
+#[usage = "\"[field]\" [visibility|order|inline] [value]"]
+#[example = ""]
+#[example = "\"Phone Number\" visibility mods"]
+#[example = "Grade visibility role @Students"]
+#[example = "Pronouns order 1"]
+#[example = "Bio inline no"]
+#[required_permissions("MANAGE_GUILD")]
+/// Control how a whois field is shown. Without a setting, the field's current settings are shown,
+/// and without a field, every field with settings is listed. Requires that you can manage the
+/// guild (the MANAGE_GUILD permission).
+///
+/// - `visibility` Who can see the field: `public`, `role <role>` (members with the role), `mods`,
+/// or `hidden`. Mods can see every field that isn't hidden, and members can see their own.
+/// - `order` A number; fields are shown from lowest to highest. Fields without one go last. Use
+/// `none` to clear it.
+/// - `inline` Whether the field can share a row with other fields (`yes` or `no`).
+async fn field(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_fields = db.collection("whois-fields");
+
+    let field_name = match args.single_quoted::<String>() {
+        Ok(field_name) => field_name,
+        Err(ArgError::Eos) => {
+            let rules = FieldRules::load(&whois_fields, guild_id).await?;
+            msg.channel_id
+                .send_message(&ctx.http, |message| {
+                    message.embed(|embed| {
+                        embed.colour(Colour::MAGENTA);
+                        if rules.fields.is_empty() {
+                            embed.description("No fields have settings, so every field is public.");
+                        }
+                        for settings in rules.fields.iter().take(25) {
+                            embed.field(&settings.name, settings.describe(), false);
+                        }
+                        embed
+                    });
+                    message
+                })
+                .await?;
+            return Ok(());
+        }
+        Err(err) => Err(err)?,
+    };
+    if field_name.starts_with('_') {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "Fields starting with `_` are never shown anyways.",
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let setting = match args.single::<String>() {
+        Ok(setting) => setting.to_lowercase(),
+        Err(ArgError::Eos) => {
+            let settings = FieldRules::load(&whois_fields, guild_id)
+                .await?
+                .get(&field_name);
+            msg.channel_id
+                .send_message(&ctx.http, |message| {
+                    message.embed(|embed| {
+                        embed.colour(Colour::MAGENTA);
+                        embed.title(&settings.name);
+                        embed.description(settings.describe());
+                        embed
+                    });
+                    message
+                })
+                .await?;
+            return Ok(());
+        }
+        Err(err) => Err(err)?,
+    };
+    let value = args.single::<String>().unwrap_or_default().to_lowercase();
+
+    let mut update = match (setting.as_str(), value.as_str()) {
+        ("visibility", "public") | ("visibility", "mods") | ("visibility", "hidden") => {
+            doc! { "$set": { "visibility": &value }, "$unset": { "role": "" } }
+        }
+        ("visibility", "role") => match parse_id(args.rest()) {
+            Some(role_id) => doc! {
+                "$set": {
+                    "visibility": "role",
+                    "role": role_id,
+                },
+            },
+            None => {
+                msg.channel_id
+                    .say(&ctx.http, "Which role? Mention it or give its ID.")
+                    .await?;
+                return Ok(());
+            }
+        },
+        ("order", "none") => doc! { "$unset": { "order": "" } },
+        ("order", order) => match order.parse::<i64>() {
+            Ok(order) => doc! { "$set": { "order": order } },
+            Err(_) => {
+                msg.channel_id
+                    .say(&ctx.http, "The order should be a whole number or `none`.")
+                    .await?;
+                return Ok(());
+            }
+        },
+        ("inline", "yes") | ("inline", "true") => doc! { "$set": { "inline": true } },
+        ("inline", "no") | ("inline", "false") => doc! { "$set": { "inline": false } },
+        _ => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "I don't understand. Do `:help whois field` for a list of settings.",
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    let after = update
+        .get_document("$set")
+        .ok()
+        .and_then(|set| set.get(&setting).cloned());
+    update.insert(
+        "$setOnInsert",
+        doc! {
+            "_guild": guild_id,
+            "name": &field_name,
+        },
+    );
+
+    let filter = doc! {
+        "_guild": guild_id,
+        "name": &field_name,
+    };
+    let before = whois_fields
+        .find_one(filter.clone(), None)
+        .await?
+        .and_then(|settings| settings.get(&setting).cloned());
+    whois_fields
+        .update_one(
+            filter,
+            update,
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    audit::record(
+        db,
+        guild_id,
+        vec![audit::Record {
+            action: "field",
+            actor: msg.author.id,
+            user: None,
+            note: format!("Changed the settings of `{}`.", field_name),
+            changes: vec![audit::change(&setting, before, after)],
+        }],
+    )
+    .await;
+
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
+
+/// Records a member opting out or back in.
+async fn record_optout(
+    db: &Database,
+    guild_id: u64,
+    msg: &Message,
+    action: &'static str,
+    note: &str,
+) {
+    audit::record(
+        db,
+        guild_id,
+        vec![audit::Record {
+            action,
+            actor: msg.author.id,
+            user: Some(msg.author.id.to_string()),
+            note: String::from(note),
+            changes: Vec::new(),
+        }],
+    )
+    .await;
+}
+
+#[command]
+#[usage = ""]
+#[example = ""]
+/// Stop `:whois identify` and `:whois here` from showing anything about you. Do `:whois optin` to
+/// undo this.
+async fn optout(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_optouts = db.collection("whois-optouts");
+
+    let user_id = msg.author.id.to_string();
+    let result = whois_optouts
+        .update_one(
+            doc! {
+                "_guild": guild_id,
+                "_user": &user_id,
+            },
+            doc! {
+                "$setOnInsert": {
+                    "_guild": guild_id,
+                    "_user": &user_id,
+                },
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    if result.upserted_id.is_some() {
+        record_optout(db, guild_id, msg, "optout", "Opted out of whois.").await;
+    }
+
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
+
+#[command]
+#[usage = ""]
+#[example = ""]
+/// Let `:whois identify` and `:whois here` show your whois entry again after `:whois optout`.
+async fn optin(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_optouts = db.collection("whois-optouts");
+
+    let result = whois_optouts
+        .delete_one(
+            doc! {
+                "_guild": guild_id,
+                "_user": msg.author.id.to_string(),
+            },
+            None,
+        )
+        .await?;
+    if result.deleted_count > 0 {
+        record_optout(db, guild_id, msg, "optin", "Opted back in to whois.").await;
+    }
+
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
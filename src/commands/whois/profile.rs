@@ -0,0 +1,365 @@
+//! Lets members edit some fields of their own whois entry. Their edits are stored separately in
+//! `whois-edits` so that they can be applied again after the next `:whois fetch`.
+use super::{audit, datasets, schema::Schema};
+use crate::db;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::ReplaceOptions,
+    Collection, Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+};
+use tokio::stream::StreamExt;
+
+/// The longest value a member can set, which is the most Discord allows in an embed field.
+const MAX_VALUE_LENGTH: usize = 1024;
+
+/// Gets the field names that members are allowed to edit from the comma-separated `editable`
+/// option.
+pub fn editable_fields(settings: &Document) -> Vec<String> {
+    settings
+        .get_str("editable")
+        .unwrap_or("")
+        .split(',')
+        .map(|field| field.trim())
+        .filter(|field| !field.is_empty() && !field.starts_with('_'))
+        .map(String::from)
+        .collect()
+}
+
+/// Finds the editable field with the given name, ignoring case.
+async fn find_editable_field(
+    ctx: &Context,
+    msg: &Message,
+    whois_settings: &Collection,
+    guild_id: u64,
+    field: &str,
+) -> CommandResult<Option<String>> {
+    let settings = whois_settings
+        .find_one(doc! { "_guild": guild_id }, None)
+        .await?
+        .unwrap_or_else(|| Document::new());
+    let editable = editable_fields(&settings);
+    match editable
+        .iter()
+        .find(|editable_field| editable_field.to_lowercase() == field.to_lowercase())
+    {
+        Some(editable_field) => Ok(Some(editable_field.clone())),
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    if editable.is_empty() {
+                        String::from("The mods haven't let anyone edit their own whois entry. They can do so with `:whois config editable \"<field>, <field>\"`.")
+                    } else {
+                        format!(
+                            "You can't edit that field. You can only edit {}.",
+                            editable
+                                .iter()
+                                .map(|field| format!("`{}`", field))
+                                .collect::<Vec<String>>()
+                                .join(", ")
+                        )
+                    },
+                )
+                .await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Finds the entry the member wants to edit: the one in the named dataset, or else the first one
+/// with the field, starting with the main dataset.
+async fn find_own_entry(
+    db: &Database,
+    guild_id: u64,
+    user_id: &str,
+    dataset: Option<&str>,
+    field: &str,
+) -> CommandResult<Option<Document>> {
+    let mut entries = datasets::find_entries(db, guild_id, user_id, dataset).await?;
+    let index = entries
+        .iter()
+        .position(|entry| entry.contains_key(field))
+        .unwrap_or(0);
+    Ok(if entries.is_empty() {
+        None
+    } else {
+        Some(entries.swap_remove(index))
+    })
+}
+
+/// Finds a member's entry or edits in a dataset.
+fn member_filter(guild_id: u64, dataset: &str, user_id: &str) -> Document {
+    let mut filter = datasets::entry_filter(guild_id, dataset);
+    filter.insert("_user", user_id);
+    filter
+}
+
+/// Stores the member's edit to a field and applies it to their whois entry. Returns the field's
+/// previous value. Field names can have dots, which `$set` would read as a path, so the documents
+/// are changed here and replaced whole.
+async fn save_edit(
+    whois_data: &Collection,
+    whois_edits: &Collection,
+    guild_id: u64,
+    mut entry: Document,
+    field: &str,
+    value: &str,
+    parsed: Bson,
+) -> CommandResult<Option<Bson>> {
+    let dataset = String::from(datasets::dataset_of(&entry));
+    let user_id = String::from(entry.get_str("_user").unwrap_or_default());
+    let filter = member_filter(guild_id, &dataset, &user_id);
+    let mut edit = match whois_edits.find_one(filter.clone(), None).await? {
+        Some(edit) => edit,
+        None => {
+            let mut edit = doc! {
+                "_guild": guild_id,
+                "_user": &user_id,
+            };
+            if let Some(name) = datasets::stored_name(&dataset) {
+                edit.insert("_dataset", name);
+            }
+            edit
+        }
+    };
+    let mut fields = edit
+        .get_document("fields")
+        .ok()
+        .cloned()
+        .unwrap_or_default();
+    fields.insert(field, value);
+    edit.insert("fields", fields);
+    whois_edits
+        .replace_one(
+            filter.clone(),
+            edit,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    let before = entry.insert(field, parsed);
+    whois_data.replace_one(filter, entry, None).await?;
+    Ok(before)
+}
+
+/// Records a member's edit to their own entry.
+async fn record_edit(
+    db: &Database,
+    guild_id: u64,
+    msg: &Message,
+    action: &'static str,
+    field: &str,
+    before: Option<Bson>,
+    value: &str,
+) {
+    audit::record(
+        db,
+        guild_id,
+        vec![audit::Record {
+            action,
+            actor: msg.author.id,
+            user: Some(msg.author.id.to_string()),
+            note: String::new(),
+            changes: vec![audit::change(
+                field,
+                before,
+                Some(Bson::String(String::from(value))),
+            )],
+        }],
+    )
+    .await;
+}
+
+/// Applies every member's edits to a dataset again, such as after it's been replaced by `:whois
+/// fetch`. Edits are only applied to existing entries, and values that no longer fit the column
+/// type are left as fetched. Edits from members who aren't in the fetched data are kept in case
+/// they're in a later fetch.
+pub async fn apply_edits(
+    whois_data: &Collection,
+    whois_edits: &Collection,
+    schema: &Schema,
+    guild_id: u64,
+    dataset: &str,
+) -> CommandResult {
+    let mut edits = whois_edits
+        .find(datasets::entry_filter(guild_id, dataset), None)
+        .await?;
+    while let Some(edit_result) = edits.next().await {
+        let edit = edit_result?;
+        let user_id = match edit.get_str("_user") {
+            Ok(user_id) => user_id,
+            _ => continue,
+        };
+        let fields = match edit.get_document("fields") {
+            Ok(fields) if !fields.is_empty() => fields,
+            _ => continue,
+        };
+        let filter = member_filter(guild_id, dataset, user_id);
+        let mut entry = match whois_data.find_one(filter.clone(), None).await? {
+            Some(entry) => entry,
+            None => continue,
+        };
+        for (field, value) in fields {
+            if let Some(Ok(value)) = value
+                .as_str()
+                .map(|value| schema.get(field).parse_value(value))
+            {
+                entry.insert(field, value);
+            }
+        }
+        whois_data.replace_one(filter, entry, None).await?;
+    }
+    Ok(())
+}
+
+/// Gets the `--dataset <name>` before the field name, if it's there.
+fn dataset_arg(args: &mut Args) -> CommandResult<Option<String>> {
+    if args.current() == Some("--dataset") {
+        args.advance();
+        Ok(Some(args.single::<String>()?.to_lowercase()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Changes a field of the member's own entry, after checking that they can edit it and that the
+/// value fits the column type. An empty value clears the field.
+async fn edit_own_entry(
+    ctx: &Context,
+    msg: &Message,
+    guild_id: u64,
+    dataset: Option<String>,
+    field: String,
+    value: &str,
+    action: &'static str,
+) -> CommandResult {
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_data = db.collection("whois-data");
+    let whois_settings = db.collection("whois-settings");
+    let whois_edits = db.collection("whois-edits");
+
+    let field = match find_editable_field(ctx, msg, &whois_settings, guild_id, &field).await? {
+        Some(field) => field,
+        None => return Ok(()),
+    };
+    let user_id = msg.author.id.to_string();
+    let entry = match find_own_entry(db, guild_id, &user_id, dataset.as_deref(), &field).await? {
+        Some(entry) => entry,
+        None => {
+            let reason = match dataset {
+                Some(name) => format!("You aren't in the `{}` dataset, so there's nothing to edit.", name),
+                None => String::from("You aren't in the whois data, so there's nothing to edit. Ask the mods to add you."),
+            };
+            msg.channel_id.say(&ctx.http, reason).await?;
+            return Ok(());
+        }
+    };
+    let schema = Schema::load(
+        &db.collection("whois-schema"),
+        guild_id,
+        datasets::dataset_of(&entry),
+    )
+    .await?;
+    let parsed = match schema.get(&field).parse_value(value) {
+        Ok(parsed) => parsed,
+        Err(problem) => {
+            msg.channel_id
+                .say(&ctx.http, format!("`{}` {}.", field, problem))
+                .await?;
+            return Ok(());
+        }
+    };
+    let before = save_edit(
+        &whois_data,
+        &whois_edits,
+        guild_id,
+        entry,
+        &field,
+        value,
+        parsed,
+    )
+    .await?;
+    record_edit(db, guild_id, msg, action, &field, before, value).await;
+
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
+
+#[command]
+#[usage = "[--dataset <name>] \"<field>\" <value>"]
+#[example = "Pronouns they/them"]
+#[example = "\"Favorite Food\" lasagna"]
+#[example = "--dataset staff Office \"Room 204\""]
+/// Change a field of your own whois entry. You can only change the fields that the mods allow
+/// with the `editable` option (see `:help whois config`), and only if you're in the whois data.
+/// Values have to fit the column's type (see `:help whois schema`). If you're in more than one
+/// dataset, the first entry with the field is changed unless you pick one with `--dataset`. Your
+/// changes will stay even after the mods do `:whois fetch` again.
+async fn set(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let dataset = dataset_arg(&mut args)?;
+    let field = args.single_quoted::<String>()?;
+    let value = args.rest().trim();
+    if value.is_empty() {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "What do you want to set it to? If you want to clear it, do `:whois unset <field>`.",
+            )
+            .await?;
+        return Ok(());
+    }
+    if value.chars().count() > MAX_VALUE_LENGTH {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "That's too long. Please keep it under {} characters.",
+                    MAX_VALUE_LENGTH
+                ),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    edit_own_entry(ctx, msg, guild_id, dataset, field, value, "set").await
+}
+
+#[command]
+#[usage = "[--dataset <name>] \"<field>\""]
+#[example = "Pronouns"]
+/// Clear a field of your own whois entry. Like `:whois set`, this only works for the fields the
+/// mods allow, and it'll stay cleared even after `:whois fetch`.
+async fn unset(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let dataset = dataset_arg(&mut args)?;
+    let field = args.single_quoted::<String>()?;
+
+    // An empty value is hidden from whois entries, and keeping it as an edit stops the next fetch
+    // from bringing the old value back.
+    edit_own_entry(ctx, msg, guild_id, dataset, field, "", "unset").await
+}
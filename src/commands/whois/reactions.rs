@@ -0,0 +1,149 @@
+//! Lets members look up a message's author by reacting to it with the emoji set by the `reaction`
+//! option. The whois card is sent in a DM so that lookups don't have to happen in public channels.
+use super::{
+    card_fields, datasets,
+    privacy::{Privacy, Viewer},
+};
+use lazy_static::lazy_static;
+use mongodb::{bson::doc, Database};
+use serenity::{
+    client::Context,
+    framework::standard::CommandResult,
+    model::{channel::Reaction, channel::ReactionType, id::UserId},
+    utils::Colour,
+};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long members have to wait between lookups.
+const COOLDOWN: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// When each member last looked someone up.
+    static ref LAST_LOOKUPS: Mutex<HashMap<UserId, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Whether the reaction is the emoji from the option, which is either the emoji itself or a custom
+/// emoji like `<:name:id>`.
+fn is_lookup_emoji(option: &str, emoji: &ReactionType) -> bool {
+    let option = option.trim();
+    match emoji {
+        ReactionType::Custom { id, .. } => {
+            option.starts_with('<')
+                && option.ends_with('>')
+                && option[..option.len() - 1]
+                    .rsplit(':')
+                    .next()
+                    .and_then(|id| id.parse::<u64>().ok())
+                    == Some(id.0)
+        }
+        // Some keyboards add a variation selector to emoji and some don't
+        ReactionType::Unicode(name) => {
+            name.trim_end_matches('\u{FE0F}') == option.trim_end_matches('\u{FE0F}')
+        }
+        _ => false,
+    }
+}
+
+/// Records a lookup by the member, or returns false if they looked someone up too recently.
+fn take_lookup(user_id: UserId) -> bool {
+    let mut last_lookups = LAST_LOOKUPS.lock().unwrap();
+    let now = Instant::now();
+    last_lookups.retain(|_, time| now.duration_since(*time) < COOLDOWN);
+    if last_lookups.contains_key(&user_id) {
+        false
+    } else {
+        last_lookups.insert(user_id, now);
+        true
+    }
+}
+
+pub async fn on_reaction_add(ctx: &Context, db: &Database, reaction: &Reaction) -> CommandResult {
+    let (guild_id, user_id) = match (reaction.guild_id, reaction.user_id) {
+        (Some(guild_id), Some(user_id)) => (guild_id, user_id),
+        _ => return Ok(()),
+    };
+    let settings = db
+        .collection("whois-settings")
+        .find_one(doc! { "_guild": guild_id.0 }, None)
+        .await?;
+    match settings
+        .as_ref()
+        .and_then(|settings| settings.get_str("reaction").ok())
+    {
+        Some(option) if is_lookup_emoji(option, &reaction.emoji) => {}
+        _ => return Ok(()),
+    }
+    let user = reaction.user(ctx).await?;
+    if user.bot {
+        return Ok(());
+    }
+    // Nobody else needs to know who was looked up. This fails without the MANAGE_MESSAGES
+    // permission, which is fine.
+    let _ = reaction.delete(ctx).await;
+    if !take_lookup(user_id) {
+        return Ok(());
+    }
+
+    let guild = match guild_id.to_guild_cached(&ctx.cache).await {
+        Some(guild) => guild,
+        None => return Ok(()),
+    };
+    let message = reaction.message(&ctx.http).await?;
+    let author_id = message.author.id.to_string();
+    let roles = match &reaction.member {
+        Some(member) => member.roles.clone(),
+        None => guild
+            .members
+            .get(&user_id)
+            .map_or_else(Vec::new, |member| member.roles.clone()),
+    };
+    let privacy = Privacy::load(db, guild_id.0, Viewer::new(&guild, user_id, roles)).await?;
+    let link = format!(
+        "https://discord.com/channels/{}/{}/{}",
+        guild_id, reaction.channel_id, reaction.message_id
+    );
+
+    if privacy.is_opted_out(&author_id) {
+        user.direct_message(ctx, |message| {
+            message.content(format!(
+                "<@{}> has chosen not to share their whois entry in **{}**.",
+                author_id, guild.name
+            ))
+        })
+        .await?;
+        return Ok(());
+    }
+    let entries = datasets::find_entries(db, guild_id.0, &author_id, None).await?;
+    if entries.is_empty() {
+        user.direct_message(ctx, |message| {
+            message.content(format!(
+                "I don't know anything about <@{}> in **{}**.",
+                author_id, guild.name
+            ))
+        })
+        .await?;
+        return Ok(());
+    }
+    let fields = card_fields(&entries, &privacy);
+    user.direct_message(ctx, |message| {
+        message.embed(|embed| {
+            embed.colour(Colour::MAGENTA);
+            embed.description(format!(
+                "What I know about <@{}> in **{}** ([their message]({}))",
+                author_id, guild.name, link
+            ));
+            for (key, value, inline) in fields {
+                embed.field(key, value, inline);
+            }
+            embed
+        });
+        message
+    })
+    .await?;
+
+    Ok(())
+}
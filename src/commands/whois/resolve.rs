@@ -0,0 +1,288 @@
+//! Works out which member a whois row is for when its ID column has a username, like `moofy` or
+//! `moofy#1234`, instead of a Discord ID. Names that match nobody or more than one member can be
+//! pinned to a member by the mods.
+use super::{audit, is_snowflake, parse_id};
+use crate::{db, pagination::truncate};
+use mongodb::{
+    bson::{doc, Document},
+    options::UpdateOptions,
+    Collection, Database,
+};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        guild::Member,
+        id::{GuildId, UserId},
+    },
+    utils::Colour,
+};
+use std::collections::HashMap;
+use tokio::stream::StreamExt;
+
+/// Names are compared ignoring case, surrounding whitespace, and a leading `@`.
+fn normalize(name: &str) -> String {
+    name.trim().trim_start_matches('@').to_lowercase()
+}
+
+pub enum Resolution {
+    Found(UserId),
+    /// More than one member has the name.
+    Ambiguous(Vec<UserId>),
+    Unresolved,
+}
+
+/// Looks up members by name using the guild's member list.
+pub struct Resolver {
+    pins: HashMap<String, UserId>,
+    by_tag: HashMap<String, UserId>,
+    by_username: HashMap<String, Vec<UserId>>,
+    by_nickname: HashMap<String, Vec<UserId>>,
+}
+
+impl Resolver {
+    pub async fn load(db: &Database, guild_id: GuildId, members: &[Member]) -> CommandResult<Self> {
+        let mut resolver = Resolver {
+            pins: load_pins(&db.collection("whois-pins"), guild_id.0).await?,
+            by_tag: HashMap::new(),
+            by_username: HashMap::new(),
+            by_nickname: HashMap::new(),
+        };
+        for member in members {
+            let user_id = member.user.id;
+            resolver
+                .by_tag
+                .insert(member.user.tag().to_lowercase(), user_id);
+            resolver
+                .by_username
+                .entry(member.user.name.to_lowercase())
+                .or_insert_with(Vec::new)
+                .push(user_id);
+            if let Some(nickname) = &member.nick {
+                resolver
+                    .by_nickname
+                    .entry(nickname.to_lowercase())
+                    .or_insert_with(Vec::new)
+                    .push(user_id);
+            }
+        }
+        Ok(resolver)
+    }
+
+    /// Finds the member that the text refers to. Pins come first, then IDs, `name#1234` tags,
+    /// usernames, and finally nicknames.
+    pub fn resolve(&self, text: &str) -> Resolution {
+        let name = normalize(text);
+        if let Some(user_id) = self.pins.get(&name) {
+            return Resolution::Found(*user_id);
+        }
+        if is_snowflake(&name) {
+            return Resolution::Found(UserId(name.parse().unwrap_or_default()));
+        }
+        if let Some(user_id) = self.by_tag.get(&name) {
+            return Resolution::Found(*user_id);
+        }
+        // Usernames might have changed since the tag was written down
+        let username = match name.rfind('#') {
+            Some(hash) => &name[..hash],
+            None => &name,
+        };
+        for members in &[
+            self.by_username.get(username),
+            self.by_nickname.get(username),
+        ] {
+            match members.map(|members| members.as_slice()) {
+                Some([user_id]) => return Resolution::Found(*user_id),
+                Some(user_ids) if !user_ids.is_empty() => {
+                    return Resolution::Ambiguous(user_ids.to_vec())
+                }
+                _ => {}
+            }
+        }
+        Resolution::Unresolved
+    }
+}
+
+/// Explains why a name couldn't be resolved, for the fetch report.
+pub fn describe_problem(name: &str, resolution: &Resolution) -> Option<String> {
+    match resolution {
+        Resolution::Found(_) => None,
+        Resolution::Ambiguous(user_ids) => Some(format!(
+            "`{}` could be any of {}. Pick one with `:whois pin \"{}\" <user>`.",
+            name,
+            user_ids
+                .iter()
+                .map(|user_id| format!("<@{}>", user_id))
+                .collect::<Vec<String>>()
+                .join(", "),
+            name
+        )),
+        Resolution::Unresolved => Some(format!(
+            "Nobody here is named `{}`. If they go by something else, do `:whois pin \"{}\" <user>`.",
+            name, name
+        )),
+    }
+}
+
+async fn load_pins(
+    whois_pins: &Collection,
+    guild_id: u64,
+) -> CommandResult<HashMap<String, UserId>> {
+    let mut cursor = whois_pins.find(doc! { "_guild": guild_id }, None).await?;
+    let mut pins = HashMap::new();
+    while let Some(doc_result) = cursor.next().await {
+        let doc: Document = doc_result?;
+        if let (Ok(name), Some(user_id)) = (
+            doc.get_str("name"),
+            doc.get_str("user").ok().and_then(parse_id),
+        ) {
+            pins.insert(String::from(name), UserId(user_id));
+        }
+    }
+    Ok(pins)
+}
+
+/// Records a name being pinned to a member or unpinned.
+async fn record_pin(
+    db: &Database,
+    guild_id: u64,
+    msg: &Message,
+    user: Option<String>,
+    note: String,
+) {
+    audit::record(
+        db,
+        guild_id,
+        vec![audit::Record {
+            action: "pin",
+            actor: msg.author.id,
+            user,
+            note,
+            changes: Vec::new(),
+        }],
+    )
+    .await;
+}
+
+#[command]
+#[usage = "[\"name\" <user id or mention>] or [remove \"name\"]"]
+#[example = ""]
+#[example = "\"moofy#1234\" 393248490739859458"]
+#[example = "\"Billy\" @William"]
+#[example = "remove \"moofy#1234\""]
+#[required_permissions("MANAGE_GUILD")]
+/// Tell me which member a name in the whois data's ID column refers to. When the ID column has
+/// usernames or `name#1234` tags instead of Discord IDs, `:whois fetch` looks them up in the member
+/// list and lists the names that match nobody or more than one member. Pin those names here, then
+/// fetch again. Without any arguments, this lists the pins. Requires that you can manage the guild
+/// (the MANAGE_GUILD permission).
+async fn pin(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_pins = db.collection("whois-pins");
+
+    if args.is_empty() {
+        let mut pins = load_pins(&whois_pins, guild_id)
+            .await?
+            .into_iter()
+            .map(|(name, user_id)| format!("`{}` => <@{}>", name, user_id))
+            .collect::<Vec<String>>();
+        pins.sort();
+        let list = pins.join("\n");
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.colour(Colour::MAGENTA);
+                    embed.description(if list.is_empty() {
+                        String::from("There are no pins.")
+                    } else {
+                        truncate(list, 2000)
+                    });
+                    embed
+                });
+                message
+            })
+            .await?;
+        return Ok(());
+    }
+
+    if args.current() == Some("remove") {
+        args.advance();
+        let name = normalize(&args.single_quoted::<String>()?);
+        match whois_pins
+            .find_one_and_delete(doc! { "_guild": guild_id, "name": &name }, None)
+            .await?
+        {
+            Some(pin) => {
+                record_pin(
+                    db,
+                    guild_id,
+                    msg,
+                    pin.get_str("user").ok().map(String::from),
+                    format!("Unpinned `{}`.", name),
+                )
+                .await;
+                msg.react(&ctx.http, '👌').await?;
+            }
+            None => {
+                msg.channel_id
+                    .say(&ctx.http, format!("`{}` isn't pinned.", name))
+                    .await?;
+            }
+        }
+        return Ok(());
+    }
+
+    let name = normalize(&args.single_quoted::<String>()?);
+    let user_id = match parse_id(args.rest()) {
+        Some(user_id) => user_id,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "Who? Mention them or give their ID.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let filter = doc! { "_guild": guild_id, "name": &name };
+    whois_pins
+        .update_one(
+            filter.clone(),
+            doc! {
+                "$set": { "user": user_id.to_string() },
+                "$setOnInsert": filter,
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+    record_pin(
+        db,
+        guild_id,
+        msg,
+        Some(user_id.to_string()),
+        format!("Pinned `{}`.", name),
+    )
+    .await;
+
+    msg.channel_id
+        .say(
+            &ctx.http,
+            format!(
+                "`{}` is <@{}> now. Do `:whois fetch` again to use it.",
+                name, user_id
+            ),
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,497 @@
+//! Gives members roles based on their whois entries, like giving everyone whose `Grade` is `10`
+//! the Sophomores role.
+use super::{
+    audit, datasets, describe_changes, entries_by_member, get_all_members,
+    profile::editable_fields, template::display_value,
+};
+use crate::{db, pagination::truncate};
+use lazy_static::lazy_static;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Collection, Database,
+};
+use regex::Regex;
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{
+        channel::Message,
+        guild::{Guild, Member},
+        id::{GuildId, RoleId, UserId},
+    },
+    utils::Colour,
+};
+use std::collections::HashSet;
+use tokio::stream::StreamExt;
+
+/// Members whose whois `field` is `value` get the role.
+struct RoleRule {
+    id: Bson,
+    field: String,
+    value: String,
+    role: RoleId,
+}
+
+impl RoleRule {
+    fn describe(&self) -> String {
+        format!("`{}` is `{}` => <@&{}>", self.field, self.value, self.role)
+    }
+
+    fn from_doc(doc: &Document) -> Option<Self> {
+        Some(RoleRule {
+            id: doc.get("_id")?.clone(),
+            field: String::from(doc.get_str("field").ok()?),
+            value: String::from(doc.get_str("value").ok()?),
+            role: RoleId(doc.get_i64("role").ok()? as u64),
+        })
+    }
+
+    /// Values are compared ignoring case and surrounding whitespace.
+    fn matches(&self, entry: &Document) -> bool {
+        entry
+            .get(&self.field)
+            .and_then(display_value)
+            .map_or(false, |value| {
+                value.trim().to_lowercase() == self.value.trim().to_lowercase()
+            })
+    }
+}
+
+async fn load_rules(whois_rolemaps: &Collection, guild_id: u64) -> CommandResult<Vec<RoleRule>> {
+    let mut cursor = whois_rolemaps
+        .find(doc! { "_guild": guild_id }, None)
+        .await?;
+    let mut rules = Vec::new();
+    while let Some(doc_result) = cursor.next().await {
+        if let Some(rule) = RoleRule::from_doc(&doc_result?) {
+            rules.push(rule);
+        }
+    }
+    Ok(rules)
+}
+
+/// The fields that members can edit themselves, in lowercase.
+async fn load_editable(db: &Database, guild_id: u64) -> CommandResult<Vec<String>> {
+    let settings = db
+        .collection("whois-settings")
+        .find_one(doc! { "_guild": guild_id }, None)
+        .await?
+        .unwrap_or_else(Document::new);
+    Ok(editable_fields(&settings)
+        .iter()
+        .map(|field| field.to_lowercase())
+        .collect())
+}
+
+/// The rules to apply. Rules on fields that members can edit are skipped, since members could
+/// otherwise give themselves the role with `:whois set`.
+async fn load_active_rules(db: &Database, guild_id: u64) -> CommandResult<Vec<RoleRule>> {
+    let editable = load_editable(db, guild_id).await?;
+    Ok(load_rules(&db.collection("whois-rolemaps"), guild_id)
+        .await?
+        .into_iter()
+        .filter(|rule| !editable.contains(&rule.field.to_lowercase()))
+        .collect())
+}
+
+pub struct RoleChange {
+    user_id: UserId,
+    role_id: RoleId,
+    add: bool,
+}
+
+impl RoleChange {
+    /// Like `+ @Sophomores to @moofy`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} <@&{}> {} <@{}>",
+            if self.add { "+" } else { "-" },
+            self.role_id,
+            if self.add { "to" } else { "from" },
+            self.user_id
+        )
+    }
+}
+
+/// Works out which roles to give and take from a member. Roles that aren't in any rule are left
+/// alone.
+fn plan_member(rules: &[RoleRule], entry: &Document, member: &Member) -> Vec<RoleChange> {
+    let managed = rules
+        .iter()
+        .map(|rule| rule.role)
+        .collect::<HashSet<RoleId>>();
+    let wanted = rules
+        .iter()
+        .filter(|rule| rule.matches(entry))
+        .map(|rule| rule.role)
+        .collect::<HashSet<RoleId>>();
+    managed
+        .into_iter()
+        .filter_map(|role_id| {
+            let add = wanted.contains(&role_id);
+            if add != member.roles.contains(&role_id) {
+                Some(RoleChange {
+                    user_id: member.user.id,
+                    role_id,
+                    add,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Works out the role changes for every member of the guild who has a whois entry.
+pub async fn plan_guild(
+    db: &Database,
+    guild_id: GuildId,
+    members: &[Member],
+) -> CommandResult<Vec<RoleChange>> {
+    let rules = load_active_rules(db, guild_id.0).await?;
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let entries = entries_by_member(db, guild_id).await?;
+    let mut changes = Vec::new();
+    for member in members {
+        if let Some(entry) = entries.get(&member.user.id.to_string()) {
+            changes.extend(plan_member(&rules, entry, member));
+        }
+    }
+    Ok(changes)
+}
+
+/// Makes the role changes, returning a description of each change that Discord refused, such as
+/// when the role is above Moofy's.
+pub async fn apply_changes(
+    ctx: &Context,
+    guild_id: GuildId,
+    changes: &[RoleChange],
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    for change in changes {
+        let result = if change.add {
+            ctx.http
+                .add_member_role(guild_id.0, change.user_id.0, change.role_id.0)
+                .await
+        } else {
+            ctx.http
+                .remove_member_role(guild_id.0, change.user_id.0, change.role_id.0)
+                .await
+        };
+        if let Err(why) = result {
+            failures.push(format!(
+                "Couldn't {} <@&{}> {} <@{}>: {}",
+                if change.add { "give" } else { "take" },
+                change.role_id,
+                if change.add { "to" } else { "from" },
+                change.user_id,
+                why
+            ));
+        }
+    }
+    failures
+}
+
+/// Gives a member who just joined the roles that their whois entry calls for.
+pub async fn sync_member(
+    ctx: &Context,
+    db: &Database,
+    guild_id: GuildId,
+    member: &Member,
+) -> CommandResult {
+    let rules = load_active_rules(db, guild_id.0).await?;
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let entries = datasets::find_entries(db, guild_id.0, &member.user.id.to_string(), None).await?;
+    if let Some(entry) = datasets::merge_entries(&entries) {
+        let changes = plan_member(&rules, &entry, member);
+        for failure in apply_changes(ctx, guild_id, &changes).await {
+            println!("Role sync for a new member had an error: {}", failure);
+        }
+    }
+    Ok(())
+}
+
+/// Records a rule being added or removed.
+async fn record_rule(db: &Database, guild_id: u64, msg: &Message, note: String) {
+    audit::record(
+        db,
+        guild_id,
+        vec![audit::Record {
+            action: "rolemap",
+            actor: msg.author.id,
+            user: None,
+            note,
+            changes: Vec::new(),
+        }],
+    )
+    .await;
+}
+
+/// Finds a role by mention, ID, or name.
+fn parse_role(guild: &Guild, text: &str) -> Option<RoleId> {
+    lazy_static! {
+        static ref ROLE_ID: Regex = Regex::new(r"^(?:<@&)?(\d+)>?$").unwrap();
+    }
+    if let Some(role_id) = ROLE_ID
+        .captures(text)
+        .and_then(|captures| captures.get(1))
+        .and_then(|id| id.as_str().parse::<u64>().ok())
+    {
+        return Some(RoleId(role_id)).filter(|role_id| guild.roles.contains_key(role_id));
+    }
+    guild
+        .role_by_name(text.trim_start_matches('@'))
+        .map(|role| role.id)
+}
+
+/// Whether the member could give the role themselves, which is only if it's below their highest
+/// role or they own the server. This stops mods from using rules to hand out roles above theirs.
+fn can_give(guild: &Guild, msg: &Message, role_id: RoleId) -> bool {
+    if guild.owner_id == msg.author.id {
+        return true;
+    }
+    let roles = match (&msg.member, guild.members.get(&msg.author.id)) {
+        (Some(member), _) => &member.roles,
+        (None, Some(member)) => &member.roles,
+        (None, None) => return false,
+    };
+    let highest = roles
+        .iter()
+        .filter_map(|role_id| guild.roles.get(role_id))
+        .map(|role| role.position)
+        .max();
+    match (guild.roles.get(&role_id), highest) {
+        (Some(role), Some(highest)) => role.position < highest,
+        _ => false,
+    }
+}
+
+#[command]
+#[usage = "[\"field\" value => role] or [remove <number>]"]
+#[example = ""]
+#[example = "Grade 10 => @Sophomores"]
+#[example = "\"Homeroom Teacher\" Mr. Smith => Smith's Homeroom"]
+#[example = "remove 2"]
+#[required_permissions("MANAGE_ROLES")]
+/// Give members a role based on a field in their whois entry. Without any arguments, this lists
+/// the rules. Once a rule is added, members whose field has the value (ignoring case) get the
+/// role, and members with a whois entry whose field doesn't have it lose it. Rules are applied
+/// after every `:whois fetch`, when members join, and with `:whois rolesync`. You can only add rules
+/// for roles below your highest role, and not for fields that members can edit themselves (see
+/// `editable` in `:help whois config`); rules on fields that become editable are skipped. Requires
+/// that you can manage roles (the MANAGE_ROLES permission).
+async fn rolemap(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let guild_id = guild.id.as_u64().to_owned();
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_rolemaps = db.collection("whois-rolemaps");
+
+    let rules = load_rules(&whois_rolemaps, guild_id).await?;
+
+    if args.is_empty() {
+        let editable = load_editable(db, guild_id).await?;
+        let list = rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                format!(
+                    "{}. {}{}",
+                    i + 1,
+                    rule.describe(),
+                    if editable.contains(&rule.field.to_lowercase()) {
+                        " (skipped, since members can edit it)"
+                    } else {
+                        ""
+                    }
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.colour(Colour::MAGENTA);
+                    embed.description(if list.is_empty() {
+                        String::from("There are no rules. Add one like `:whois rolemap Grade 10 => @Sophomores`.")
+                    } else {
+                        truncate(list, 2000)
+                    });
+                    embed
+                });
+                message
+            })
+            .await?;
+        return Ok(());
+    }
+
+    if args.current() == Some("remove") {
+        args.advance();
+        let number = args.single::<usize>()?;
+        match rules.get(number.wrapping_sub(1)) {
+            Some(rule) => {
+                whois_rolemaps
+                    .delete_one(doc! { "_id": rule.id.clone() }, None)
+                    .await?;
+                record_rule(db, guild_id, msg, format!("Removed {}", rule.describe())).await;
+                msg.react(&ctx.http, '👌').await?;
+            }
+            None => {
+                msg.channel_id
+                    .say(
+                        &ctx.http,
+                        "There's no rule with that number. Do `:whois rolemap` to list them.",
+                    )
+                    .await?;
+            }
+        }
+        return Ok(());
+    }
+
+    let field = args.single_quoted::<String>()?;
+    let rest = args.rest();
+    let (value, role) = match rest.find("=>") {
+        Some(arrow) => (rest[..arrow].trim(), rest[arrow + 2..].trim()),
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "Rules look like `:whois rolemap <field> <value> => <role>`.",
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    let value = value.trim_matches('"');
+    let role_id = match parse_role(&guild, role) {
+        Some(role_id) => role_id,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "I couldn't find that role.")
+                .await?;
+            return Ok(());
+        }
+    };
+    if !can_give(&guild, msg, role_id) {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                "You can only add rules for roles below your highest role.",
+            )
+            .await?;
+        return Ok(());
+    }
+    if load_editable(db, guild_id)
+        .await?
+        .contains(&field.to_lowercase())
+    {
+        msg.channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "Members can edit `{}` themselves with `:whois set`, so it can't give roles.",
+                    field
+                ),
+            )
+            .await?;
+        return Ok(());
+    }
+
+    whois_rolemaps
+        .insert_one(
+            doc! {
+                "_guild": guild_id,
+                "field": &field,
+                "value": value,
+                "role": role_id.0,
+            },
+            None,
+        )
+        .await?;
+    record_rule(
+        db,
+        guild_id,
+        msg,
+        format!("Added `{}` is `{}` => <@&{}>", field, value, role_id),
+    )
+    .await;
+
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
+
+#[command]
+#[usage = "[dry]"]
+#[example = ""]
+#[example = "dry"]
+#[required_permissions("MANAGE_ROLES")]
+/// Give and take roles according to the `:whois rolemap` rules now. With `dry`, I'll only list the
+/// changes I would make. Requires that you can manage roles (the MANAGE_ROLES permission).
+async fn rolesync(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let dry_run = args.rest().trim().eq_ignore_ascii_case("dry");
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    let members = get_all_members(ctx, guild_id).await?;
+    let changes = plan_guild(db, guild_id, &members).await?;
+    let failures = if dry_run {
+        Vec::new()
+    } else {
+        apply_changes(ctx, guild_id, &changes).await
+    };
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.colour(if failures.is_empty() {
+                    Colour::MAGENTA
+                } else {
+                    Colour::RED
+                });
+                embed.description(if changes.is_empty() {
+                    String::from("Everyone already has the right roles.")
+                } else {
+                    describe_changes(changes.iter().map(|change| change.describe()), &failures)
+                });
+                embed
+            });
+            message.content(if dry_run {
+                format!("Here are the {} role changes I would make:", changes.len())
+            } else {
+                format!(
+                    "I made {} of {} role changes.",
+                    changes.len() - failures.len(),
+                    changes.len()
+                )
+            });
+            message
+        })
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,470 @@
+//! Column types for whois datasets, so that numbers and dates are stored as numbers and dates
+//! instead of text, and so that bad rows are caught when fetching.
+use super::{
+    audit,
+    datasets::{Dataset, MAIN_DATASET},
+    is_snowflake, send_csv,
+};
+use crate::{db, pagination::truncate};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::UpdateOptions,
+    Collection,
+};
+use regex::Regex;
+use reqwest::Url;
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::channel::Message,
+    utils::Colour,
+};
+use std::{cmp::Ordering, collections::HashMap};
+use tokio::stream::StreamExt;
+
+/// Date formats that people tend to put in spreadsheets.
+const DATE_FORMATS: [&str; 5] = ["%Y-%m-%d", "%m/%d/%Y", "%B %d, %Y", "%b %d, %Y", "%d %B %Y"];
+
+/// Date and time formats, such as Google Forms timestamps.
+const DATE_TIME_FORMATS: [&str; 2] = ["%m/%d/%Y %H:%M:%S", "%Y-%m-%d %H:%M:%S"];
+
+/// Reads a date in one of the common formats.
+pub fn parse_date(text: &str) -> Option<DateTime<Utc>> {
+    let text = text.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Some(date.with_timezone(&Utc));
+    }
+    DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+                .map(|date| date.and_hms(0, 0, 0))
+        })
+        .map(|date| DateTime::from_utc(date, Utc))
+}
+
+/// What kind of values a column holds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnType {
+    Text,
+    Number,
+    Date,
+    /// One of a list of values, ignoring case.
+    Enum(Vec<String>),
+    DiscordId,
+    Url,
+}
+
+impl ColumnType {
+    /// The type names that can be given to `:whois schema`.
+    const NAMES: [&'static str; 6] = ["string", "number", "date", "enum", "discord", "url"];
+
+    fn from_name(name: &str, values: Vec<String>) -> Option<Self> {
+        match name {
+            "string" => Some(ColumnType::Text),
+            "number" => Some(ColumnType::Number),
+            "date" => Some(ColumnType::Date),
+            "enum" => Some(ColumnType::Enum(values)),
+            "discord" => Some(ColumnType::DiscordId),
+            "url" => Some(ColumnType::Url),
+            _ => None,
+        }
+    }
+
+    fn from_doc(doc: &Document) -> Option<Self> {
+        let values = doc
+            .get_array("values")
+            .map(|values| {
+                values
+                    .iter()
+                    .filter_map(|value| value.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_else(|_| Vec::new());
+        ColumnType::from_name(doc.get_str("type").ok()?, values)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ColumnType::Text => "string",
+            ColumnType::Number => "number",
+            ColumnType::Date => "date",
+            ColumnType::Enum(_) => "enum",
+            ColumnType::DiscordId => "discord",
+            ColumnType::Url => "url",
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            ColumnType::Enum(values) => format!(
+                "one of {}",
+                values
+                    .iter()
+                    .map(|value| format!("`{}`", value))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            ColumnType::Text => String::from("text"),
+            ColumnType::Number => String::from("a number"),
+            ColumnType::Date => String::from("a date, like 2020-12-31"),
+            ColumnType::DiscordId => String::from("a Discord ID"),
+            ColumnType::Url => String::from("a link"),
+        }
+    }
+
+    /// Converts a CSV cell to a value of this type. Empty cells are always allowed.
+    pub fn parse_value(&self, text: &str) -> Result<Bson, String> {
+        lazy_static! {
+            static ref DISCORD_ID: Regex = Regex::new(r"^<?@?!?(\d+)>?$").unwrap();
+        }
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            return Ok(Bson::String(String::new()));
+        }
+        let value = match self {
+            ColumnType::Text => Some(Bson::String(String::from(text))),
+            ColumnType::Number => trimmed
+                .replace(',', "")
+                .parse::<f64>()
+                .ok()
+                .map(Bson::Double),
+            ColumnType::Date => parse_date(trimmed).map(Bson::DateTime),
+            ColumnType::Enum(values) => values
+                .iter()
+                .find(|value| value.to_lowercase() == trimmed.to_lowercase())
+                .map(|value| Bson::String(value.clone())),
+            ColumnType::DiscordId => DISCORD_ID
+                .captures(trimmed)
+                .and_then(|captures| captures.get(1))
+                .filter(|id| is_snowflake(id.as_str()))
+                .map(|id| Bson::String(String::from(id.as_str()))),
+            ColumnType::Url => Url::parse(trimmed)
+                .ok()
+                .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+                .map(|_| Bson::String(String::from(trimmed))),
+        };
+        value.ok_or_else(|| format!("should be {}, not `{}`", self.describe(), trimmed))
+    }
+}
+
+/// The column types of a dataset. Columns without a type are text.
+pub struct Schema {
+    columns: HashMap<String, ColumnType>,
+}
+
+impl Schema {
+    pub async fn load(
+        whois_schema: &Collection,
+        guild_id: u64,
+        dataset: &str,
+    ) -> CommandResult<Self> {
+        let mut cursor = whois_schema
+            .find(doc! { "_guild": guild_id, "dataset": dataset }, None)
+            .await?;
+        let mut columns = HashMap::new();
+        while let Some(doc_result) = cursor.next().await {
+            let doc = doc_result?;
+            if let (Ok(field), Some(column_type)) =
+                (doc.get_str("field"), ColumnType::from_doc(&doc))
+            {
+                columns.insert(String::from(field), column_type);
+            }
+        }
+        Ok(Schema { columns })
+    }
+
+    /// Gets the schema of every dataset in the guild by dataset name.
+    pub async fn load_all(
+        whois_schema: &Collection,
+        guild_id: u64,
+    ) -> CommandResult<HashMap<String, Self>> {
+        let mut cursor = whois_schema.find(doc! { "_guild": guild_id }, None).await?;
+        let mut schemas: HashMap<String, Schema> = HashMap::new();
+        while let Some(doc_result) = cursor.next().await {
+            let doc = doc_result?;
+            if let (Ok(dataset), Ok(field), Some(column_type)) = (
+                doc.get_str("dataset"),
+                doc.get_str("field"),
+                ColumnType::from_doc(&doc),
+            ) {
+                schemas
+                    .entry(String::from(dataset))
+                    .or_insert_with(|| Schema {
+                        columns: HashMap::new(),
+                    })
+                    .columns
+                    .insert(String::from(field), column_type);
+            }
+        }
+        Ok(schemas)
+    }
+
+    pub fn get(&self, field: &str) -> &ColumnType {
+        static TEXT: ColumnType = ColumnType::Text;
+        self.columns.get(field).unwrap_or(&TEXT)
+    }
+}
+
+/// Compares two whois values: numbers by value, dates by time, and everything else as text
+/// ignoring case. Returns `None` if either is empty.
+pub fn compare_values(a: &Bson, b: &Bson) -> Option<Ordering> {
+    fn number(value: &Bson) -> Option<f64> {
+        match value {
+            Bson::Int32(number) => Some(*number as f64),
+            Bson::Int64(number) => Some(*number as f64),
+            Bson::Double(number) => Some(*number),
+            _ => None,
+        }
+    }
+    match (a, b) {
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        _ => match (number(a), number(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ => {
+                let a = super::template::display_value(a)?.to_lowercase();
+                let b = super::template::display_value(b)?.to_lowercase();
+                Some(a.cmp(&b))
+            }
+        },
+    }
+}
+
+/// How many rows have problems, given the problems in row order.
+pub fn bad_row_count(problems: &[(u64, String)]) -> usize {
+    let mut bad_rows = problems.iter().map(|(row, _)| *row).collect::<Vec<u64>>();
+    bad_rows.dedup();
+    bad_rows.len()
+}
+
+/// Tells the mods about every bad row from a fetch. If there are too many to fit in a message, the
+/// full list is attached as a CSV file.
+pub async fn send_report(
+    ctx: &Context,
+    msg: &Message,
+    content: &str,
+    problems: &[(u64, String)],
+) -> CommandResult {
+    let mut description = String::new();
+    let mut complete = true;
+    for (row, problem) in problems {
+        let line = format!("Row {}: {}\n", row, problem);
+        // 2000 minus room for the [...]
+        if description.len() + line.len() > 2000 - 6 {
+            description.push_str("[...]");
+            complete = false;
+            break;
+        }
+        description.push_str(&line);
+    }
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.colour(Colour::RED);
+                embed.description(description);
+                embed
+            });
+            message.content(content);
+            message
+        })
+        .await?;
+    if !complete {
+        let rows = problems
+            .iter()
+            .map(|(row, problem)| vec![row.to_string(), problem.clone()])
+            .collect::<Vec<Vec<String>>>();
+        send_csv(
+            ctx,
+            msg,
+            "problems.csv",
+            "Here's the full list.",
+            &[String::from("Row"), String::from("Problem")],
+            &rows,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[command]
+#[usage = "[--dataset <name>] \"[field]\" [type] [enum values...]"]
+#[example = ""]
+#[example = "Grade number"]
+#[example = "Birthday date"]
+#[example = "House enum Red Green Blue Yellow"]
+#[example = "--dataset staff Discord discord"]
+#[example = "Grade string"]
+#[required_permissions("MANAGE_GUILD")]
+/// Set the type of a whois column: `string` (the default), `number`, `date`, `enum` (followed by
+/// the allowed values), `discord` (a Discord ID), or `url`. Without a type, this shows the column's
+/// type, and without a column, this lists them all. Types take effect on the next `:whois fetch`,
+/// which won't replace the data if any rows have bad values. Instead, I'll list every bad row.
+/// Numbers and dates sort and compare properly in `:whois search`, and dates are shown like
+/// "December 31, 2020". Requires that you can manage the guild (the MANAGE_GUILD permission).
+async fn schema(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild_id = match msg.guild_id {
+        Some(id) => id.as_u64().to_owned(),
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+    let whois_schema = db.collection("whois-schema");
+
+    let dataset = if args.current() == Some("--dataset") {
+        args.advance();
+        let name = args.single::<String>()?.to_lowercase();
+        if !Dataset::load_all(db, guild_id)
+            .await?
+            .iter()
+            .any(|dataset| dataset.name == name)
+        {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "There's no dataset named `{}`. Do `:whois dataset` to list them.",
+                        name
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+        name
+    } else {
+        String::from(MAIN_DATASET)
+    };
+
+    let schema = Schema::load(&whois_schema, guild_id, &dataset).await?;
+
+    if args.is_empty() {
+        let mut columns = schema
+            .columns
+            .iter()
+            .map(|(field, column_type)| format!("`{}`: {}", field, column_type.describe()))
+            .collect::<Vec<String>>();
+        columns.sort();
+        let list = columns.join("\n");
+        msg.channel_id
+            .send_message(&ctx.http, |message| {
+                message.embed(|embed| {
+                    embed.colour(Colour::MAGENTA);
+                    embed.title(format!("Column types for the `{}` dataset", dataset));
+                    embed.description(if list.is_empty() {
+                        String::from("Every column is text.")
+                    } else {
+                        truncate(list, 2000)
+                    });
+                    embed
+                });
+                message
+            })
+            .await?;
+        return Ok(());
+    }
+
+    let field = args.single_quoted::<String>()?;
+    let type_name = match args.single::<String>() {
+        Ok(type_name) => type_name.to_lowercase(),
+        Err(_) => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!("`{}` is {}.", field, schema.get(&field).describe()),
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    let mut values = Vec::new();
+    while !args.is_empty() {
+        values.push(args.single_quoted::<String>()?);
+    }
+    let column_type = match ColumnType::from_name(&type_name, values) {
+        Some(ColumnType::Enum(values)) if values.is_empty() => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    "List the allowed values after `enum`, like `:whois schema House enum Red Green Blue`.",
+                )
+                .await?;
+            return Ok(());
+        }
+        Some(column_type) => column_type,
+        None => {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "The types are {}.",
+                        ColumnType::NAMES
+                            .iter()
+                            .map(|name| format!("`{}`", name))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let change = audit::change(
+        &field,
+        Some(Bson::String(schema.get(&field).describe())),
+        Some(Bson::String(column_type.describe())),
+    );
+    let filter = doc! {
+        "_guild": guild_id,
+        "dataset": &dataset,
+        "field": &field,
+    };
+    if column_type == ColumnType::Text {
+        whois_schema.delete_one(filter, None).await?;
+    } else {
+        let values = match &column_type {
+            ColumnType::Enum(values) => values.clone(),
+            _ => Vec::new(),
+        };
+        whois_schema
+            .update_one(
+                filter.clone(),
+                doc! {
+                    "$set": {
+                        "type": column_type.name(),
+                        "values": values,
+                    },
+                    "$setOnInsert": filter,
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+    }
+    audit::record(
+        db,
+        guild_id,
+        vec![audit::Record {
+            action: "schema",
+            actor: msg.author.id,
+            user: None,
+            note: format!("Changed a column type of the `{}` dataset.", dataset),
+            changes: vec![change],
+        }],
+    )
+    .await;
+
+    msg.react(&ctx.http, '👌').await?;
+
+    Ok(())
+}
@@ -0,0 +1,288 @@
+//! Finds and sorts whois entries by their fields, using the column types from `:whois schema` so
+//! that numbers and dates compare properly.
+use super::{
+    datasets::{self, Dataset, DisplayTemplates},
+    privacy::{Privacy, Viewer},
+    schema::{compare_values, ColumnType, Schema},
+    template::{display_value, DiscordInfo},
+};
+use crate::{
+    db,
+    pagination::{truncate, Page},
+};
+use mongodb::bson::{doc, Bson, Document};
+use serenity::{
+    client::Context,
+    framework::standard::{macros::command, Args, CommandResult},
+    model::{channel::Message, id::UserId},
+    utils::Colour,
+};
+use std::cmp::Ordering;
+use tokio::stream::StreamExt;
+
+/// How a field is compared with a value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    /// The field contains the value as text, ignoring case.
+    Contains,
+}
+
+impl Operator {
+    fn parse(text: &str) -> Option<Self> {
+        match text {
+            "=" | "==" => Some(Operator::Equal),
+            "!=" => Some(Operator::NotEqual),
+            "<" => Some(Operator::Less),
+            "<=" => Some(Operator::LessOrEqual),
+            ">" => Some(Operator::Greater),
+            ">=" => Some(Operator::GreaterOrEqual),
+            "~" => Some(Operator::Contains),
+            _ => None,
+        }
+    }
+}
+
+/// Only entries whose field compares with the value this way are listed.
+struct Condition {
+    field: String,
+    operator: Operator,
+    value: String,
+}
+
+impl Condition {
+    fn matches(&self, entry: &Document, column_type: &ColumnType) -> bool {
+        let field_value = match entry.get(&self.field) {
+            Some(value) => value,
+            None => return false,
+        };
+        if self.operator == Operator::Contains {
+            return display_value(field_value).map_or(false, |text| {
+                text.to_lowercase().contains(&self.value.to_lowercase())
+            });
+        }
+        // Read the value the same way as the column so that numbers and dates compare properly
+        let value = column_type
+            .parse_value(&self.value)
+            .unwrap_or_else(|_| Bson::String(self.value.clone()));
+        match compare_values(field_value, &value) {
+            Some(ordering) => match self.operator {
+                Operator::Equal => ordering == Ordering::Equal,
+                Operator::NotEqual => ordering != Ordering::Equal,
+                Operator::Less => ordering == Ordering::Less,
+                Operator::LessOrEqual => ordering != Ordering::Greater,
+                Operator::Greater => ordering == Ordering::Greater,
+                Operator::GreaterOrEqual => ordering != Ordering::Less,
+                Operator::Contains => unreachable!(),
+            },
+            None => false,
+        }
+    }
+}
+
+#[command]
+#[usage = "[--dataset <name>] [\"field\" <operator> \"value\"] [by \"field\" [desc]] [page number]"]
+#[example = "Grade >= 10"]
+#[example = "Grade >= 10 by \"Last Name\""]
+#[example = "House = Red 2"]
+#[example = "\"Last Name\" ~ smi"]
+#[example = "by Birthday desc"]
+#[example = "--dataset staff Department = Science"]
+/// List the whois entries whose field compares with a value, sorted by a field. The operators are
+/// `=`, `!=`, `<`, `<=`, `>`, `>=`, and `~`, which means the field contains the text. Columns typed
+/// as numbers or dates with `:whois schema` are compared as numbers or dates, so `Grade >= 10`
+/// doesn't put 9 after 10. You can only search and sort by fields you're allowed to see.
+async fn search(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let guild = match msg.guild(&ctx.cache).await {
+        Some(guild) => guild,
+        None => {
+            msg.channel_id
+                .say(&ctx.http, "You aren't in a server.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let guild_id = guild.id.as_u64().to_owned();
+
+    let mut dataset = None;
+    let mut condition = None;
+    let mut sort: Option<(String, bool)> = None;
+    let mut page_number = 1;
+    while !args.is_empty() {
+        let current = args.current().unwrap_or_default().to_lowercase();
+        if current == "--dataset" {
+            args.advance();
+            dataset = Some(args.single::<String>()?.to_lowercase());
+        } else if current == "by" {
+            args.advance();
+            let field = args.single_quoted::<String>()?;
+            let descending = match args.current().map(|order| order.to_lowercase()) {
+                Some(order) if order == "desc" || order == "asc" => {
+                    args.advance();
+                    order == "desc"
+                }
+                _ => false,
+            };
+            sort = Some((field, descending));
+        } else if args.remaining() == 1 && current.parse::<usize>().is_ok() {
+            page_number = args.single::<usize>()?;
+        } else {
+            let field = args.single_quoted::<String>()?;
+            let operator = args.single::<String>().ok();
+            let value = args.single_quoted::<String>().ok();
+            match (operator.as_deref().and_then(Operator::parse), value) {
+                (Some(operator), Some(value)) => {
+                    condition = Some(Condition {
+                        field,
+                        operator,
+                        value,
+                    });
+                }
+                _ => {
+                    msg.channel_id
+                        .say(
+                            &ctx.http,
+                            "Searches look like `:whois search Grade >= 10`. You can compare with `=`, `!=`, `<`, `<=`, `>`, `>=`, or `~` (contains).",
+                        )
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    let data = ctx.data.read().await;
+    let db = data.get::<db::Db>().expect("Expected Db in TypeMap.");
+
+    if let Some(name) = &dataset {
+        if !Dataset::load_all(db, guild_id)
+            .await?
+            .iter()
+            .any(|dataset| &dataset.name == name)
+        {
+            msg.channel_id
+                .say(
+                    &ctx.http,
+                    format!(
+                        "There's no dataset named `{}`. Do `:whois dataset` to list them.",
+                        name
+                    ),
+                )
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let privacy = Privacy::load(db, guild_id, Viewer::from_message(&guild, msg)).await?;
+    let schemas = Schema::load_all(&db.collection("whois-schema"), guild_id).await?;
+    let display_templates = DisplayTemplates::load(db, guild_id).await?;
+    let column_type = |entry: &Document, field: &str| -> ColumnType {
+        schemas
+            .get(datasets::dataset_of(entry))
+            .map_or(ColumnType::Text, |schema| schema.get(field).clone())
+    };
+
+    let filter = match &dataset {
+        Some(name) => datasets::entry_filter(guild_id, name),
+        None => doc! { "_guild": guild_id },
+    };
+    let mut cursor = db.collection("whois-data").find(filter, None).await?;
+    // Each result is the entry and the part of it that the viewer can see
+    let mut results = Vec::new();
+    while let Some(doc_result) = cursor.next().await {
+        let entry = doc_result?;
+        let user_id = match entry.get_str("_user") {
+            Ok(user_id) => user_id,
+            Err(_) => continue,
+        };
+        if privacy.is_opted_out(user_id) {
+            continue;
+        }
+        let visible = privacy.visible_document(&entry);
+        if let Some(condition) = &condition {
+            if !condition.matches(&visible, &column_type(&entry, &condition.field)) {
+                continue;
+            }
+        }
+        results.push((entry, visible));
+    }
+
+    if let Some((field, descending)) = &sort {
+        results.sort_by(|(_, a), (_, b)| {
+            match (a.get(field), b.get(field)) {
+                (Some(a), Some(b)) => {
+                    let ordering = compare_values(a, b).unwrap_or(Ordering::Equal);
+                    if *descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                }
+                // Entries without the field go last either way
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        });
+    }
+
+    // Show the value that the results were sorted or searched by
+    let shown_field = sort
+        .as_ref()
+        .map(|(field, _)| field.clone())
+        .or_else(|| condition.as_ref().map(|condition| condition.field.clone()));
+    let lines = results
+        .iter()
+        .map(|(entry, visible)| {
+            let user_id = entry.get_str("_user").unwrap_or_default();
+            let discord = match user_id
+                .parse::<u64>()
+                .ok()
+                .and_then(|id| guild.members.get(&UserId(id)))
+            {
+                Some(member) => DiscordInfo::from_member(&guild.roles, member),
+                None => DiscordInfo {
+                    id: String::from(user_id),
+                    ..Default::default()
+                },
+            };
+            let display = display_templates
+                .for_entry(entry)
+                .render(Some(visible), &discord);
+            match shown_field
+                .as_ref()
+                .and_then(|field| visible.get(field))
+                .and_then(display_value)
+            {
+                Some(value) => format!("{} — {}", display, value),
+                None => display,
+            }
+        })
+        .collect::<Vec<String>>();
+    let page = Page::of(&lines, page_number);
+
+    msg.channel_id
+        .send_message(&ctx.http, |message| {
+            message.embed(|embed| {
+                embed.colour(Colour::MAGENTA);
+                embed.description(if lines.is_empty() {
+                    String::from("Nobody matched.")
+                } else {
+                    truncate(page.text, 2000)
+                });
+                embed.footer(|footer| {
+                    footer.text(format!("{} results. {}", lines.len(), page.footer()))
+                });
+                embed
+            });
+            message
+        })
+        .await?;
+
+    Ok(())
+}
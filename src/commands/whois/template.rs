@@ -0,0 +1,748 @@
+//! The template language used by the whois `display` option.
+//!
+//! - `{{First Name}}` inserts a field from the whois entry.
+//! - `{{Nickname|First Name|"someone"}}` uses the first alternative that isn't empty. Quoted
+//!   alternatives are used as-is.
+//! - `{{@nickname}}` inserts something Discord knows about the member. See [`Attribute`].
+//! - `{{Last Name:upper}}` applies filters, in order. See [`Filter`].
+//! - `{{#if Pronouns}} ({{Pronouns}}){{else}} (no pronouns){{/if}}` only renders the first part if
+//!   the condition isn't empty.
+//! - `{{Period 1\: Teacher}}` puts a backslash before a `:`, `|`, or `"` that's part of a field
+//!   name.
+//!
+//! A `{{` that's never closed with a `}}` is left as text.
+use mongodb::bson::{Bson, Document};
+use serenity::model::{
+    guild::{Guild, Member, Role},
+    id::RoleId,
+    user::User,
+};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+/// Something Discord knows about a member, referred to with an `@` in a template.
+#[derive(Debug, Clone, Copy)]
+pub enum Attribute {
+    Id,
+    Mention,
+    Username,
+    Nickname,
+    TopRole,
+    Joined,
+}
+
+const ATTRIBUTES: [(&str, Attribute); 6] = [
+    ("id", Attribute::Id),
+    ("mention", Attribute::Mention),
+    ("username", Attribute::Username),
+    ("nickname", Attribute::Nickname),
+    ("top_role", Attribute::TopRole),
+    ("joined", Attribute::Joined),
+];
+
+#[derive(Debug, Clone)]
+enum Source {
+    Field(String),
+    Discord(Attribute),
+    Literal(String),
+}
+
+/// Transforms the value of an expression.
+#[derive(Debug, Clone, Copy)]
+pub enum Filter {
+    Upper,
+    Lower,
+    /// The first letter of each word, so "Billy Bob Thornton" becomes "BBT".
+    Initials,
+    /// Cuts the value off at the given number of characters, ending it with an ellipsis.
+    Truncate(usize),
+}
+
+const FILTER_NAMES: [&str; 4] = ["upper", "lower", "initials", "truncate(<length>)"];
+
+impl Filter {
+    fn apply(&self, value: String) -> String {
+        match self {
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Initials => value
+                .split_whitespace()
+                .filter_map(|word| word.chars().next())
+                .collect(),
+            Filter::Truncate(length) => {
+                if value.chars().count() > *length {
+                    let mut truncated = value.chars().take(length - 1).collect::<String>();
+                    truncated.push('…');
+                    truncated
+                } else {
+                    value
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Expression {
+    sources: Vec<Source>,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Value(Expression),
+    If {
+        condition: Expression,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+/// A reference to a whois field in a template, kept so that the field names can be checked
+/// against the fetched data.
+#[derive(Debug, Clone)]
+pub struct FieldReference {
+    pub name: String,
+    pub position: usize,
+    pub length: usize,
+}
+
+/// A problem with a template. `position` and `length` are byte offsets into the template of the
+/// offending token.
+#[derive(Debug)]
+pub struct TemplateError {
+    pub position: usize,
+    pub length: usize,
+    pub reason: String,
+}
+
+impl TemplateError {
+    fn new(position: usize, length: usize, reason: String) -> Self {
+        TemplateError {
+            position,
+            length: length.max(1),
+            reason,
+        }
+    }
+
+    /// Formats the error for Discord, underlining the offending token in the template.
+    pub fn describe(&self, template: &str) -> String {
+        let line_start = template[..self.position]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let line_end = template[self.position..]
+            .find('\n')
+            .map_or(template.len(), |index| self.position + index);
+        let line = &template[line_start..line_end];
+        let column = template[line_start..self.position].chars().count();
+        let token_end = (self.position + self.length).min(line_end);
+        let width = template[self.position..token_end].chars().count().max(1);
+        format!(
+            "{} (at character {}):\n```\n{}\n{}{}\n```",
+            self.reason,
+            column + 1,
+            line,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{} (at byte {})", self.reason, self.position)
+    }
+}
+
+/// What Discord knows about the member being displayed.
+#[derive(Debug, Clone, Default)]
+pub struct DiscordInfo {
+    pub id: String,
+    pub username: String,
+    pub nickname: Option<String>,
+    pub top_role: Option<String>,
+    pub joined: Option<String>,
+}
+
+impl DiscordInfo {
+    pub fn from_user(user: &User) -> Self {
+        DiscordInfo {
+            id: user.id.to_string(),
+            username: user.name.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_member(roles: &HashMap<RoleId, Role>, member: &Member) -> Self {
+        let top_role = member
+            .roles
+            .iter()
+            .filter_map(|role_id| roles.get(role_id))
+            .max_by_key(|role| role.position)
+            .map(|role| role.name.clone());
+        DiscordInfo {
+            nickname: member.nick.clone(),
+            top_role,
+            joined: member
+                .joined_at
+                .map(|date| date.format("%Y-%m-%d").to_string()),
+            ..DiscordInfo::from_user(&member.user)
+        }
+    }
+
+    /// Looks up the member in the guild, falling back to what's known about the user if they've
+    /// left.
+    pub fn from_guild(guild: &Guild, user: &User) -> Self {
+        match guild.members.get(&user.id) {
+            Some(member) => DiscordInfo::from_member(&guild.roles, member),
+            None => DiscordInfo::from_user(user),
+        }
+    }
+
+    fn get(&self, attribute: Attribute) -> Option<String> {
+        match attribute {
+            Attribute::Id => Some(self.id.clone()),
+            Attribute::Mention => Some(format!("<@{}>", self.id)),
+            Attribute::Username => Some(self.username.clone()),
+            Attribute::Nickname => self.nickname.clone(),
+            Attribute::TopRole => self.top_role.clone(),
+            Attribute::Joined => self.joined.clone(),
+        }
+    }
+}
+
+/// How dates in the whois data are shown, like "December 31, 2020".
+const DATE_DISPLAY_FORMAT: &str = "%B %-d, %Y";
+
+/// Converts a whois field value to text, or `None` if there's nothing worth showing.
+pub fn display_value(value: &Bson) -> Option<String> {
+    let text = match value {
+        Bson::String(str) => str.clone(),
+        Bson::Int32(number) => number.to_string(),
+        Bson::Int64(number) => number.to_string(),
+        Bson::Double(number) => number.to_string(),
+        Bson::Boolean(boolean) => boolean.to_string(),
+        Bson::DateTime(date) => date.format(DATE_DISPLAY_FORMAT).to_string(),
+        _ => return None,
+    };
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Splits `text` by `separator` except inside double quotes or after a backslash. Each piece is
+/// returned trimmed along with its byte offset relative to `offset`.
+fn split_outside_quotes(text: &str, offset: usize, separator: char) -> Vec<(usize, &str)> {
+    let mut pieces = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == separator && !in_quotes {
+            pieces.push((start, &text[start..index]));
+            start = index + c.len_utf8();
+        }
+    }
+    pieces.push((start, &text[start..]));
+    pieces
+        .into_iter()
+        .map(|(start, piece)| {
+            let leading = piece.len() - piece.trim_start().len();
+            (offset + start + leading, piece.trim())
+        })
+        .collect()
+}
+
+/// Removes the backslashes before escaped characters, so `Period 1\: Teacher` becomes `Period 1:
+/// Teacher`.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.push(chars.next().unwrap_or(c)),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// An `#if` that hasn't been closed yet: the position of its tag, its condition, the nodes before
+/// `else`, and the nodes after `else` if there was one.
+type OpenIf = (usize, Expression, Vec<Node>, Option<Vec<Node>>);
+
+/// The template, parsed and ready to be rendered.
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+    fields: Vec<FieldReference>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut fields = Vec::new();
+        let mut stack: Vec<OpenIf> = Vec::new();
+        let mut nodes = Vec::new();
+        let mut rest_start = 0;
+
+        // The nodes currently being added to
+        fn current<'a>(stack: &'a mut [OpenIf], nodes: &'a mut Vec<Node>) -> &'a mut Vec<Node> {
+            match stack.last_mut() {
+                Some((_, _, _, Some(otherwise))) => otherwise,
+                Some((_, _, then, None)) => then,
+                None => nodes,
+            }
+        }
+
+        while let Some(open) = template[rest_start..].find("{{") {
+            let tag_start = rest_start + open;
+            let content_start = tag_start + 2;
+            let content_end = match template[content_start..].find("}}") {
+                Some(close) => content_start + close,
+                // The rest is just text
+                None => break,
+            };
+            if tag_start > rest_start {
+                current(&mut stack, &mut nodes)
+                    .push(Node::Text(String::from(&template[rest_start..tag_start])));
+            }
+            let tag_length = content_end + 2 - tag_start;
+            let content = &template[content_start..content_end];
+            let trimmed = content.trim();
+            let trimmed_start = content_start + (content.len() - content.trim_start().len());
+
+            if trimmed.starts_with("#if ") || trimmed == "#if" {
+                let condition =
+                    Template::parse_expression(&trimmed[3..], trimmed_start + 3, &mut fields)
+                        .map_err(|err| {
+                            if trimmed[3..].trim().is_empty() {
+                                TemplateError::new(
+                                    tag_start,
+                                    tag_length,
+                                    String::from(
+                                        "`#if` needs a condition, like `{{#if Pronouns}}`",
+                                    ),
+                                )
+                            } else {
+                                err
+                            }
+                        })?;
+                stack.push((tag_start, condition, Vec::new(), None));
+            } else if trimmed == "else" {
+                match stack.last_mut() {
+                    Some((_, _, _, otherwise @ None)) => *otherwise = Some(Vec::new()),
+                    Some(_) => {
+                        return Err(TemplateError::new(
+                            tag_start,
+                            tag_length,
+                            String::from("This `#if` already has an `else`"),
+                        ))
+                    }
+                    None => {
+                        return Err(TemplateError::new(
+                            tag_start,
+                            tag_length,
+                            String::from("This `else` isn't inside an `#if`"),
+                        ))
+                    }
+                }
+            } else if trimmed == "/if" {
+                match stack.pop() {
+                    Some((_, condition, then, otherwise)) => {
+                        current(&mut stack, &mut nodes).push(Node::If {
+                            condition,
+                            then,
+                            otherwise: otherwise.unwrap_or_default(),
+                        })
+                    }
+                    None => {
+                        return Err(TemplateError::new(
+                            tag_start,
+                            tag_length,
+                            String::from("This `/if` doesn't close any `#if`"),
+                        ))
+                    }
+                }
+            } else if trimmed.starts_with('#') || trimmed.starts_with('/') {
+                return Err(TemplateError::new(
+                    trimmed_start,
+                    trimmed.len(),
+                    String::from("Unknown block; only `#if`, `else`, and `/if` exist"),
+                ));
+            } else {
+                let expression = Template::parse_expression(content, content_start, &mut fields)?;
+                current(&mut stack, &mut nodes).push(Node::Value(expression));
+            }
+            rest_start = content_end + 2;
+        }
+
+        if let Some((position, _, _, _)) = stack.last() {
+            let length = template[*position..].find("}}").map_or(2, |end| end + 2);
+            return Err(TemplateError::new(
+                *position,
+                length,
+                String::from("This `#if` is never closed with a `{{/if}}`"),
+            ));
+        }
+        if rest_start < template.len() {
+            nodes.push(Node::Text(String::from(&template[rest_start..])));
+        }
+
+        Ok(Template { nodes, fields })
+    }
+
+    fn parse_expression(
+        content: &str,
+        offset: usize,
+        fields: &mut Vec<FieldReference>,
+    ) -> Result<Expression, TemplateError> {
+        let mut parts = split_outside_quotes(content, offset, ':').into_iter();
+        let (sources_offset, sources_str) = parts.next().unwrap_or((offset, ""));
+
+        let mut sources = Vec::new();
+        for (position, source) in split_outside_quotes(sources_str, sources_offset, '|') {
+            if source.is_empty() {
+                return Err(TemplateError::new(
+                    position,
+                    1,
+                    String::from("Expected a field name here"),
+                ));
+            }
+            if source.starts_with('"') {
+                if source.len() < 2 || !source.ends_with('"') {
+                    return Err(TemplateError::new(
+                        position,
+                        source.len(),
+                        String::from("This quote is never closed"),
+                    ));
+                }
+                sources.push(Source::Literal(unescape(&source[1..source.len() - 1])));
+            } else if let Some(written) = source.strip_prefix('@') {
+                let name = written.to_lowercase();
+                match ATTRIBUTES
+                    .iter()
+                    .find(|(attribute_name, _)| *attribute_name == name)
+                {
+                    Some((_, attribute)) => sources.push(Source::Discord(*attribute)),
+                    None => {
+                        return Err(TemplateError::new(
+                            position,
+                            source.len(),
+                            format!(
+                                "I don't know what `{}` is. Try one of {}",
+                                source,
+                                ATTRIBUTES
+                                    .iter()
+                                    .map(|(name, _)| format!("`@{}`", name))
+                                    .collect::<Vec<String>>()
+                                    .join(", ")
+                            ),
+                        ))
+                    }
+                }
+            } else if source.replace("\\\"", "").contains('"') {
+                return Err(TemplateError::new(
+                    position,
+                    source.len(),
+                    String::from("Quotes in field names need a backslash before them, like `\\\"`"),
+                ));
+            } else {
+                let name = unescape(source);
+                fields.push(FieldReference {
+                    name: name.clone(),
+                    position,
+                    length: source.len(),
+                });
+                sources.push(Source::Field(name));
+            }
+        }
+
+        let mut filters = Vec::new();
+        for (position, filter) in parts {
+            let (name, argument) = match filter.find('(') {
+                Some(paren) if filter.ends_with(')') => (
+                    filter[..paren].trim(),
+                    Some(filter[paren + 1..filter.len() - 1].trim()),
+                ),
+                _ => (filter, None),
+            };
+            filters.push(match (name.to_lowercase().as_str(), argument) {
+                ("upper", None) => Filter::Upper,
+                ("lower", None) => Filter::Lower,
+                ("initials", None) => Filter::Initials,
+                ("truncate", Some(length)) => match length.parse::<usize>() {
+                    Ok(length) if length > 0 => Filter::Truncate(length),
+                    _ => {
+                        return Err(TemplateError::new(
+                            position,
+                            filter.len(),
+                            String::from("`truncate` needs a positive length, like `truncate(10)`"),
+                        ))
+                    }
+                },
+                ("truncate", None) => {
+                    return Err(TemplateError::new(
+                        position,
+                        filter.len(),
+                        String::from("`truncate` needs a length, like `truncate(10)`"),
+                    ))
+                }
+                ("upper", Some(_)) | ("lower", Some(_)) | ("initials", Some(_)) => {
+                    return Err(TemplateError::new(
+                        position,
+                        filter.len(),
+                        format!("`{}` doesn't take anything in parentheses", name),
+                    ))
+                }
+                _ => {
+                    return Err(TemplateError::new(
+                        position,
+                        filter.len(),
+                        format!(
+                            "I don't know of a filter named `{}`. Try one of {}",
+                            name,
+                            FILTER_NAMES
+                                .iter()
+                                .map(|name| format!("`{}`", name))
+                                .collect::<Vec<String>>()
+                                .join(", ")
+                        ),
+                    ))
+                }
+            });
+        }
+
+        Ok(Expression { sources, filters })
+    }
+
+    /// Reads the template the way `display` was read before this language: each `{{field}}` is
+    /// replaced with the field named exactly as written. Templates saved back then might not parse
+    /// now, like ones with a `:` in a field name, so they're read this way instead.
+    pub fn literal(template: &str) -> Self {
+        let mut nodes = Vec::new();
+        let mut fields = Vec::new();
+        let mut rest_start = 0;
+        let mut search_start = 0;
+        while let Some(index) = template[search_start..].find("{{") {
+            let open = search_start + index;
+            let name_start = open + 2;
+            // The field name has at least one character and can't span lines
+            let first_length = match template[name_start..].chars().next() {
+                Some(first) => first.len_utf8(),
+                None => break,
+            };
+            let close = match template[name_start + first_length..].find("}}") {
+                Some(index) => name_start + first_length + index,
+                None => break,
+            };
+            let name = &template[name_start..close];
+            if name.contains('\n') {
+                search_start = open + 1;
+                continue;
+            }
+            if rest_start < open {
+                nodes.push(Node::Text(String::from(&template[rest_start..open])));
+            }
+            nodes.push(Node::Value(Expression {
+                sources: vec![Source::Field(String::from(name))],
+                filters: Vec::new(),
+            }));
+            fields.push(FieldReference {
+                name: String::from(name),
+                position: name_start,
+                length: name.len(),
+            });
+            rest_start = close + 2;
+            search_start = rest_start;
+        }
+        if rest_start < template.len() {
+            nodes.push(Node::Text(String::from(&template[rest_start..])));
+        }
+        Template { nodes, fields }
+    }
+
+    /// The whois fields referred to by the template.
+    pub fn fields(&self) -> &Vec<FieldReference> {
+        &self.fields
+    }
+
+    fn evaluate(
+        expression: &Expression,
+        doc: Option<&Document>,
+        discord: &DiscordInfo,
+    ) -> Option<String> {
+        let value = expression.sources.iter().find_map(|source| {
+            let value = match source {
+                Source::Field(name) => doc.and_then(|doc| doc.get(name)).and_then(display_value),
+                Source::Discord(attribute) => discord.get(*attribute),
+                Source::Literal(text) => Some(text.clone()),
+            };
+            value.filter(|value| !value.is_empty())
+        })?;
+        Some(
+            expression
+                .filters
+                .iter()
+                .fold(value, |value, filter| filter.apply(value)),
+        )
+    }
+
+    fn render_nodes(
+        nodes: &[Node],
+        doc: Option<&Document>,
+        discord: &DiscordInfo,
+        output: &mut String,
+    ) {
+        for node in nodes {
+            match node {
+                Node::Text(text) => output.push_str(text),
+                Node::Value(expression) => {
+                    if let Some(value) = Template::evaluate(expression, doc, discord) {
+                        output.push_str(&value);
+                    }
+                }
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    let branch = if Template::evaluate(condition, doc, discord).is_some() {
+                        then
+                    } else {
+                        otherwise
+                    };
+                    Template::render_nodes(branch, doc, discord, output);
+                }
+            }
+        }
+    }
+
+    /// Fills in the template. Fields that the whois entry doesn't have are left empty.
+    pub fn render(&self, doc: Option<&Document>, discord: &DiscordInfo) -> String {
+        let mut output = String::new();
+        Template::render_nodes(&self.nodes, doc, discord, &mut output);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn discord() -> DiscordInfo {
+        DiscordInfo {
+            id: String::from("393248490739859458"),
+            username: String::from("moofy"),
+            nickname: Some(String::from("Moo")),
+            ..Default::default()
+        }
+    }
+
+    fn render(template: &str, doc: &Document) -> String {
+        Template::parse(template)
+            .unwrap_or_else(|err| panic!("{} failed: {}", template, err))
+            .render(Some(doc), &discord())
+    }
+
+    fn error_at(template: &str) -> (usize, usize) {
+        match Template::parse(template) {
+            Ok(_) => panic!("{} should have failed", template),
+            Err(err) => (err.position, err.length),
+        }
+    }
+
+    #[test]
+    fn inserts_fields() {
+        let entry = doc! { "First Name": "Billy", "Grade": 10 };
+        assert_eq!(render("{{First Name}} ({{ Grade }})", &entry), "Billy (10)");
+        assert_eq!(render("{{Last Name}}!", &entry), "!");
+    }
+
+    #[test]
+    fn uses_the_first_alternative() {
+        let entry = doc! { "Nickname": "", "First Name": "Billy" };
+        assert_eq!(render("{{Nickname|First Name}}", &entry), "Billy");
+        assert_eq!(render("{{Pronouns|\"someone\"}}", &entry), "someone");
+        assert_eq!(render("{{Pronouns|@nickname}}", &entry), "Moo");
+        assert_eq!(render("{{@mention}}", &entry), "<@393248490739859458>");
+    }
+
+    #[test]
+    fn applies_filters() {
+        let entry = doc! { "Name": "Billy Bob Thornton" };
+        assert_eq!(render("{{Name:upper}}", &entry), "BILLY BOB THORNTON");
+        assert_eq!(render("{{Name:initials:lower}}", &entry), "bbt");
+        assert_eq!(render("{{Name:truncate(6)}}", &entry), "Billy…");
+    }
+
+    #[test]
+    fn renders_if_blocks() {
+        let template = "{{#if Pronouns}}({{Pronouns}}){{else}}(none){{/if}}";
+        assert_eq!(
+            render(template, &doc! { "Pronouns": "they/them" }),
+            "(they/them)"
+        );
+        assert_eq!(render(template, &doc! {}), "(none)");
+        assert_eq!(
+            render("{{#if A}}a{{#if B}}b{{/if}}{{/if}}", &doc! { "A": "1" }),
+            "a"
+        );
+    }
+
+    #[test]
+    fn escapes_field_names() {
+        let entry = doc! { "Period 1: Teacher": "Mr. Smith", "A|B": "ab", "\"Q\"": "q" };
+        assert_eq!(render("{{Period 1\\: Teacher}}", &entry), "Mr. Smith");
+        assert_eq!(render("{{A\\|B:upper}}", &entry), "AB");
+        assert_eq!(render("{{\\\"Q\\\"}}", &entry), "q");
+        let template = Template::parse("{{Period 1\\: Teacher}}").unwrap();
+        assert_eq!(template.fields()[0].name, "Period 1: Teacher");
+    }
+
+    #[test]
+    fn leaves_unclosed_braces_as_text() {
+        let entry = doc! { "Name": "Billy" };
+        assert_eq!(render("{{Name}} {{", &entry), "Billy {{");
+        assert_eq!(render("1 {{ 2", &entry), "1 {{ 2");
+    }
+
+    #[test]
+    fn literal_templates_use_field_names_as_written() {
+        let entry = doc! { "Period 1: Teacher": "Mr. Smith", "#": "3", "A|B": "ab" };
+        let render = |template: &str| Template::literal(template).render(Some(&entry), &discord());
+        assert_eq!(render("{{Period 1: Teacher}} ({{#}})"), "Mr. Smith (3)");
+        assert_eq!(render("{{A|B}}{{C}}"), "ab");
+        assert_eq!(render("{{}} {{\nA|B}}"), "{{}} {{\nA|B}}");
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error_at("Hi {{Name:shout}}"), (10, 5));
+        assert_eq!(error_at("{{@email}}"), (2, 6));
+        assert_eq!(error_at("{{Name||Other}}"), (7, 1));
+        assert_eq!(error_at("{{#if Name}}hi"), (0, 12));
+        assert_eq!(error_at("{{/if}}"), (0, 7));
+        assert_eq!(error_at("{{Na\"me}}"), (2, 5));
+    }
+
+    #[test]
+    fn describes_errors() {
+        let template = "Hi {{Name:shout}}";
+        let err = Template::parse(template).unwrap_err();
+        assert!(err
+            .describe(template)
+            .ends_with("(at character 11):\n```\nHi {{Name:shout}}\n          ^^^^^\n```"));
+    }
+}
@@ -15,8 +15,11 @@ use serenity::{
     framework::standard::StandardFramework,
     http::Http,
     model::{
+        channel::{Message, Reaction},
+        event::MessageUpdateEvent,
         gateway::{Activity, Ready},
-        id::GuildId,
+        guild::Member,
+        id::{ChannelId, GuildId, MessageId},
     },
     Client,
 };
@@ -29,6 +32,7 @@ use std::{
 mod commands;
 mod db;
 mod error_with_reason;
+mod pagination;
 
 struct Handler;
 
@@ -57,6 +61,37 @@ impl EventHandler for Handler {
         // }
         println!("{} unknown members", ctx.cache.unknown_members().await);
     }
+
+    async fn guild_member_addition(&self, ctx: Context, guild_id: GuildId, new_member: Member) {
+        commands::whois::on_member_join(&ctx, guild_id, &new_member).await;
+    }
+
+    async fn message(&self, ctx: Context, msg: Message) {
+        commands::pings::on_message(&ctx, &msg).await;
+    }
+
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+    ) {
+        commands::pings::on_message_delete(&ctx, channel_id, deleted_message_id).await;
+    }
+
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        commands::pings::on_message_update(&ctx, &event).await;
+    }
+
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        commands::whois::on_reaction_add(&ctx, &reaction).await;
+    }
 }
 
 #[tokio::main]
@@ -135,6 +170,8 @@ async fn main() {
         .group(&commands::webtoon::WEBTOON_GROUP)
         .group(&commands::emoji::EMOJI_GROUP)
         .group(&commands::math::MATH_GROUP)
+        .group(&commands::pings::PINGS_GROUP)
+        .group(&commands::mydata::MYDATA_GROUP)
         .group(&commands::owner::OWNER_GROUP);
 
     let mut client = Client::builder(&token)
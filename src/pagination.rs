@@ -0,0 +1,43 @@
+/// How many lines go on a page.
+pub const PAGE_SIZE: usize = 20;
+
+/// One page of a long list.
+pub struct Page {
+    pub text: String,
+    /// 1-indexed, like what people type.
+    pub number: usize,
+    pub total: usize,
+}
+
+impl Page {
+    /// Gets a page of lines. Page numbers past the end give the last page.
+    pub fn of(lines: &[String], number: usize) -> Self {
+        let total = ((lines.len() + PAGE_SIZE - 1) / PAGE_SIZE).max(1);
+        let number = number.max(1).min(total);
+        let start = (number - 1) * PAGE_SIZE;
+        let end = (start + PAGE_SIZE).min(lines.len());
+        Page {
+            text: lines[start..end].join("\n"),
+            number,
+            total,
+        }
+    }
+
+    /// Something like "Page 1 of 3", for an embed footer.
+    pub fn footer(&self) -> String {
+        format!("Page {} of {}", self.number, self.total)
+    }
+}
+
+/// Cuts text that's too long for an embed description, marking where it was cut. Cuts between
+/// characters so emoji and accented letters don't panic.
+pub fn truncate(text: String, length: usize) -> String {
+    if text.len() <= length {
+        return text;
+    }
+    let mut end = length - 6;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}\n[...]", &text[0..end])
+}